@@ -2,185 +2,259 @@
     Simon H - 2024
 */
 
+/*
+    Properties.
+
+    Every node and relationship heads a chain of attribute blocks through
+    its attr_head, each block holding one key and its typed value. A key
+    appears at most once per chain, setting it again replaces the value in
+    place and new keys are appended to the tail.
+*/
+
 use bincode::{deserialize, serialize};
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
-use std::mem::size_of;
 
 // type imports can be combined, but this is easier to read
-use crate::disk::*;
-use crate::node::*;
-// use crate::relationship::*;
-
-use crate::types::{Attribute, Header, ATR_PAD}; // import structs
-use crate::types::{AttributeBlock, BlockType, RelationshipBlock};
-use crate::types::{NodeBlock, PATH}; // import Block Types
-
-// custom error macro
-macro_rules! custom_error {
-    ($msg:expr) => {
-        return Err(Error::new(ErrorKind::Other, $msg))
-    };
-}
-
-// map bincode error to io error
-macro_rules! map_bincode_error {
-    ($expr:expr) => {
-        $expr.map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Bincode serialization error: {:?}", err),
-            )
+use crate::database::Database;
+use crate::error::{GdbError, RecordKind, Result};
+use crate::types::{Attribute, Entity, PropertyValue}; // import structs
+use crate::types::{AttributeBlock, BlockType, NodeBlock, RelationshipBlock}; // import Block Types
+use crate::wal::WalOp;
+
+impl Database {
+    //  Given offset, return attribute (key, value and the next link of its chain)
+    pub(crate) fn read_attribute(&self, offset: u64) -> Result<Attribute> {
+        let attribute_block = self.read_record_block::<AttributeBlock>(
+            offset,
+            BlockType::Attribute,
+            RecordKind::Attribute,
+        )?;
+
+        let bytes = self.load_bytes(&attribute_block.property)?;
+        let (key, value) = deserialize::<(String, PropertyValue)>(&bytes)
+            .map_err(|_| GdbError::Corrupt { offset })?;
+
+        Ok(Attribute {
+            key,
+            value,
+            attr_next: attribute_block.attr_next,
         })
-    };
-}
-
-pub fn compare_attribute(attrib1: &Attribute, attrib2: &Attribute) -> bool {
-    if attrib1.value == attrib2.value {
-        return true;
     }
-    return false;
-}
-
-pub fn get_attribute(offset: u64) -> Result<Attribute> {
-    let mut stream = File::open(PATH)?;
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<AttributeBlock>());
-
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.read_to_end(&mut buffer)?;
-
-    let attribute_block = map_bincode_error!(deserialize::<AttributeBlock>(&buffer))?;
-
-    return Ok(attribute_block.attribute);
-}
-
-pub fn create_attribute(new_attribute: Attribute) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    // read header
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-
-    let mut header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    // go to first empty
-    stream.seek(SeekFrom::Start(header.first_empty))?;
-
-    let attribute_block = AttributeBlock {
-        block_type: BlockType::Attribute,
-        attribute: new_attribute,
-        pad: [0; ATR_PAD], // pad for consistent sizing across block types
-    };
-
-    // write relationship information
-    let serialized_attribute_block = map_bincode_error!(serialize(&attribute_block))?;
-    stream.write(&serialized_attribute_block)?;
-
-    // update first empty
-    let new_first_empty = get_first_empty(&stream, &header)?;
-
-    // update header
-    // TODO: update header, update associations...
-
-    Ok(())
-}
-
-pub fn get_attribute_address(attribute: &Attribute) -> Result<u64> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
 
-    // read header
-    let mut buffer = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-    let header = map_bincode_error!(deserialize::<Header>(&buffer))?;
+    //  Write attribute at offset, long properties spill into overflow blocks
+    pub(crate) fn write_attribute(&mut self, offset: u64, attribute: &Attribute) -> Result<()> {
+        let attribute_block = AttributeBlock {
+            block_type: BlockType::Attribute,
+            property: self.store_bytes(&serialize(&(&attribute.key, &attribute.value))?)?,
+            attr_next: attribute.attr_next,
+        };
 
-    for i in 0..header.total_blocks {
-        let offset = size_of::<Header>() as u64 + (i * size_of::<RelationshipBlock>() as u64);
-        let current_attribute = get_attribute(offset)?;
+        self.write_block(offset, &attribute_block)
+    }
 
-        if compare_attribute(&current_attribute, attribute) {
-            return Ok(offset);
+    //  Offset of the node or relationship block owning the chain, with its attr_head
+    fn entity_attr_head(&self, entity: Entity) -> Result<(u64, u64)> {
+        match entity {
+            Entity::Node(id) => {
+                let node = self.get_node_from_id(id)?;
+                Ok((self.get_node_address(&node)?, node.attr_head))
+            }
+            Entity::Relationship(offset) => {
+                let relationship = self.get_relationship(offset)?;
+                Ok((offset, relationship.attr_head))
+            }
         }
     }
 
-    custom_error!("No Relationship Found, FATAL...");
-}
-
-//  Print all attributes of a node.
-pub fn print_attributes(node_offset: u64) -> Result<()> {
-    let node = get_node(node_offset)?;
+    //  Point the entity at address to a new attr_head
+    fn set_entity_attr_head(&mut self, entity: Entity, address: u64, attr_head: u64) -> Result<()> {
+        match entity {
+            Entity::Node(_) => {
+                let mut node_block = self.read_block::<NodeBlock>(address)?;
+                node_block.attr_head = attr_head;
+                self.write_block(address, &node_block)
+            }
+            Entity::Relationship(_) => {
+                let mut relationship_block = self.read_block::<RelationshipBlock>(address)?;
+                relationship_block.relationship.attr_head = attr_head;
+                self.write_block(address, &relationship_block)
+            }
+        }
+    }
 
-    // node.attr_head // seek to attr head...
+    //  Every (offset, attribute) in the chain starting at attr_head, in chain order
+    pub(crate) fn attribute_chain(&self, attr_head: u64) -> Result<Vec<(u64, Attribute)>> {
+        let mut chain = Vec::new();
+        let mut attr_address = attr_head;
 
-    // TODO: open stream etc... & finish...
-    //
-    Ok(())
-}
+        while attr_address != 0 {
+            let attribute = self.read_attribute(attr_address)?;
+            let attr_next = attribute.attr_next;
 
-pub fn append_attribute(node_address: u64, attribute_offset: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
+            chain.push((attr_address, attribute));
+            attr_address = attr_next;
+        }
 
-    stream.seek(SeekFrom::Start(node_address))?;
+        Ok(chain)
+    }
 
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<AttributeBlock>());
-    stream.read_to_end(&mut buffer)?;
-    let mut attribute_block = map_bincode_error!(deserialize::<AttributeBlock>(&buffer))?;
+    //  Set key to value on a node or relationship, replacing any previous value
+    pub fn set_property(&mut self, entity: Entity, key: &str, value: PropertyValue) -> Result<()> {
+        self.logged_op(WalOp::UpdateAttribute, |db| {
+            let (address, attr_head) = db.entity_attr_head(entity)?;
+            let chain = db.attribute_chain(attr_head)?;
+
+            // node properties are indexed by the block of their node
+            let text = matches!(entity, Entity::Node(_)) && db.is_text_indexed(key)?;
+            if let Entity::Node(_) = entity {
+                if let Some((_, old)) = chain.iter().find(|(_, attr)| attr.key == key) {
+                    db.unindex_property(key, &old.value, address)?;
+                }
+                db.index_property(key, &value, address)?;
+            }
+            if text {
+                db.unindex_text(address)?;
+            }
+
+            if let Some((offset, attribute)) = chain.iter().find(|(_, attr)| attr.key == key) {
+                // existing key, rewrite its block in place
+                let attribute_block = db.read_block::<AttributeBlock>(*offset)?;
+                db.free_string(&attribute_block.property)?;
+
+                let attribute = Attribute {
+                    value,
+                    ..attribute.clone()
+                };
+                db.write_attribute(*offset, &attribute)?;
+            } else {
+                // take first empty from the free list, growing the file if needed
+                let offset = db.allocate_block()?;
+                let attribute = Attribute {
+                    key: key.to_string(),
+                    value,
+                    attr_next: 0,
+                };
+                db.write_attribute(offset, &attribute)?;
+
+                // link onto the tail of the chain
+                match chain.last() {
+                    Some((tail_offset, _)) => {
+                        let mut tail_block = db.read_block::<AttributeBlock>(*tail_offset)?;
+                        tail_block.attr_next = offset;
+                        db.write_block(*tail_offset, &tail_block)?;
+                    }
+                    None => db.set_entity_attr_head(entity, address, offset)?,
+                }
+            }
+
+            // the text index reads the node again now the value is in place
+            if text {
+                db.index_text(address)?;
+            }
+
+            match entity {
+                Entity::Node(id) => {
+                    db.enforce_schema(id)?;
+                    db.enforce_constraints(id)
+                }
+                Entity::Relationship(offset) => db.enforce_relationship_schema(offset),
+            }
+        })
+    }
 
-    if attribute_block.attribute.attr_next == 0 {
-        attribute_block.attribute.attr_next = attribute_offset;
-        stream.seek(SeekFrom::Start(node_address))?;
+    //  Value of key on a node or relationship
+    pub fn get_property(&self, entity: Entity, key: &str) -> Result<PropertyValue> {
+        let (_, attr_head) = self.entity_attr_head(entity)?;
 
-        let serialized_attribute_block = map_bincode_error!(serialize(&attribute_block))?;
-        stream.write_all(&serialized_attribute_block)?;
-        return Ok(());
-    } else {
-        append_attribute(attribute_block.attribute.attr_next, attribute_offset)?;
+        self.attribute_chain(attr_head)?
+            .into_iter()
+            .find(|(_, attribute)| attribute.key == key)
+            .map(|(_, attribute)| attribute.value)
+            .ok_or_else(|| GdbError::not_found(RecordKind::Attribute, key))
     }
 
-    Ok(())
-}
-
-// Assigns attribute to EMPTY_BLOCK and writes to disk
-pub fn delete_attribute(attribute: Attribute) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
+    //  Every key and value of a node or relationship, in the order they were first set
+    pub fn get_properties(&self, entity: Entity) -> Result<Vec<(String, PropertyValue)>> {
+        let (_, attr_head) = self.entity_attr_head(entity)?;
 
-    let empty_block: NodeBlock = Default::default();
-    let empty_block_srl = map_bincode_error!(serialize(&empty_block))?; // serialise
+        Ok(self
+            .attribute_chain(attr_head)?
+            .into_iter()
+            .map(|(_, attribute)| (attribute.key, attribute.value))
+            .collect())
+    }
 
-    let attr_address = get_attribute_address(&attribute)?;
+    //  Remove key from a node or relationship, returning the value it held
+    pub fn remove_property(&mut self, entity: Entity, key: &str) -> Result<PropertyValue> {
+        self.logged_op(WalOp::DeleteAttribute, |db| {
+            let (address, attr_head) = db.entity_attr_head(entity)?;
+            let chain = db.attribute_chain(attr_head)?;
+
+            let Some(i) = chain.iter().position(|(_, attribute)| attribute.key == key) else {
+                return Err(GdbError::not_found(RecordKind::Attribute, key));
+            };
+            let (offset, attribute) = &chain[i];
+
+            let text = matches!(entity, Entity::Node(_)) && db.is_text_indexed(key)?;
+            if let Entity::Node(_) = entity {
+                db.unindex_property(key, &attribute.value, address)?;
+            }
+            if text {
+                db.unindex_text(address)?;
+            }
+
+            // unlink from the previous block (or the owner) before freeing
+            if i == 0 {
+                db.set_entity_attr_head(entity, address, attribute.attr_next)?;
+            } else {
+                let prev_offset = chain[i - 1].0;
+                let mut prev_block = db.read_block::<AttributeBlock>(prev_offset)?;
+                prev_block.attr_next = attribute.attr_next;
+                db.write_block(prev_offset, &prev_block)?;
+            }
+
+            db.delete_record_offset(*offset)?;
+            if text {
+                db.index_text(address)?;
+            }
+
+            match entity {
+                Entity::Node(id) => {
+                    db.enforce_schema(id)?;
+                    db.enforce_constraints(id)?;
+                }
+                Entity::Relationship(offset) => db.enforce_relationship_schema(offset)?,
+            }
+
+            Ok(attribute.value.clone())
+        })
+    }
 
-    // read current header information
-    let mut header_buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut header_buffer)?;
-    let header = map_bincode_error!(deserialize::<Header>(&header_buffer))?;
+    //  Print all attributes of a node.
+    pub fn print_attributes(&self, node_offset: u64) -> Result<()> {
+        let node = self.get_node(node_offset)?;
 
-    stream.seek(SeekFrom::Start(attr_address))?;
-    stream.write_all(&empty_block_srl)?;
+        for (_, attribute) in self.attribute_chain(node.attr_head)? {
+            println!("Attribute: {} = {:?}\r", attribute.key, attribute.value);
+        }
 
-    if header.first_empty > attr_address {
-        new_first_empty()?;
+        Ok(())
     }
 
-    Ok(())
-}
-
-// traverse linked list of attributes and delete along the tree
-pub fn delete_attributes(attr_head: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    stream.seek(SeekFrom::Start(attr_head))?;
+    // traverse linked list of attributes and delete along the tree, the owner's attr_head is left for the caller to reset or free
+    pub(crate) fn delete_attributes(&mut self, attr_head: u64) -> Result<()> {
+        self.logged_op(WalOp::DeleteAttribute, |db| {
+            let mut attr_address = attr_head;
 
-    let mut attr_address = attr_head;
+            while attr_address > 0 {
+                // read attr information
+                let attr_block = db.read_block::<AttributeBlock>(attr_address)?;
 
-    while attr_address > 0 {
-        // read attr information
-        let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<AttributeBlock>());
-        stream.read_to_end(&mut buffer)?;
-        let attr_block = map_bincode_error!(deserialize::<AttributeBlock>(&buffer))?;
+                db.delete_record_offset(attr_address)?;
 
-        delete_attribute(attr_block.attribute)?;
+                attr_address = attr_block.attr_next
+            }
 
-        attr_address = attr_block.attribute.attr_next
+            Ok(())
+        })
     }
-
-    Ok(())
 }
@@ -0,0 +1,1073 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Consistency checker (fsck) with optional repair.
+
+    check() walks every block of the file and reports:
+        - blocks whose checksum or encoding is broken
+        - rlt_head / rlt_next / in_head / in_next / attr_head / attr_next,
+          string overflow, dictionary and label pointers pointing outside
+          the block area or at a block of the wrong type
+        - rlt_prev / in_prev / member_prev not pointing back at the previous
+          relationship or label of their chain
+        - chains that loop back on themselves
+        - overflow, dictionary, label and index blocks nothing refers to
+        - relationships whose node_from / node_to is not a live node, or
+          whose type is missing from the dictionary
+        - labels whose node is not a live node, or whose name is missing
+          from the dictionary
+        - an id, name, property or full-text index that is broken, out of
+          order or disagrees with the nodes in the file, or full-text
+          counters in the header that do not match the nodes
+        - a free list that points at live blocks, loops, or misses empties
+        - header total_blocks / db_size not matching the blocks in the file,
+          or a next_node_id that a node already holds
+        - a node holding the reserved id u64::MAX, which the id counter
+          cannot move past
+        - nodes sharing an id, the id index holds one node per id
+
+    repair() fixes what it reports as one logged operation: broken chains
+    are truncated at the bad link and back pointers reset to match,
+    relationships with a missing endpoint are deleted (and unlinked from
+    the chains they were in), as are labels on a missing node or with a
+    missing name, unknown relationship types are cleared, every node but
+    the first holding a shared id gets a new one from the counter (with the
+    relationships on its chains pointing at it again), corrupt and
+    orphaned blocks are freed, then the free list and the header are
+    rebuilt from scratch. Indexes are rebuilt from the repaired nodes last,
+    a bad one is thrown away first.
+*/
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+
+use crate::database::Database;
+use crate::disk::Slot;
+use crate::error::{GdbError, Result};
+use crate::index::name_index_key;
+use crate::property_index::{is_property_index, property_index_key};
+use crate::text_index::{document_terms, term_key};
+use crate::types::DictionaryKind;
+use crate::types::{BlockType, EmptyBlock}; // import Block Types
+use crate::types::{BLOCK_SIZE, HEADER_SIZE};
+use crate::wal::WalOp;
+
+// Pointer field of a block (or of the header) that links to another block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    RltHead,
+    RltNext,
+    RltPrev,
+    InHead,
+    InNext,
+    InPrev,
+    AttrHead,
+    AttrNext,
+    NodeFrom,
+    NodeTo,
+    FirstEmpty,
+    NextEmpty,
+    Overflow,
+    OverflowNext,
+    RltType,
+    DictionaryHead,
+    DictionaryNext,
+    LabelHead,
+    LabelNext,
+    LabelNode,
+    MemberHead,
+    MemberNext,
+    MemberPrev,
+    IdIndex,
+    NameIndex,
+    TextIndex,
+    IndexHead,
+    IndexChild,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Field::RltHead => "rlt_head",
+            Field::RltNext => "rlt_next",
+            Field::RltPrev => "rlt_prev",
+            Field::InHead => "in_head",
+            Field::InNext => "in_next",
+            Field::InPrev => "in_prev",
+            Field::AttrHead => "attr_head",
+            Field::AttrNext => "attr_next",
+            Field::NodeFrom => "node_from",
+            Field::NodeTo => "node_to",
+            Field::FirstEmpty => "first_empty",
+            Field::NextEmpty => "next_empty",
+            Field::Overflow => "overflow",
+            Field::OverflowNext => "next",
+            Field::RltType => "rlt_type",
+            Field::DictionaryHead => "dictionary_head",
+            Field::DictionaryNext => "next",
+            Field::LabelHead => "label_head",
+            Field::LabelNext => "label_next",
+            Field::LabelNode => "node",
+            Field::MemberHead => "head",
+            Field::MemberNext => "member_next",
+            Field::MemberPrev => "member_prev",
+            Field::IdIndex => "id_index",
+            Field::NameIndex => "name_index",
+            Field::TextIndex => "text_index",
+            Field::IndexHead => "head",
+            Field::IndexChild => "children",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Single problem found by the checker, offset is the block holding the bad value (0 = header)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    // checksum or decode failure
+    CorruptBlock {
+        offset: u64,
+    },
+    // points outside the block area or at the wrong kind of block
+    BadPointer {
+        offset: u64,
+        field: Field,
+        target: u64,
+    },
+    // following field revisits a block
+    Cycle {
+        offset: u64,
+        field: Field,
+    },
+    // relationship endpoint is not a live node
+    DanglingEndpoint {
+        offset: u64,
+        field: Field,
+        id: u64,
+    },
+    // empty block missing from the free list
+    LostEmptyBlock {
+        offset: u64,
+    },
+    // header field disagrees with the blocks in the file
+    HeaderMismatch {
+        field: &'static str,
+        found: u64,
+        expected: u64,
+    },
+    // no final block after the last block
+    MissingFinalBlock {
+        offset: u64,
+    },
+    // overflow, dictionary, label or index block nothing refers to
+    OrphanBlock {
+        offset: u64,
+    },
+    // relationship type or label id not in the dictionary
+    UnknownType {
+        offset: u64,
+        id: u64,
+    },
+    // node missing from an index, or an index entry for a node that isn't there
+    IndexMismatch {
+        offset: u64,
+        field: Field,
+        key: u64,
+    },
+    // node holds the reserved id u64::MAX, the id counter cannot move past it
+    ReservedId {
+        offset: u64,
+        id: u64,
+    },
+    // node holds an id an earlier node already holds
+    DuplicateId {
+        offset: u64,
+        id: u64,
+        first: u64,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::CorruptBlock { offset } => write!(f, "@{}: corrupt block", offset),
+            Violation::BadPointer {
+                offset,
+                field,
+                target,
+            } => write!(
+                f,
+                "@{}: {} points at invalid block {}",
+                offset, field, target
+            ),
+            Violation::Cycle { offset, field } => {
+                write!(f, "@{}: {} closes a cycle", offset, field)
+            }
+            Violation::DanglingEndpoint { offset, field, id } => {
+                write!(f, "@{}: {} refers to missing node {}", offset, field, id)
+            }
+            Violation::LostEmptyBlock { offset } => {
+                write!(f, "@{}: empty block not on the free list", offset)
+            }
+            Violation::HeaderMismatch {
+                field,
+                found,
+                expected,
+            } => write!(f, "header {} is {}, expected {}", field, found, expected),
+            Violation::MissingFinalBlock { offset } => {
+                write!(f, "@{}: final block missing", offset)
+            }
+            Violation::OrphanBlock { offset } => {
+                write!(f, "@{}: block not referenced", offset)
+            }
+            Violation::UnknownType { offset, id } => {
+                write!(f, "@{}: id {} not in the dictionary", offset, id)
+            }
+            Violation::IndexMismatch { offset, field, key } => {
+                write!(
+                    f,
+                    "@{}: {} disagrees with the nodes about key {}",
+                    offset, field, key
+                )
+            }
+            Violation::ReservedId { offset, id } => {
+                write!(f, "@{}: node holds reserved id {}", offset, id)
+            }
+            Violation::DuplicateId { offset, id, first } => {
+                write!(
+                    f,
+                    "@{}: node id {} is already held by @{}",
+                    offset, id, first
+                )
+            }
+        }
+    }
+}
+
+// Result of check() or repair()
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub violations: Vec<Violation>, // everything found, before any repair
+    pub repaired: bool,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+// Everything learnt from one walk over the file
+struct Inspection {
+    violations: Vec<Violation>,
+    total_blocks: u64,     // blocks in front of the final block
+    slots: Vec<Slot>,      // repaired content of every block
+    dirty: HashSet<usize>, // slots the repair has to rewrite
+    write_final: bool,     // final block has to be written
+    dictionary_head: u64,  // repaired header dictionary_head
+    id_index: u64,         // repaired header id_index
+    name_index: u64,       // repaired header name_index
+    text_index: u64,       // repaired header text_index
+    next_node_id: u64,     // repaired header next_node_id
+    rebuild_index: bool,   // indexes are dropped by the repair
+}
+
+impl Inspection {
+    fn slot_of(&self, offset: u64) -> Option<usize> {
+        if offset < HEADER_SIZE || !(offset - HEADER_SIZE).is_multiple_of(BLOCK_SIZE) {
+            return None;
+        }
+
+        let slot = (offset - HEADER_SIZE) / BLOCK_SIZE;
+        (slot < self.total_blocks).then_some(slot as usize)
+    }
+
+    fn pointer(&self, slot: usize, field: Field) -> u64 {
+        match (&self.slots[slot], field) {
+            (Slot::Node(node), Field::RltHead) => node.rlt_head,
+            (Slot::Node(node), Field::InHead) => node.in_head,
+            (Slot::Node(node), Field::AttrHead) => node.attr_head,
+            (Slot::Node(node), Field::Overflow) => node.name.overflow,
+            (Slot::Node(node), Field::LabelHead) => node.label_head,
+            (Slot::Relationship(rlt), Field::RltNext) => rlt.rlt_next,
+            (Slot::Relationship(rlt), Field::InNext) => rlt.in_next,
+            (Slot::Relationship(rlt), Field::RltPrev) => rlt.rlt_prev,
+            (Slot::Relationship(rlt), Field::InPrev) => rlt.in_prev,
+            (Slot::Relationship(rlt), Field::AttrHead) => rlt.attr_head,
+            (Slot::Attribute(attr), Field::AttrNext) => attr.attr_next,
+            (Slot::Attribute(attr), Field::Overflow) => attr.property.overflow,
+            (Slot::Overflow(overflow), Field::OverflowNext) => overflow.next,
+            (Slot::Dictionary(entry), Field::DictionaryNext) => entry.next,
+            (Slot::Dictionary(entry), Field::Overflow) => entry.name.overflow,
+            (Slot::Dictionary(entry), Field::MemberHead) => entry.head,
+            (Slot::Label(label), Field::LabelNext) => label.label_next,
+            (Slot::Label(label), Field::MemberNext) => label.member_next,
+            (Slot::Label(label), Field::MemberPrev) => label.member_prev,
+            _ => 0,
+        }
+    }
+
+    fn set_pointer(&mut self, slot: usize, field: Field, value: u64) {
+        match (&mut self.slots[slot], field) {
+            (Slot::Node(node), Field::RltHead) => node.rlt_head = value,
+            (Slot::Node(node), Field::InHead) => node.in_head = value,
+            (Slot::Node(node), Field::AttrHead) => node.attr_head = value,
+            (Slot::Node(node), Field::Overflow) => node.name.overflow = value,
+            (Slot::Node(node), Field::LabelHead) => node.label_head = value,
+            (Slot::Relationship(rlt), Field::RltNext) => rlt.rlt_next = value,
+            (Slot::Relationship(rlt), Field::InNext) => rlt.in_next = value,
+            (Slot::Relationship(rlt), Field::RltPrev) => rlt.rlt_prev = value,
+            (Slot::Relationship(rlt), Field::InPrev) => rlt.in_prev = value,
+            (Slot::Relationship(rlt), Field::AttrHead) => rlt.attr_head = value,
+            (Slot::Attribute(attr), Field::AttrNext) => attr.attr_next = value,
+            (Slot::Attribute(attr), Field::Overflow) => attr.property.overflow = value,
+            (Slot::Overflow(overflow), Field::OverflowNext) => overflow.next = value,
+            (Slot::Dictionary(entry), Field::DictionaryNext) => entry.next = value,
+            (Slot::Dictionary(entry), Field::Overflow) => entry.name.overflow = value,
+            (Slot::Dictionary(entry), Field::MemberHead) => entry.head = value,
+            (Slot::Label(label), Field::LabelNext) => label.label_next = value,
+            (Slot::Label(label), Field::MemberNext) => label.member_next = value,
+            (Slot::Label(label), Field::MemberPrev) => label.member_prev = value,
+            _ => return,
+        }
+        self.dirty.insert(slot);
+    }
+
+    /*
+        Walk the chain starting at slot.field, truncating it at the first link
+        to a block of the wrong kind or back into the chain. Links to a
+        relationship or label dropped by the checker are skipped over.
+        Returns the slots left on the chain, including slot itself.
+    */
+    fn check_chain(
+        &mut self,
+        slot: usize,
+        field: Field,
+        dropped: &BTreeMap<usize, Slot>,
+    ) -> HashSet<usize> {
+        let mut prev = (slot, field);
+        let mut visited = HashSet::from([slot]);
+        let mut target = self.pointer(slot, field);
+
+        while target != 0 {
+            let offset = HEADER_SIZE + prev.0 as u64 * BLOCK_SIZE;
+            let next_slot = self.slot_of(target);
+
+            if next_slot.is_some_and(|next| !visited.insert(next)) {
+                self.violations.push(Violation::Cycle {
+                    offset,
+                    field: prev.1,
+                });
+                self.set_pointer(prev.0, prev.1, 0);
+                break;
+            }
+
+            // unlink a dropped block and carry on with its successor
+            if let Some(successor) = next_slot
+                .and_then(|next| dropped.get(&next))
+                .and_then(|block| dropped_successor(block, prev.1))
+            {
+                target = successor;
+                self.set_pointer(prev.0, prev.1, target);
+                continue;
+            }
+
+            let next_field = match (next_slot.map(|next| &self.slots[next]), prev.1) {
+                (Some(Slot::Relationship(_)), Field::RltHead | Field::RltNext) => Field::RltNext,
+                (Some(Slot::Relationship(_)), Field::InHead | Field::InNext) => Field::InNext,
+                (Some(Slot::Attribute(_)), Field::AttrHead | Field::AttrNext) => Field::AttrNext,
+                (Some(Slot::Overflow(_)), Field::Overflow | Field::OverflowNext) => {
+                    Field::OverflowNext
+                }
+                (Some(Slot::Dictionary(_)), Field::DictionaryNext) => Field::DictionaryNext,
+                (Some(Slot::Label(_)), Field::LabelHead | Field::LabelNext) => Field::LabelNext,
+                (Some(Slot::Label(_)), Field::MemberHead | Field::MemberNext) => Field::MemberNext,
+                _ => {
+                    self.violations.push(Violation::BadPointer {
+                        offset,
+                        field: prev.1,
+                        target,
+                    });
+                    self.set_pointer(prev.0, prev.1, 0);
+                    break;
+                }
+            };
+
+            let next = next_slot.unwrap();
+
+            // relationship and member chains are doubly linked, the block must point back the way we came
+            if let Some(back) = back_field(next_field) {
+                let expected = if prev.1 == next_field { offset } else { 0 };
+                let found = self.pointer(next, back);
+
+                if found != expected {
+                    self.violations.push(Violation::BadPointer {
+                        offset: HEADER_SIZE + next as u64 * BLOCK_SIZE,
+                        field: back,
+                        target: found,
+                    });
+                    self.set_pointer(next, back, expected);
+                }
+            }
+
+            prev = (next, next_field);
+            target = self.pointer(next, next_field);
+        }
+
+        visited
+    }
+    /*
+        Walk the index tree at root, field of the block at holder (0 for the
+        header). Its leaves, left to
+        right, must hold exactly the expected (key, offset) entries, in
+        order. Index blocks reached are added to indexed. Any problem marks
+        every index for a rebuild.
+    */
+    fn check_index(
+        &mut self,
+        holder: u64,
+        field: Field,
+        root: u64,
+        expected: &BTreeSet<(u64, u64)>,
+        indexed: &mut HashSet<usize>,
+    ) {
+        let mut entries = Vec::new(); // (leaf offset, entry)
+        let mut pending = vec![(holder, field, root)];
+
+        while let Some((holder, holder_field, target)) = pending.pop() {
+            if target == 0 {
+                continue;
+            }
+
+            let next_slot = self.slot_of(target);
+            let Some(next) = next_slot.filter(|&next| matches!(self.slots[next], Slot::Index(_)))
+            else {
+                self.violations.push(Violation::BadPointer {
+                    offset: holder,
+                    field: holder_field,
+                    target,
+                });
+                self.rebuild_index = true;
+                return;
+            };
+
+            if !indexed.insert(next) {
+                self.violations.push(Violation::Cycle {
+                    offset: holder,
+                    field: holder_field,
+                });
+                self.rebuild_index = true;
+                return;
+            }
+
+            let Slot::Index(index_block) = &self.slots[next] else {
+                unreachable!();
+            };
+
+            if index_block.leaf {
+                entries.extend(index_block.entries.iter().map(|entry| (target, *entry)));
+            } else if index_block.children.len() != index_block.entries.len() + 1 {
+                self.violations
+                    .push(Violation::CorruptBlock { offset: target });
+                self.rebuild_index = true;
+                return;
+            } else {
+                // pushed backwards so the leftmost child comes off first
+                for child in index_block.children.iter().rev() {
+                    pending.push((target, Field::IndexChild, *child));
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for (j, (leaf_offset, entry)) in entries.iter().enumerate() {
+            let in_order = j == 0 || entries[j - 1].1 < *entry;
+
+            if !in_order || !expected.contains(entry) {
+                self.violations.push(Violation::IndexMismatch {
+                    offset: *leaf_offset,
+                    field,
+                    key: entry.0,
+                });
+                self.rebuild_index = true;
+            }
+            seen.insert(*entry);
+        }
+
+        for (key, offset) in expected {
+            if !seen.contains(&(*key, *offset)) {
+                self.violations.push(Violation::IndexMismatch {
+                    offset: *offset,
+                    field,
+                    key: *key,
+                });
+                self.rebuild_index = true;
+            }
+        }
+    }
+}
+
+//  Back pointer matching the next pointer of a doubly linked chain.
+fn back_field(field: Field) -> Option<Field> {
+    match field {
+        Field::RltNext => Some(Field::RltPrev),
+        Field::InNext => Some(Field::InPrev),
+        Field::MemberNext => Some(Field::MemberPrev),
+        _ => None,
+    }
+}
+
+//  Where a chain reaching dropped through field continues, None if dropped was never on such a chain.
+fn dropped_successor(dropped: &Slot, field: Field) -> Option<u64> {
+    match (dropped, field) {
+        (Slot::Relationship(rlt), Field::RltHead | Field::RltNext) => Some(rlt.rlt_next),
+        (Slot::Relationship(rlt), Field::InHead | Field::InNext) => Some(rlt.in_next),
+        (Slot::Label(label), Field::LabelHead | Field::LabelNext) => Some(label.label_next),
+        (Slot::Label(label), Field::MemberHead | Field::MemberNext) => Some(label.member_next),
+        _ => None,
+    }
+}
+
+impl Database {
+    //  Walk the whole file and report every inconsistency found, the file is not modified.
+    pub fn check(&self) -> Result<CheckReport> {
+        let inspection = self.inspect()?;
+
+        Ok(CheckReport {
+            violations: inspection.violations,
+            repaired: false,
+        })
+    }
+
+    //  Check the file and fix every inconsistency found, as a single logged operation.
+    pub fn repair(&mut self) -> Result<CheckReport> {
+        self.logged_op(WalOp::Repair, |db| {
+            let inspection = db.inspect()?;
+
+            if !inspection.violations.is_empty() {
+                db.apply_repair(&inspection)?;
+            }
+
+            Ok(CheckReport {
+                repaired: !inspection.violations.is_empty(),
+                violations: inspection.violations,
+            })
+        })
+    }
+
+    //  Blocks that fit in the file (including writes staged by the current operation).
+    fn blocks_on_disk(&self) -> Result<u64> {
+        let mut len = self.file.metadata()?.len();
+        if let Some((offset, data)) = self.staged.last_key_value() {
+            len = len.max(offset + data.len() as u64);
+        }
+
+        Ok(len.saturating_sub(HEADER_SIZE) / BLOCK_SIZE)
+    }
+
+    fn inspect(&self) -> Result<Inspection> {
+        let header = self.read_header()?;
+        let mut violations = Vec::new();
+
+        // the file really ends at the first final block
+        let blocks_on_disk = self.blocks_on_disk()?;
+        let mut total_blocks = blocks_on_disk;
+        for i in 0..blocks_on_disk {
+            if let Ok(block) = self.get_block(HEADER_SIZE + i * BLOCK_SIZE) {
+                if block.block_type == BlockType::Final {
+                    total_blocks = i;
+                    break;
+                }
+            }
+        }
+
+        let write_final = total_blocks == blocks_on_disk;
+        if write_final {
+            violations.push(Violation::MissingFinalBlock {
+                offset: HEADER_SIZE + total_blocks * BLOCK_SIZE,
+            });
+        }
+
+        let db_size = HEADER_SIZE + (total_blocks + 1) * BLOCK_SIZE;
+        if header.total_blocks != total_blocks {
+            violations.push(Violation::HeaderMismatch {
+                field: "total_blocks",
+                found: header.total_blocks,
+                expected: total_blocks,
+            });
+        }
+        if header.db_size != db_size {
+            violations.push(Violation::HeaderMismatch {
+                field: "db_size",
+                found: header.db_size,
+                expected: db_size,
+            });
+        }
+
+        // decode every block
+        let mut slots = Vec::with_capacity(total_blocks as usize);
+        let mut empties = BTreeMap::new(); // slot -> next_empty
+        let mut dirty = HashSet::new();
+
+        for i in 0..total_blocks {
+            let offset = HEADER_SIZE + i * BLOCK_SIZE;
+
+            let slot = match self.read_slot(offset) {
+                Ok(Slot::Free) => {
+                    let empty_block = self.read_block::<EmptyBlock>(offset)?;
+                    empties.insert(i as usize, empty_block.next_empty);
+                    Slot::Free
+                }
+                Ok(slot) => slot,
+                Err(GdbError::Corrupt { .. }) => {
+                    violations.push(Violation::CorruptBlock { offset });
+                    dirty.insert(i as usize);
+                    Slot::Free
+                }
+                Err(err) => return Err(err),
+            };
+
+            slots.push(slot);
+        }
+
+        let mut inspection = Inspection {
+            violations,
+            total_blocks,
+            slots,
+            dirty,
+            write_final,
+            dictionary_head: header.dictionary_head,
+            id_index: header.id_index,
+            name_index: header.name_index,
+            text_index: header.text_index,
+            next_node_id: header.next_node_id,
+            rebuild_index: false,
+        };
+
+        // dictionary chain, starting from the header
+        let mut dictionary = HashSet::new();
+        if header.dictionary_head != 0 {
+            match inspection.slot_of(header.dictionary_head) {
+                Some(first) if matches!(inspection.slots[first], Slot::Dictionary(_)) => {
+                    dictionary =
+                        inspection.check_chain(first, Field::DictionaryNext, &BTreeMap::new());
+                }
+                _ => {
+                    inspection.violations.push(Violation::BadPointer {
+                        offset: 0,
+                        field: Field::DictionaryHead,
+                        target: header.dictionary_head,
+                    });
+                    inspection.dictionary_head = 0;
+                }
+            }
+        }
+
+        let dictionary_ids = |kind: DictionaryKind| -> HashSet<u64> {
+            dictionary
+                .iter()
+                .filter_map(|&i| match &inspection.slots[i] {
+                    Slot::Dictionary(entry) if entry.kind == kind => Some(entry.id),
+                    _ => None,
+                })
+                .collect()
+        };
+        let type_ids = dictionary_ids(DictionaryKind::RelationshipType);
+        let label_ids = dictionary_ids(DictionaryKind::Label);
+
+        // relationship endpoints must be live nodes
+        let node_ids: HashSet<u64> = inspection
+            .slots
+            .iter()
+            .filter_map(|slot| match slot {
+                Slot::Node(node) => Some(node.id),
+                _ => None,
+            })
+            .collect();
+
+        // every id held by one node only
+        let mut holders = BTreeMap::new(); // id -> first slot holding it
+        let mut duplicates = Vec::new();
+        for (i, slot) in inspection.slots.iter().enumerate() {
+            let Slot::Node(node) = slot else {
+                continue;
+            };
+            if let Some(&first) = holders.get(&node.id) {
+                inspection.violations.push(Violation::DuplicateId {
+                    offset: HEADER_SIZE + i as u64 * BLOCK_SIZE,
+                    id: node.id,
+                    first: HEADER_SIZE + first as u64 * BLOCK_SIZE,
+                });
+                duplicates.push(i);
+            } else {
+                holders.insert(node.id, i);
+            }
+        }
+
+        // the id counter must be past every id in use
+        let max_id = node_ids.iter().max().copied().unwrap_or(0);
+        match max_id.checked_add(1) {
+            Some(expected) if header.next_node_id < expected => {
+                inspection.violations.push(Violation::HeaderMismatch {
+                    field: "next_node_id",
+                    found: header.next_node_id,
+                    expected,
+                });
+                inspection.next_node_id = expected;
+            }
+            Some(_) => {}
+            None => {
+                let slot = inspection
+                    .slots
+                    .iter()
+                    .position(|slot| matches!(slot, Slot::Node(node) if node.id == max_id))
+                    .unwrap_or(0);
+                inspection.violations.push(Violation::ReservedId {
+                    offset: HEADER_SIZE + slot as u64 * BLOCK_SIZE,
+                    id: max_id,
+                });
+                inspection.next_node_id = u64::MAX; // no id left to hand out
+            }
+        }
+
+        let mut dropped = BTreeMap::new(); // slot -> relationship or label being deleted
+        for i in 0..inspection.slots.len() {
+            let Slot::Relationship(rlt) = inspection.slots[i] else {
+                continue;
+            };
+
+            let offset = HEADER_SIZE + i as u64 * BLOCK_SIZE;
+            if rlt.rlt_type != 0 && !type_ids.contains(&rlt.rlt_type) {
+                inspection.violations.push(Violation::UnknownType {
+                    offset,
+                    id: rlt.rlt_type,
+                });
+                if let Slot::Relationship(rlt) = &mut inspection.slots[i] {
+                    rlt.rlt_type = 0; // keep the relationship, untyped
+                }
+                inspection.dirty.insert(i);
+            }
+
+            for (field, id) in [
+                (Field::NodeFrom, rlt.node_from),
+                (Field::NodeTo, rlt.node_to),
+            ] {
+                if !node_ids.contains(&id) {
+                    inspection
+                        .violations
+                        .push(Violation::DanglingEndpoint { offset, field, id });
+                }
+            }
+
+            if !node_ids.contains(&rlt.node_from) || !node_ids.contains(&rlt.node_to) {
+                dropped.insert(i, std::mem::replace(&mut inspection.slots[i], Slot::Free));
+                inspection.dirty.insert(i);
+            }
+        }
+
+        // labels must name a dictionary entry and point at a live node
+        for i in 0..inspection.slots.len() {
+            let Slot::Label(label) = &inspection.slots[i] else {
+                continue;
+            };
+
+            let offset = HEADER_SIZE + i as u64 * BLOCK_SIZE;
+            let mut keep = true;
+
+            if !label_ids.contains(&label.label) {
+                inspection.violations.push(Violation::UnknownType {
+                    offset,
+                    id: label.label,
+                });
+                keep = false;
+            }
+
+            let node_slot = inspection.slot_of(label.node);
+            if !node_slot.is_some_and(|node| matches!(inspection.slots[node], Slot::Node(_))) {
+                inspection.violations.push(Violation::BadPointer {
+                    offset,
+                    field: Field::LabelNode,
+                    target: label.node,
+                });
+                keep = false;
+            }
+
+            if !keep {
+                dropped.insert(i, std::mem::replace(&mut inspection.slots[i], Slot::Free));
+                inspection.dirty.insert(i);
+            }
+        }
+
+        // relationship, attribute, label and overflow chains
+        let mut claimed = HashSet::new(); // overflow blocks holding part of a string
+        let mut labelled = HashSet::new(); // label blocks on a node's or a label's chain
+        for i in 0..inspection.slots.len() {
+            match inspection.slots[i] {
+                Slot::Node(_) => {
+                    inspection.check_chain(i, Field::RltHead, &dropped);
+                    inspection.check_chain(i, Field::InHead, &dropped);
+                    inspection.check_chain(i, Field::AttrHead, &dropped);
+                    claimed.extend(inspection.check_chain(i, Field::Overflow, &dropped));
+                    labelled.extend(inspection.check_chain(i, Field::LabelHead, &dropped));
+                }
+                Slot::Relationship(_) => {
+                    inspection.check_chain(i, Field::AttrHead, &dropped);
+                }
+                Slot::Attribute(_) => {
+                    claimed.extend(inspection.check_chain(i, Field::Overflow, &dropped));
+                }
+                Slot::Dictionary(ref entry) if dictionary.contains(&i) => {
+                    // only labels head a chain of members, indexes head a tree
+                    let label = entry.kind == DictionaryKind::Label;
+                    claimed.extend(inspection.check_chain(i, Field::Overflow, &dropped));
+                    if label {
+                        labelled.extend(inspection.check_chain(i, Field::MemberHead, &dropped));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // later holders of a shared id are renumbered, relationships follow the chain they are on
+        for i in duplicates {
+            let id = inspection.next_node_id;
+            if id == u64::MAX {
+                break; // no id left to hand out, reported above
+            }
+            inspection.next_node_id += 1;
+
+            if let Slot::Node(node) = &mut inspection.slots[i] {
+                node.id = id;
+            }
+            inspection.dirty.insert(i);
+
+            for (head, next) in [
+                (Field::RltHead, Field::RltNext),
+                (Field::InHead, Field::InNext),
+            ] {
+                let mut target = inspection.pointer(i, head);
+                while let Some(rlt) = inspection.slot_of(target) {
+                    if let Slot::Relationship(relationship) = &mut inspection.slots[rlt] {
+                        match head {
+                            Field::RltHead => relationship.node_from = id,
+                            _ => relationship.node_to = id,
+                        }
+                    }
+                    inspection.dirty.insert(rlt);
+                    target = inspection.pointer(rlt, next);
+                }
+            }
+            inspection.rebuild_index = true; // entries for the old id point at both nodes
+        }
+
+        // indexes, each must hold one entry per node
+        let mut id_entries = BTreeSet::new();
+        let mut name_entries = BTreeSet::new();
+        let mut documents = Vec::new(); // (offset, name, properties) of every node
+        for i in 0..inspection.slots.len() {
+            let Slot::Node(node) = &inspection.slots[i] else {
+                continue;
+            };
+
+            let offset = HEADER_SIZE + i as u64 * BLOCK_SIZE;
+            id_entries.insert((node.id, offset));
+            let Ok(name) = self.load_string(&node.name, offset) else {
+                inspection.rebuild_index = true; // name itself is broken, reported above
+                continue;
+            };
+            name_entries.insert((name_index_key(&name), offset));
+
+            // the chain was repaired above, follow it through the slots
+            let mut properties = Vec::new();
+            let mut target = inspection.pointer(i, Field::AttrHead);
+            while let Some(attr) = inspection.slot_of(target) {
+                match self.read_attribute(target) {
+                    Ok(attribute) => properties.push((attribute.key, attribute.value)),
+                    Err(_) => inspection.rebuild_index = true,
+                }
+                target = inspection.pointer(attr, Field::AttrNext);
+            }
+
+            documents.push((offset, name, properties));
+        }
+
+        let mut indexes = vec![
+            (0, Field::IdIndex, header.id_index, id_entries),
+            (0, Field::NameIndex, header.name_index, name_entries),
+        ];
+
+        // property indexes, one entry per indexed value of a node
+        let mut text_keys = Vec::new();
+        for &i in &dictionary {
+            let Slot::Dictionary(entry) = &inspection.slots[i] else {
+                continue;
+            };
+            if !is_property_index(entry.kind) && entry.kind != DictionaryKind::TextIndex {
+                continue;
+            }
+
+            let entry_offset = HEADER_SIZE + i as u64 * BLOCK_SIZE;
+            let Ok(key) = self.load_string(&entry.name, entry_offset) else {
+                inspection.rebuild_index = true; // name itself is broken, reported above
+                continue;
+            };
+            if entry.kind == DictionaryKind::TextIndex {
+                text_keys.push(key);
+                continue;
+            }
+
+            let mut expected = BTreeSet::new();
+            for (offset, _, properties) in &documents {
+                for (_, value) in properties.iter().filter(|(found, _)| *found == key) {
+                    if let Some(index_key) = property_index_key(entry.kind, value) {
+                        expected.insert((index_key, *offset));
+                    }
+                }
+            }
+
+            indexes.push((entry_offset, Field::IndexHead, entry.head, expected));
+        }
+
+        // full-text index, one entry per term of a node and the header counting them
+        let mut text_entries = BTreeSet::new();
+        let mut text_terms = 0;
+        for (offset, name, properties) in &documents {
+            let values = properties
+                .iter()
+                .filter(|(key, _)| text_keys.contains(key))
+                .map(|(_, value)| value);
+            let terms = document_terms(name, values);
+
+            text_terms += terms.len() as u64;
+            text_entries.extend(terms.iter().map(|term| (term_key(term), *offset)));
+        }
+        indexes.push((0, Field::TextIndex, header.text_index, text_entries));
+
+        for (field, found, expected) in [
+            (
+                "text_documents",
+                header.text_documents,
+                documents.len() as u64,
+            ),
+            ("text_terms", header.text_terms, text_terms),
+        ] {
+            if found != expected && !inspection.rebuild_index {
+                inspection.violations.push(Violation::HeaderMismatch {
+                    field,
+                    found,
+                    expected,
+                });
+                inspection.rebuild_index = true; // the rebuild counts them again
+            }
+        }
+
+        let mut indexed = HashSet::new();
+        for (holder, field, root, expected) in &indexes {
+            if !inspection.rebuild_index {
+                inspection.check_index(*holder, *field, *root, expected, &mut indexed);
+            }
+        }
+
+        // a bad index is dropped whole, with the others
+        if inspection.rebuild_index {
+            for i in 0..inspection.slots.len() {
+                match &mut inspection.slots[i] {
+                    Slot::Index(_) => inspection.slots[i] = Slot::Free,
+                    Slot::Dictionary(entry) if is_property_index(entry.kind) => entry.head = 0,
+                    _ => continue,
+                }
+                inspection.dirty.insert(i);
+            }
+            inspection.id_index = 0;
+            inspection.name_index = 0;
+            inspection.text_index = 0;
+        }
+
+        for i in 0..inspection.slots.len() {
+            let orphan = match inspection.slots[i] {
+                Slot::Overflow(_) => !claimed.contains(&i),
+                Slot::Dictionary(_) => !dictionary.contains(&i),
+                Slot::Label(_) => !labelled.contains(&i),
+                Slot::Index(_) => !indexed.contains(&i),
+                _ => false,
+            };
+            if orphan {
+                inspection.violations.push(Violation::OrphanBlock {
+                    offset: HEADER_SIZE + i as u64 * BLOCK_SIZE,
+                });
+                inspection.slots[i] = Slot::Free;
+                inspection.dirty.insert(i);
+            }
+        }
+
+        // free list, must visit every empty block and nothing else
+        let mut visited = HashSet::new();
+        let mut prev = (0, Field::FirstEmpty);
+        let mut target = header.first_empty;
+
+        while target != 0 {
+            let next = match inspection.slot_of(target) {
+                Some(next) if empties.contains_key(&next) => next,
+                _ => {
+                    inspection.violations.push(Violation::BadPointer {
+                        offset: prev.0,
+                        field: prev.1,
+                        target,
+                    });
+                    break;
+                }
+            };
+
+            if !visited.insert(next) {
+                inspection.violations.push(Violation::Cycle {
+                    offset: prev.0,
+                    field: prev.1,
+                });
+                break;
+            }
+
+            prev = (target, Field::NextEmpty);
+            target = empties[&next];
+        }
+
+        for &slot in empties.keys() {
+            if !visited.contains(&slot) {
+                inspection.violations.push(Violation::LostEmptyBlock {
+                    offset: HEADER_SIZE + slot as u64 * BLOCK_SIZE,
+                });
+            }
+        }
+
+        Ok(inspection)
+    }
+
+    fn apply_repair(&mut self, inspection: &Inspection) -> Result<()> {
+        let mut header = self.read_header()?;
+        header.total_blocks = inspection.total_blocks;
+        header.first_empty = 0; // rebuilt below
+        header.dictionary_head = inspection.dictionary_head;
+        header.id_index = inspection.id_index;
+        header.name_index = inspection.name_index;
+        header.text_index = inspection.text_index;
+        header.next_node_id = inspection.next_node_id;
+        header.schema_generation = header.schema_generation.wrapping_add(1); // its dictionary entry may have been freed
+        header.db_size = HEADER_SIZE + (inspection.total_blocks + 1) * BLOCK_SIZE;
+        self.write_header(&header)?;
+
+        if inspection.write_final {
+            self.write_final_block(HEADER_SIZE + inspection.total_blocks * BLOCK_SIZE)?;
+        }
+
+        // walk backwards so the rebuilt free list runs front to back
+        for (i, slot) in inspection.slots.iter().enumerate().rev() {
+            if matches!(slot, Slot::Free) || inspection.dirty.contains(&i) {
+                self.write_slot(HEADER_SIZE + i as u64 * BLOCK_SIZE, slot)?;
+            }
+        }
+
+        // repair may have changed or dropped nodes, indexes are built again from what is left
+        self.rebuild_indexes()
+    }
+}
@@ -0,0 +1,172 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Compaction (vacuum).
+
+    Deleting a record only returns its block to the free list, the file
+    never shrinks. compact() slides every live block down to the front of
+    the file, keeping their order, rewrites every rlt_head, rlt_next,
+    rlt_prev, in_head, in_next, in_prev, attr_head, attr_next, string
+    overflow, dictionary, label and index pointer that pointed at a moved
+    block, drops the free list and truncates the file after the new final
+    block.
+
+    Index entries are ordered by (key, offset). Blocks keep their order, so
+    relocating every offset keeps the entries in order too. A separator may
+    name a block deleted since it was copied up, it moves to where the next
+    live block lands, which keeps it between the same entries.
+
+    Relationship endpoints (node_from / node_to) hold node ids rather than
+    offsets, so they stay valid when nodes move.
+
+    The moves are logged as one write-ahead log record and the file is only
+    truncated once that record has been applied, a crash leaves either the
+    old layout or the compacted one (with unused bytes past the final block).
+*/
+
+use std::collections::BTreeMap;
+
+use crate::database::Database;
+use crate::disk::Slot;
+use crate::error::{GdbError, Result};
+use crate::types::{BLOCK_SIZE, HEADER_SIZE};
+use crate::wal::WalOp;
+
+// Result of a compaction
+#[derive(Debug, PartialEq)]
+pub struct CompactionReport {
+    pub blocks_before: u64,
+    pub blocks_after: u64,
+    pub bytes_reclaimed: u64,
+}
+
+impl Database {
+    //  Move live blocks to the front of the file, fix up every pointer to them and truncate the file.
+    pub fn compact(&mut self) -> Result<CompactionReport> {
+        if self.in_transaction() {
+            return Err(GdbError::TransactionInProgress); // truncation can't be rolled back
+        }
+
+        let blocks_before = self.read_header()?.total_blocks;
+        let size_before = self.file.metadata()?.len();
+
+        let db_size = self.logged_op(WalOp::Compact, |db| {
+            let mut header = db.read_header()?;
+
+            // new offset of every live block, in file order
+            let mut live = Vec::new();
+            let mut moved = BTreeMap::new();
+
+            for i in 0..header.total_blocks {
+                let offset = HEADER_SIZE + i * BLOCK_SIZE;
+
+                match db.read_slot(offset)? {
+                    Slot::Free => {}
+                    slot => {
+                        moved.insert(offset, HEADER_SIZE + live.len() as u64 * BLOCK_SIZE);
+                        live.push((offset, slot));
+                    }
+                }
+            }
+
+            // a pointer to anything but a live block means the file needs repair() first
+            let relocate = |holder: u64, pointer: u64| -> Result<u64> {
+                if pointer == 0 {
+                    return Ok(0);
+                }
+                moved
+                    .get(&pointer)
+                    .copied()
+                    .ok_or(GdbError::Corrupt { offset: holder })
+            };
+
+            let end = HEADER_SIZE + moved.len() as u64 * BLOCK_SIZE;
+            let relocate_separator = |(key, offset): (u64, u64)| -> (u64, u64) {
+                let next_live = moved.range(offset..).next().map(|(_, new)| *new);
+                (key, next_live.unwrap_or(end))
+            };
+
+            for (old_offset, mut slot) in live {
+                match &mut slot {
+                    Slot::Node(node_block) => {
+                        node_block.rlt_head = relocate(old_offset, node_block.rlt_head)?;
+                        node_block.attr_head = relocate(old_offset, node_block.attr_head)?;
+                        node_block.name.overflow = relocate(old_offset, node_block.name.overflow)?;
+                        node_block.label_head = relocate(old_offset, node_block.label_head)?;
+                        node_block.in_head = relocate(old_offset, node_block.in_head)?;
+                    }
+                    Slot::Relationship(relationship) => {
+                        relationship.rlt_next = relocate(old_offset, relationship.rlt_next)?;
+                        relationship.attr_head = relocate(old_offset, relationship.attr_head)?;
+                        relationship.in_next = relocate(old_offset, relationship.in_next)?;
+                        relationship.rlt_prev = relocate(old_offset, relationship.rlt_prev)?;
+                        relationship.in_prev = relocate(old_offset, relationship.in_prev)?;
+                    }
+                    Slot::Attribute(attribute_block) => {
+                        attribute_block.attr_next =
+                            relocate(old_offset, attribute_block.attr_next)?;
+                        attribute_block.property.overflow =
+                            relocate(old_offset, attribute_block.property.overflow)?;
+                    }
+                    Slot::Overflow(overflow_block) => {
+                        overflow_block.next = relocate(old_offset, overflow_block.next)?;
+                    }
+                    Slot::Dictionary(dictionary_block) => {
+                        dictionary_block.next = relocate(old_offset, dictionary_block.next)?;
+                        dictionary_block.name.overflow =
+                            relocate(old_offset, dictionary_block.name.overflow)?;
+                        dictionary_block.head = relocate(old_offset, dictionary_block.head)?;
+                    }
+                    Slot::Label(label_block) => {
+                        label_block.node = relocate(old_offset, label_block.node)?;
+                        label_block.label_next = relocate(old_offset, label_block.label_next)?;
+                        label_block.member_next = relocate(old_offset, label_block.member_next)?;
+                        label_block.member_prev = relocate(old_offset, label_block.member_prev)?;
+                    }
+                    Slot::Index(index_block) if index_block.leaf => {
+                        for entry in index_block.entries.iter_mut() {
+                            entry.1 = relocate(old_offset, entry.1)?;
+                        }
+                    }
+                    Slot::Index(index_block) => {
+                        for separator in index_block.entries.iter_mut() {
+                            *separator = relocate_separator(*separator);
+                        }
+                        for child in index_block.children.iter_mut() {
+                            *child = relocate(old_offset, *child)?;
+                        }
+                    }
+                    Slot::Free => {}
+                }
+
+                db.write_slot(moved[&old_offset], &slot)?;
+            }
+
+            let total_blocks = moved.len() as u64;
+            db.write_final_block(HEADER_SIZE + total_blocks * BLOCK_SIZE)?;
+
+            header.dictionary_head = relocate(0, header.dictionary_head)?;
+            header.id_index = relocate(0, header.id_index)?;
+            header.name_index = relocate(0, header.name_index)?;
+            header.text_index = relocate(0, header.text_index)?;
+            header.total_blocks = total_blocks;
+            header.first_empty = 0; // every block is live, the next allocation grows the file
+            header.db_size = HEADER_SIZE + (total_blocks + 1) * BLOCK_SIZE;
+            db.write_header(&header)?;
+
+            Ok(header.db_size)
+        })?;
+
+        // record is applied, drop everything past the new final block
+        self.file.set_len(db_size)?;
+        self.file.sync_all()?;
+
+        Ok(CompactionReport {
+            blocks_before,
+            blocks_after: self.read_header()?.total_blocks,
+            bytes_reclaimed: size_before.saturating_sub(db_size),
+        })
+    }
+}
@@ -0,0 +1,219 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Constraints.
+
+    create_constraint() declares a rule on the nodes carrying a label:
+
+        - UniqueName: no two of them share a name
+        - Unique: no two of them hold the same value under a property key
+        - Required: each of them holds a value under a property key
+
+    Each constraint is a dictionary entry, named after the label for
+    UniqueName and after the label and key (split by KEY_SEPARATOR) for the
+    others. Declaring one checks the nodes already carrying the label.
+
+    UniqueName finds namesakes through the name index. Unique uses a hash
+    or ordered property index on its key when there is one and otherwise
+    reads every node with the label, so declare an index on the key before
+    loading many nodes into a Unique constraint.
+
+    Every write that can break a constraint (add_label, update_node_name,
+    set_property, remove_property) checks the node it changed before its
+    logged operation ends, so a violation discards the whole write and
+    returns GdbError::ConstraintViolation saying which rule and which nodes.
+
+    Relationship endpoints are always checked: create_relationship refuses
+    a node_from or node_to that is not a node.
+*/
+
+// type imports can be combined, but this is easier to read
+use crate::database::Database;
+use crate::error::{GdbError, Result};
+use crate::types::DictionaryKind; // import Block Types
+use crate::types::{Constraint, Node, PropertyValue}; // import structs
+use crate::wal::WalOp;
+
+const KEY_SEPARATOR: char = '\u{1f}'; // between label and key in a constraint's name
+
+//  Dictionary kind and name declaring constraint.
+fn constraint_entry(constraint: &Constraint) -> (DictionaryKind, String) {
+    match constraint {
+        Constraint::UniqueName { label } => (DictionaryKind::UniqueName, label.clone()),
+        Constraint::Unique { label, key } => (
+            DictionaryKind::UniqueKey,
+            format!("{}{}{}", label, KEY_SEPARATOR, key),
+        ),
+        Constraint::Required { label, key } => (
+            DictionaryKind::RequiredKey,
+            format!("{}{}{}", label, KEY_SEPARATOR, key),
+        ),
+    }
+}
+
+//  Constraint declared by a dictionary entry of kind called name, None for other kinds.
+fn entry_constraint(kind: DictionaryKind, name: &str) -> Option<Constraint> {
+    let split = || {
+        let (label, key) = name.split_once(KEY_SEPARATOR).unwrap_or((name, ""));
+        (label.to_string(), key.to_string())
+    };
+
+    match kind {
+        DictionaryKind::UniqueName => Some(Constraint::UniqueName {
+            label: name.to_string(),
+        }),
+        DictionaryKind::UniqueKey => {
+            let (label, key) = split();
+            Some(Constraint::Unique { label, key })
+        }
+        DictionaryKind::RequiredKey => {
+            let (label, key) = split();
+            Some(Constraint::Required { label, key })
+        }
+        _ => None,
+    }
+}
+
+//  Label constraint applies to.
+fn constraint_label(constraint: &Constraint) -> &str {
+    match constraint {
+        Constraint::UniqueName { label }
+        | Constraint::Unique { label, .. }
+        | Constraint::Required { label, .. } => label,
+    }
+}
+
+impl Database {
+    //  Declare constraint, failing if a node already breaks it. Declaring it again does nothing.
+    pub fn create_constraint(&mut self, constraint: Constraint) -> Result<()> {
+        self.logged_op(WalOp::CreateConstraint, |db| {
+            if constraint_label(&constraint).contains(KEY_SEPARATOR) {
+                return Err(GdbError::ConstraintViolation(format!(
+                    "label {:?} cannot be constrained, it contains a control character",
+                    constraint_label(&constraint)
+                )));
+            }
+
+            let (kind, name) = constraint_entry(&constraint);
+            db.intern(kind, &name)?;
+
+            for node in db.nodes_with_label(constraint_label(&constraint))? {
+                db.check_constraint(&constraint, &node)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    //  Every declared constraint, in the order they were declared.
+    pub fn constraints(&self) -> Result<Vec<Constraint>> {
+        let mut constraints = Vec::new();
+
+        for (offset, entry) in self.dictionary_entries()? {
+            let name = self.load_string(&entry.name, offset)?;
+            if let Some(constraint) = entry_constraint(entry.kind, &name) {
+                constraints.push(constraint);
+            }
+        }
+
+        Ok(constraints)
+    }
+
+    //  Fail if the node with node_id breaks a constraint on any of its labels, as it is now.
+    pub(crate) fn enforce_constraints(&self, node_id: u64) -> Result<()> {
+        let labels = self.node_labels(node_id)?;
+        if labels.is_empty() {
+            return Ok(());
+        }
+
+        let node = self.get_node_from_id(node_id)?;
+        for constraint in self.constraints()? {
+            if labels
+                .iter()
+                .any(|label| label == constraint_label(&constraint))
+            {
+                self.check_constraint(&constraint, &node)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    //  Fail if node, which carries the constraint's label, breaks it.
+    fn check_constraint(&self, constraint: &Constraint, node: &Node) -> Result<()> {
+        match constraint {
+            Constraint::UniqueName { label } => {
+                // the name index finds the namesakes, only they need their labels read
+                for other in self.nodes_with_name(&node.name, false)? {
+                    if other.id != node.id && self.node_labels(other.id)?.contains(label) {
+                        return Err(GdbError::ConstraintViolation(format!(
+                            "nodes {} and {} with label {} are both named {:?}",
+                            other.id, node.id, label, node.name
+                        )));
+                    }
+                }
+            }
+            Constraint::Unique { label, key } => {
+                let Some(value) = self.node_property(node, key)? else {
+                    return Ok(()); // nothing to clash with
+                };
+
+                // with an index on key only the nodes holding value are read, otherwise every node with label
+                let (others, indexed) = match self.indexed_nodes_where(key, &value)? {
+                    Some(holding) => (holding, true),
+                    None => (self.nodes_with_label(label)?, false),
+                };
+
+                for other in others {
+                    if other.id == node.id {
+                        continue;
+                    }
+
+                    let clashes = if indexed {
+                        self.node_labels(other.id)?.contains(label)
+                    } else {
+                        self.node_property(&other, key)?.as_ref() == Some(&value)
+                    };
+                    if clashes {
+                        return Err(GdbError::ConstraintViolation(format!(
+                            "nodes {} and {} with label {} both have {} = {:?}",
+                            other.id, node.id, label, key, value
+                        )));
+                    }
+                }
+            }
+            Constraint::Required { label, key } => {
+                if self.node_property(node, key)?.is_none() {
+                    return Err(GdbError::ConstraintViolation(format!(
+                        "node {} with label {} has no {} property",
+                        node.id, label, key
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    //  Value of key on node, None when it has none.
+    fn node_property(&self, node: &Node, key: &str) -> Result<Option<PropertyValue>> {
+        Ok(self
+            .attribute_chain(node.attr_head)?
+            .into_iter()
+            .find(|(_, attribute)| attribute.key == key)
+            .map(|(_, attribute)| attribute.value))
+    }
+
+    //  Offset of the node a relationship's end ("node_from" or "node_to") names, failing if there is none.
+    pub(crate) fn relationship_endpoint(&self, end: &str, id: u64) -> Result<u64> {
+        match self.get_node_address_from_id(id) {
+            Err(GdbError::NotFound { .. }) => Err(GdbError::ConstraintViolation(format!(
+                "relationship {} {} is not a node",
+                end, id
+            ))),
+            result => result,
+        }
+    }
+}
@@ -0,0 +1,312 @@
+/*
+    Simon H - 2024
+*/
+
+use bincode::{deserialize, serialize};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Result as IoResult;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{GdbError, Result};
+use crate::schema::SchemaCache;
+use crate::types::{GrowthPolicy, Header};
+use crate::types::{
+    BLOCK_SIZE, CHECKSUM_SIZE, FORMAT_VERSION, HEADER_SIZE, MAGIC, SUPPORTED_FEATURES,
+};
+use crate::wal::{read_records, wal_path};
+
+//  Check a header read from disk was written in a layout this build understands.
+pub fn validate_header(header: &Header) -> Result<()> {
+    validate_header_version(header, FORMAT_VERSION)
+}
+
+//  Same checks as validate_header, for a file expected to be in format version.
+fn validate_header_version(header: &Header, version: u32) -> Result<()> {
+    if header.magic != MAGIC {
+        return Err(GdbError::NotADatabase);
+    }
+
+    if header.format_version != version {
+        return Err(GdbError::UnsupportedVersion {
+            found: header.format_version,
+            supported: FORMAT_VERSION,
+        });
+    }
+
+    if header.block_size as u64 != BLOCK_SIZE {
+        return Err(GdbError::IncompatibleLayout(format!(
+            "block size {} (expected {})",
+            header.block_size, BLOCK_SIZE
+        )));
+    }
+
+    let unknown_features = header.feature_flags & !SUPPORTED_FEATURES;
+    if unknown_features != 0 {
+        return Err(GdbError::IncompatibleLayout(format!(
+            "unknown feature flags {:#x}",
+            unknown_features
+        )));
+    }
+
+    Ok(())
+}
+
+//  Fail with NotADatabase unless file starts with MAGIC, the only part of the header a crash cannot leave half written.
+fn check_magic(file: &File) -> Result<()> {
+    let mut magic = [0u8; 4];
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(()) if magic == MAGIC => Ok(()),
+        Ok(()) => Err(GdbError::NotADatabase),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(GdbError::NotADatabase),
+        Err(e) => Err(e.into()),
+    }
+}
+
+//  Serialize value into a len byte slot: zero padded payload followed by its CRC32.
+pub(crate) fn encode_slot<T: Serialize>(value: &T, len: u64) -> Result<Vec<u8>> {
+    let payload_len = (len - CHECKSUM_SIZE) as usize;
+
+    let mut data = serialize(value)?;
+    if data.len() > payload_len {
+        return Err(GdbError::Serialization(format!(
+            "record is {} bytes, slot holds {}",
+            data.len(),
+            payload_len
+        )));
+    }
+    data.resize(payload_len, 0);
+
+    let checksum = crc32fast::hash(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+
+    Ok(data)
+}
+
+//  Check the CRC32 at the end of a slot read from offset and return the payload in front of it.
+pub(crate) fn verify_slot(buffer: &[u8], offset: u64) -> Result<&[u8]> {
+    let (payload, stored) = buffer.split_at(buffer.len() - CHECKSUM_SIZE as usize);
+
+    if crc32fast::hash(payload) != u32::from_le_bytes(stored.try_into().unwrap()) {
+        return Err(GdbError::Corrupt { offset }); // torn write or damaged bytes
+    }
+
+    Ok(payload)
+}
+
+/*
+    Handle to a single database file.
+
+    Owns the path and an open read/write handle, every node, relationship,
+    attribute and disk operation is implemented as a method on this struct
+    (see disk.rs, node.rs, relationship.rs and attribute.rs).
+
+    Writes are staged in memory and only reach the file through the
+    write-ahead log once the surrounding operation succeeds (see wal.rs).
+*/
+#[derive(Debug)]
+pub struct Database {
+    pub(crate) path: PathBuf,
+    pub(crate) file: File,
+    pub(crate) wal: File,
+    pub(crate) growth_policy: GrowthPolicy,
+    pub(crate) staged: BTreeMap<u64, Vec<u8>>, // offset -> bytes written by the current operation
+    pub(crate) op_depth: u32,                  // nesting of logged operations
+    pub(crate) txn_depth: u32,                 // op_depth of the open transaction, 0 when none
+    pub(crate) wal_pending: bool,              // log may hold a record not applied to the file yet
+    pub(crate) schema_cache: RefCell<SchemaCache>, // parsed schema (see schema.rs)
+}
+
+impl Database {
+    //  Create (or truncate) a database file at path and format it with record_no empty blocks.
+    pub fn create<P: AsRef<Path>>(path: P, record_no: u64) -> Result<Database> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref())?;
+        let wal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(wal_path(path.as_ref()))?;
+
+        let mut db = Database {
+            path: path.as_ref().to_path_buf(),
+            file,
+            wal,
+            growth_policy: GrowthPolicy::default(),
+            staged: BTreeMap::new(),
+            op_depth: 0,
+            txn_depth: 0,
+            wal_pending: false,
+            schema_cache: RefCell::default(),
+        };
+        db.format_disk(record_no)?;
+
+        Ok(db)
+    }
+
+    //  Open an existing, formatted database file, replaying the write-ahead log if a previous run crashed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref())?;
+        check_magic(&file)?; // before the log is created next to it or replayed into it
+        let wal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(wal_path(path.as_ref()))?;
+
+        let mut db = Database {
+            path: path.as_ref().to_path_buf(),
+            file,
+            wal,
+            growth_policy: GrowthPolicy::default(),
+            staged: BTreeMap::new(),
+            op_depth: 0,
+            txn_depth: 0,
+            wal_pending: false,
+            schema_cache: RefCell::default(),
+        };
+        db.recover()?;
+        validate_header(&db.read_header_unchecked()?)?; // fail early on files that are not databases
+        db.read_header()?;
+
+        Ok(db)
+    }
+
+    /*
+        Open a file written in format version without write access, for
+        migration only. A log left behind by a crash is read but not
+        replayed: its writes are staged, so reads see them, and neither file
+        is changed. The handle is never written, the log slot only holds a
+        second read-only handle to the file.
+    */
+    pub(crate) fn open_read_only<P: AsRef<Path>>(path: P, version: u32) -> Result<Database> {
+        let file = File::open(path.as_ref())?;
+        check_magic(&file)?;
+
+        let mut staged = BTreeMap::new();
+        match File::open(wal_path(path.as_ref())) {
+            Ok(wal) => {
+                for record in read_records(&wal)? {
+                    for write in record.writes {
+                        staged.insert(write.offset, write.data);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let db = Database {
+            path: path.as_ref().to_path_buf(),
+            wal: file.try_clone()?,
+            file,
+            growth_policy: GrowthPolicy::default(),
+            staged,
+            op_depth: 0,
+            txn_depth: 0,
+            wal_pending: false,
+            schema_cache: RefCell::default(),
+        };
+        validate_header_version(&db.read_header_unchecked()?, version)?;
+        db.read_header()?;
+
+        Ok(db)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    //  Set how many blocks are added when allocation runs out of empty blocks.
+    pub fn set_growth_policy(&mut self, growth_policy: GrowthPolicy) {
+        self.growth_policy = growth_policy;
+    }
+
+    pub fn growth_policy(&self) -> GrowthPolicy {
+        self.growth_policy
+    }
+
+    pub(crate) fn read_header(&self) -> Result<Header> {
+        let buffer = self.read_bytes(0, HEADER_SIZE)?;
+        let payload = verify_slot(&buffer, 0)?;
+        deserialize::<Header>(payload).map_err(|_| GdbError::Corrupt { offset: 0 })
+    }
+
+    //  Header without the checksum check, only used to identify the file on open.
+    fn read_header_unchecked(&self) -> Result<Header> {
+        let buffer = self.read_bytes(0, HEADER_SIZE)?;
+        deserialize::<Header>(&buffer).map_err(|_| GdbError::NotADatabase)
+    }
+
+    pub(crate) fn write_header(&mut self, header: &Header) -> Result<()> {
+        let serialized_header = encode_slot(header, HEADER_SIZE)?;
+        self.stage(0, serialized_header);
+        Ok(())
+    }
+
+    //  Deserialize the block stored at offset as T, failing with Corrupt if its checksum doesn't match.
+    pub(crate) fn read_block<T: DeserializeOwned>(&self, offset: u64) -> Result<T> {
+        let buffer = self.read_bytes(offset, BLOCK_SIZE)?;
+        let payload = verify_slot(&buffer, offset)?;
+        deserialize::<T>(payload).map_err(|_| GdbError::Corrupt { offset })
+    }
+
+    //  Serialize block and write it at offset, zero padded and checksummed to BLOCK_SIZE.
+    pub(crate) fn write_block<T: Serialize>(&mut self, offset: u64, block: &T) -> Result<()> {
+        let serialized_block = encode_slot(block, BLOCK_SIZE)?;
+        self.stage(offset, serialized_block);
+        Ok(())
+    }
+
+    //  Queue bytes for the write-ahead log, only valid inside a logged operation.
+    fn stage(&mut self, offset: u64, data: Vec<u8>) {
+        debug_assert!(self.op_depth > 0, "write outside of a logged operation");
+        self.staged.insert(offset, data);
+    }
+
+    //  Read len bytes from offset, anything past the end of file is read as zeros.
+    //  Writes staged by the current operation are overlaid on the file contents.
+    pub(crate) fn read_bytes(&self, offset: u64, len: u64) -> IoResult<Vec<u8>> {
+        let mut buffer = vec![0u8; len as usize];
+        let mut filled = 0;
+
+        while filled < buffer.len() {
+            let read = self
+                .file
+                .read_at(&mut buffer[filled..], offset + filled as u64)?;
+            if read == 0 {
+                break; // EOF, remainder stays zeroed
+            }
+            filled += read;
+        }
+
+        let end = offset + len;
+        for (&staged_offset, data) in self.staged.range(..end) {
+            let staged_end = staged_offset + data.len() as u64;
+            if staged_end <= offset {
+                continue;
+            }
+
+            let from = staged_offset.max(offset);
+            let to = staged_end.min(end);
+            buffer[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                &data[(from - staged_offset) as usize..(to - staged_offset) as usize],
+            );
+        }
+
+        Ok(buffer)
+    }
+}
@@ -0,0 +1,162 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Name dictionary.
+
+    Names repeated across many records (relationship types, labels) are stored once,
+    as a DictionaryBlock on a chain starting at the header's
+    dictionary_head. Records hold the entry's id instead of the name, ids
+    are handed out per kind starting at 1 so 0 can mean "none".
+
+    Property indexes are declared here as well, one entry per indexed key
+    and index kind, its head holding the root of the index tree.
+
+    The dictionary only grows, entries are never deleted.
+*/
+
+use crate::database::Database;
+use crate::error::{GdbError, RecordKind, Result};
+use crate::types::{BlockType, DictionaryBlock, DictionaryKind}; // import Block Types
+
+//  Kind reported when a dictionary lookup fails.
+pub(crate) fn record_kind(kind: DictionaryKind) -> RecordKind {
+    match kind {
+        DictionaryKind::RelationshipType => RecordKind::RelationshipType,
+        DictionaryKind::Label => RecordKind::Label,
+        DictionaryKind::HashIndex | DictionaryKind::OrderedIndex => RecordKind::PropertyIndex,
+        DictionaryKind::TextIndex => RecordKind::TextIndex,
+        DictionaryKind::UniqueName | DictionaryKind::UniqueKey | DictionaryKind::RequiredKey => {
+            RecordKind::Constraint
+        }
+        DictionaryKind::Schema => RecordKind::Schema,
+    }
+}
+
+impl Database {
+    //  Every dictionary entry with its offset, in the order they were added.
+    pub(crate) fn dictionary_entries(&self) -> Result<Vec<(u64, DictionaryBlock)>> {
+        let header = self.read_header()?;
+
+        let mut entries = Vec::new();
+        let mut offset = header.dictionary_head;
+
+        while offset != 0 {
+            let dictionary_block = self.read_block::<DictionaryBlock>(offset)?;
+
+            // wrong block type, or longer than the file can hold (a cycle)
+            if dictionary_block.block_type != BlockType::Dictionary
+                || entries.len() as u64 >= header.total_blocks
+            {
+                return Err(GdbError::Corrupt { offset });
+            }
+
+            let next = dictionary_block.next;
+            entries.push((offset, dictionary_block));
+            offset = next;
+        }
+
+        Ok(entries)
+    }
+
+    //  Entry holding name with its offset, or None if it was never stored.
+    pub(crate) fn dictionary_entry(
+        &self,
+        kind: DictionaryKind,
+        name: &str,
+    ) -> Result<Option<(u64, DictionaryBlock)>> {
+        for (offset, entry) in self.dictionary_entries()? {
+            if entry.kind == kind && self.load_string(&entry.name, offset)? == name {
+                return Ok(Some((offset, entry)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    //  Id of name, or None if it was never stored.
+    pub(crate) fn dictionary_id(&self, kind: DictionaryKind, name: &str) -> Result<Option<u64>> {
+        Ok(self
+            .dictionary_entry(kind, name)?
+            .map(|(_, entry)| entry.id))
+    }
+
+    //  Name stored under id.
+    pub(crate) fn dictionary_name(&self, kind: DictionaryKind, id: u64) -> Result<String> {
+        for (offset, entry) in self.dictionary_entries()? {
+            if entry.kind == kind && entry.id == id {
+                return self.load_string(&entry.name, offset);
+            }
+        }
+
+        Err(GdbError::not_found(record_kind(kind), id))
+    }
+
+    //  Id of name, adding it to the dictionary first if needed. Only valid inside a logged operation.
+    pub(crate) fn intern(&mut self, kind: DictionaryKind, name: &str) -> Result<u64> {
+        let entries = self.dictionary_entries()?;
+
+        let mut last_id = 0;
+        for (offset, entry) in &entries {
+            if entry.kind != kind {
+                continue;
+            }
+            if self.load_string(&entry.name, *offset)? == name {
+                return Ok(entry.id);
+            }
+            last_id = last_id.max(entry.id);
+        }
+
+        // take first empty from the free list, growing the file if needed
+        let offset = self.allocate_block()?;
+        let dictionary_block = DictionaryBlock {
+            block_type: BlockType::Dictionary,
+            kind,
+            id: last_id + 1,
+            name: self.store_string(name)?,
+            next: 0,
+            head: 0,
+        };
+        self.write_block(offset, &dictionary_block)?;
+
+        // link onto the tail so ids stay in the order they were added
+        match entries.last() {
+            Some((tail_offset, _)) => {
+                let mut tail_block = self.read_block::<DictionaryBlock>(*tail_offset)?;
+                tail_block.next = offset;
+                self.write_block(*tail_offset, &tail_block)?;
+            }
+            None => {
+                let mut header = self.read_header()?;
+                header.dictionary_head = offset;
+                self.write_header(&header)?;
+            }
+        }
+
+        Ok(dictionary_block.id)
+    }
+
+    //  Every name of kind stored in the file, in the order they were added.
+    pub(crate) fn dictionary_names(&self, kind: DictionaryKind) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for (offset, entry) in self.dictionary_entries()? {
+            if entry.kind == kind {
+                names.push(self.load_string(&entry.name, offset)?);
+            }
+        }
+
+        Ok(names)
+    }
+
+    //  Every relationship type stored in the file.
+    pub fn relationship_types(&self) -> Result<Vec<String>> {
+        self.dictionary_names(DictionaryKind::RelationshipType)
+    }
+
+    //  Every label stored in the file, including labels no node carries any more.
+    pub fn labels(&self) -> Result<Vec<String>> {
+        self.dictionary_names(DictionaryKind::Label)
+    }
+}
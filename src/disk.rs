@@ -2,171 +2,68 @@
     Simon H - 2024
 */
 
-use bincode::{deserialize, serialize};
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
-use std::mem::size_of;
-use std::os::unix::fs::FileExt;
+use bincode::deserialize;
+use serde::de::DeserializeOwned;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 
 // type imports can be combined, but this is easier to read
-use crate::types::Header; // import structs
-use crate::types::{AttributeBlock, Block, BlockType, NodeBlock, RelationshipBlock}; // import Block Types
-use crate::types::{EXPORT_PATH, PATH};
-
-// custom error macro
-macro_rules! custom_error {
-    ($msg:expr) => {
-        return Err(Error::new(ErrorKind::Other, $msg))
-    };
+use crate::database::Database;
+use crate::error::{GdbError, RecordKind, Result};
+use crate::types::IndexBlock; // import Block Types
+use crate::types::{AttributeBlock, Block, BlockType, EmptyBlock, NodeBlock}; // import Block Types
+use crate::types::{DictionaryBlock, LabelBlock, OverflowBlock, RelationshipBlock}; // import Block Types
+use crate::types::{Header, Relationship}; // import structs
+use crate::types::{BLOCK_SIZE, HEADER_SIZE, RLT_PAD};
+use crate::wal::WalOp;
+
+// Decoded content of a block, used by passes that rewrite the whole file
+#[derive(Debug)]
+pub(crate) enum Slot {
+    Free,
+    Node(NodeBlock),
+    Relationship(Relationship),
+    Attribute(AttributeBlock),
+    Overflow(OverflowBlock),
+    Dictionary(DictionaryBlock),
+    Label(LabelBlock),
+    Index(IndexBlock),
 }
 
-// map bincode error to io error
-macro_rules! map_bincode_error {
-    ($expr:expr) => {
-        $expr.map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Bincode serialization error: {:?}", err),
-            )
-        })
-    };
-}
-
-// Format files used in DB - create header and empty blocks
-pub fn format_disk(record_no: u64) -> Result<()> {
-    let mut stream = File::create(PATH)?;
-    let node_block_size = size_of::<NodeBlock>() as u64;
-    let db_size: u64 = size_of::<Header>() as u64 + (node_block_size * record_no) + 56 * 2; // TODO: make consistent... -> added padding, prevents EoF errors
-
-    let block: NodeBlock = Default::default();
-
-    let header = Header {
-        total_blocks: record_no.try_into().unwrap(), // TODO: implement correctly (remove unwrap),
-        first_empty: size_of::<Header>().try_into().unwrap(), // or create a DEFAULT...
-        db_size: db_size.try_into().unwrap(),
-    };
-
-    println!("Header: {:?}\r", header);
-
-    let serialized_header = map_bincode_error!(serialize(&header))?;
-    let serialized_block = map_bincode_error!(serialize(&block))?;
-
-    // write header to file:
-    stream.write_all(&serialized_header)?; // safe to unwrap as checked above
-
-    // seek to first empty'
-    stream.seek(SeekFrom::Start(header.first_empty))?;
-    let mut offset = header.first_empty;
-
-    assert_eq!(header.first_empty, size_of::<Header>() as u64);
-
-    for _ in 0..header.total_blocks {
-        // stream.seek(SeekFrom::Start(offset))?;
-        stream.write_at(&serialized_block, offset)?;
-        offset += node_block_size;
-    }
-
-    let mut final_block: Block = Default::default();
-    final_block.block_type = BlockType::Final;
-
-    let serialized_final_block = map_bincode_error!(serialize(&final_block))?;
-
-    stream.write_at(&serialized_final_block, offset)?;
-
-    Ok(())
-}
-
-// Grow output file when total blocks > blocks available, implemented to dynamically scale Database files.
-pub fn expand_file(amount: u64) -> Result<()> {
-    println!("Expanding file...");
-    // open file in append mode:
-    let mut stream = OpenOptions::new().append(true).open(PATH)?;
-
-    // get current file size:
-    let _current_size = stream.metadata()?.len(); // needed?
-
-    // serialise Unset block
-    let block: NodeBlock = Default::default(); // NodeBlock used but is set to Unset...
-    let serialized_block = map_bincode_error!(serialize(&block))?;
-
-    // write empty blocks to file:
-    for _ in 0..amount {
-        stream.write_all(&serialized_block)?;
-    }
-
-    // update header to reflect new db size
-    // read header
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-    let mut header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    // update header
-    header.db_size += amount * size_of::<NodeBlock>() as u64;
-    header.total_blocks += amount;
-
-    // write header
-    stream.seek(SeekFrom::Start(0))?;
-    let serialized_header = map_bincode_error!(serialize(&header))?;
-    stream.write_all(&serialized_header)?;
-
-    Ok(())
-}
-
-/* potentially redundant...
-pub fn populate_from_file() -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).open()?;
-
-    Ok(())
-}
-*/
-
-// Print header of file, given file name.
-pub fn print_header() -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
-
-    // read and print header
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-
-    let header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    println!("Header: {:?}\r", header);
-    Ok(())
-}
-
-//  Given an offset print node to console.
-// pub fn print_node_name(offset: u64) -> Result<()> {
-//     let mut stream = OpenOptions::new().read(true).open(PATH).unwrap();
-
-//     // Move to offset
-//     stream.seek(SeekFrom::Start(offset))?;
-
-//     // Read bytes into Block struct
-//     let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<NodeBlock>());
-//     stream.read_to_end(&mut buffer)?;
-
-//     // Decode bytes into Block struct
-//     let result_node = map_bincode_error!(deserialize::<NodeBlock>(&buffer))?;
-//     println!("-> {}", str_conversion::char_print(&result_node.node.name));
-//     Ok(())
-// }
-
-pub fn print_block(block: Block, buffer: &Vec<u8>) -> Result<()> {
+pub fn print_block(block: Block, buffer: &[u8]) -> Result<()> {
     match block.block_type {
         BlockType::Node => {
-            let node_block = map_bincode_error!(deserialize::<NodeBlock>(&buffer))?;
+            let node_block = deserialize::<NodeBlock>(buffer)?;
             println!("Node: {:?}\r", node_block);
         }
         BlockType::Relationship => {
-            let relationship_block = map_bincode_error!(deserialize::<RelationshipBlock>(&buffer))?;
+            let relationship_block = deserialize::<RelationshipBlock>(buffer)?;
             println!("Relationship: {:?}\r", relationship_block);
         }
         BlockType::Attribute => {
-            let attribute_block = map_bincode_error!(deserialize::<AttributeBlock>(&buffer))?;
+            let attribute_block = deserialize::<AttributeBlock>(buffer)?;
             println!("Attribute: {:?}\r", attribute_block);
         }
+        BlockType::Overflow => {
+            let overflow_block = deserialize::<OverflowBlock>(buffer)?;
+            println!("Overflow: {:?}\r", overflow_block);
+        }
+        BlockType::Dictionary => {
+            let dictionary_block = deserialize::<DictionaryBlock>(buffer)?;
+            println!("Dictionary: {:?}\r", dictionary_block);
+        }
+        BlockType::Label => {
+            let label_block = deserialize::<LabelBlock>(buffer)?;
+            println!("Label: {:?}\r", label_block);
+        }
+        BlockType::Index => {
+            let index_block = deserialize::<IndexBlock>(buffer)?;
+            println!("Index: {:?}\r", index_block);
+        }
         BlockType::Empty => {
-            println!("Empty found");
+            let empty_block = deserialize::<EmptyBlock>(buffer)?;
+            println!("Empty found, next: {}\r", empty_block.next_empty);
         }
         BlockType::Unset => {
             println!("Unset");
@@ -179,262 +76,315 @@ pub fn print_block(block: Block, buffer: &Vec<u8>) -> Result<()> {
     Ok(())
 }
 
-//  Print any generic block given offset.
-pub fn print_block_offset(offset: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
-    // Move to offset
-    println!("Seeking -> Offset: {}\r", offset);
-    stream.seek(SeekFrom::Start(offset))?;
+impl Database {
+    // Format files used in DB - create header and empty blocks
+    pub fn format_disk(&mut self, record_no: u64) -> Result<()> {
+        if self.in_transaction() {
+            return Err(GdbError::TransactionInProgress); // truncation can't be rolled back
+        }
 
-    // Read bytes into Block struct
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<NodeBlock>());
-    stream.read_to_end(&mut buffer)?;
+        let db_size = self.logged_op(WalOp::Format, |db| {
+            let db_size: u64 = HEADER_SIZE + (BLOCK_SIZE * record_no) + BLOCK_SIZE; // header, blocks and the final block
 
-    // Decode bytes into Block struct
-    let block = map_bincode_error!(deserialize::<Block>(&buffer))?;
+            let first_empty = if record_no > 0 { HEADER_SIZE } else { 0 };
+            let mut header = Header::new(record_no, first_empty, db_size);
 
-    print_block(block, &buffer)?;
-    Ok(())
-}
+            // the schema is gone, handles still open on the file must not keep using theirs
+            if let Ok(previous) = db.read_header() {
+                header.schema_generation = previous.schema_generation.wrapping_add(1);
+            }
+
+            println!("Header: {:?}\r", header);
 
-//  Print all blocks in file.
-pub fn print_all_blocks() -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
+            // write header to file:
+            db.write_header(&header)?;
 
-    // read header
-    let mut header_buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut header_buffer)?;
-    let header = map_bincode_error!(deserialize::<Header>(&header_buffer))?;
+            // write empty blocks, each pointing at the next to build the free list
+            let mut offset = HEADER_SIZE;
 
-    stream.seek(SeekFrom::Start(size_of::<Header>() as u64))?; // move to first block (after header)
+            for i in 0..header.total_blocks {
+                let next_empty = if i + 1 < header.total_blocks {
+                    offset + BLOCK_SIZE
+                } else {
+                    0
+                };
+                db.write_block(offset, &EmptyBlock::new(next_empty))?;
+                offset += BLOCK_SIZE;
+            }
 
-    for i in 0..header.total_blocks {
-        let curr_offset = size_of::<Header>() as u64 + (i * size_of::<NodeBlock>() as u64);
-        // let curr_offset = 24 + (i * 56);
-        // Move to offset
-        stream.seek(SeekFrom::Start(curr_offset))?;
-        let mut buffer = Vec::with_capacity(size_of::<NodeBlock>());
-        stream.read_to_end(&mut buffer)?;
+            db.write_final_block(offset)?;
 
-        let block = get_block(curr_offset)?;
-        println!("@: {:?}\r", curr_offset);
-        print_block(block, &buffer)?;
+            Ok(db_size)
+        })?;
+
+        // record is applied, drop whatever the file held past the new final block
+        self.file.set_len(db_size)?;
+        self.file.sync_all()?;
+
+        Ok(())
     }
 
-    Ok(())
-}
+    // Grow output file when total blocks > blocks available, implemented to dynamically scale Database files.
+    pub fn expand_file(&mut self, amount: u64) -> Result<()> {
+        self.logged_op(WalOp::UpdateHeader, |db| {
+            if amount == 0 {
+                return Ok(());
+            }
 
-pub fn print_n_blocks(n: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
+            let mut header = db.read_header()?;
+
+            // new blocks start where the final block currently sits
+            let final_offset = HEADER_SIZE + header.total_blocks * BLOCK_SIZE;
+
+            // write empty blocks over the old final block, the last one links to the existing free list:
+            let mut offset = final_offset;
+            for i in 0..amount {
+                let next_empty = if i + 1 < amount {
+                    offset + BLOCK_SIZE
+                } else {
+                    header.first_empty
+                };
+                db.write_block(offset, &EmptyBlock::new(next_empty))?;
+                offset += BLOCK_SIZE;
+            }
 
-    let mut header_buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_exact(&mut header_buffer)?;
+            // relocate the final block after the new blocks
+            db.write_final_block(offset)?;
 
-    stream.seek(SeekFrom::Start(size_of::<Header>() as u64))?;
+            // update header, logged in the same record as the new blocks
+            header.first_empty = final_offset;
+            header.db_size += amount * BLOCK_SIZE;
+            header.total_blocks += amount;
 
-    for i in 0..n {
-        let curr_offset = size_of::<Header>() as u64 + (i * size_of::<NodeBlock>() as u64);
+            db.write_header(&header)
+        })
+    }
 
-        stream.seek(SeekFrom::Start(curr_offset))?;
-        // let mut buffer = [0u8; 96];
-        let mut buffer = Vec::with_capacity(96);
-        println!("Buffer size at {} -> {}", i, std::mem::size_of_val(&buffer));
-        stream.read_exact(&mut buffer)?;
+    // Print header of file.
+    pub fn print_header(&self) -> Result<()> {
+        let header = self.read_header()?;
 
-        let block = get_block(curr_offset)?;
-        println!("@: {:?}\r", curr_offset);
-        print_block(block, &buffer)?;
+        println!("Header: {:?}\r", header);
+        Ok(())
     }
 
-    Ok(())
-}
+    //  Print any generic block given offset.
+    pub fn print_block_offset(&self, offset: u64) -> Result<()> {
+        println!("Seeking -> Offset: {}\r", offset);
 
-pub fn get_first_empty(mut stream: &File, header: &Header) -> Result<u64> {
-    const STRUCT_SIZE: u64 = size_of::<Block>() as u64;
-    let mut curr_offset = size_of::<Header>() as u64;
+        let buffer = self.read_bytes(offset, BLOCK_SIZE)?;
+        let block = self.get_block(offset)?;
 
-    stream.seek(SeekFrom::Start(curr_offset))?; // move to first block
+        print_block(block, &buffer)?;
+        Ok(())
+    }
 
-    // TODO: check logic
-    for _ in 0..header.total_blocks {
-        // Read bytes into Block struct
-        let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Block>());
-        stream.read_to_end(&mut buffer)?; // TODO: find alternative to read_to_end...
+    //  Print all blocks in file.
+    pub fn print_all_blocks(&self) -> Result<()> {
+        let header = self.read_header()?;
 
-        // let mut buffer: [u8; STRUCT_SIZE as usize] //= !needs initialising...;
-        // stream.read_to_end_exact(&mut buffer)?;
+        self.print_n_blocks(header.total_blocks)
+    }
 
-        // Decode bytes into Block struct
-        let block = map_bincode_error!(deserialize::<Block>(&buffer))?;
+    pub fn print_n_blocks(&self, n: u64) -> Result<()> {
+        for i in 0..n {
+            let curr_offset = HEADER_SIZE + (i * BLOCK_SIZE);
 
-        // move to next block (for next iteration)
-        curr_offset += STRUCT_SIZE;
-        stream.seek(SeekFrom::Start(curr_offset))?;
+            let buffer = self.read_bytes(curr_offset, BLOCK_SIZE)?;
+            let block = self.get_block(curr_offset)?;
 
-        // return if block is empty or unset
-        if block.block_type == BlockType::Empty || block.block_type == BlockType::Unset {
-            return Ok(curr_offset - STRUCT_SIZE);
+            println!("@: {:?}\r", curr_offset);
+            print_block(block, &buffer)?;
         }
+
+        Ok(())
     }
-    // block not found, preventative option to expand?
-    custom_error!("Error in getting first empty");
-}
 
-// update first empty
-pub fn new_first_empty() -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
+    //  Pop the head of the free list, O(1), growing the file by the growth policy when it is empty.
+    pub(crate) fn allocate_block(&mut self) -> Result<u64> {
+        let mut header = self.read_header()?;
 
-    // read current header information
-    let mut header_buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut header_buffer)?;
-    let mut header = map_bincode_error!(deserialize::<Header>(&header_buffer))?;
+        if header.first_empty == 0 {
+            let amount = self.growth_policy.blocks_to_add(header.total_blocks);
+            self.expand_file(amount)?;
 
-    let new_first_empty = get_first_empty(&stream, &header)?;
+            header = self.read_header()?;
+            if header.first_empty == 0 {
+                return Err(GdbError::Full);
+            }
+        }
 
-    header.first_empty = new_first_empty;
+        let offset = header.first_empty;
+        let empty_block = self.read_block::<EmptyBlock>(offset)?;
 
-    // write new header information
-    stream.seek(SeekFrom::Start(0))?;
-    let serialized_header = map_bincode_error!(serialize(&header))?;
-    stream.write_all(&serialized_header)?;
+        if empty_block.block_type != BlockType::Empty && empty_block.block_type != BlockType::Unset
+        {
+            return Err(GdbError::Corrupt { offset }); // free list points at a live block
+        }
 
-    Ok(())
-}
+        header.first_empty = empty_block.next_empty;
+        self.write_header(&header)?;
 
-// Debug function
-pub fn print_first_empty() -> Result<()> {
-    let _stream = File::open(PATH)?;
-    // println!("First Empty: {}", get_first_empty(&stream)?);
-    Ok(())
-}
+        Ok(offset)
+    }
+
+    //  Push the block at offset onto the free list, O(1).
+    pub(crate) fn free_block(&mut self, offset: u64) -> Result<()> {
+        let mut header = self.read_header()?;
 
-pub fn get_block(offset: u64) -> Result<Block> {
-    let mut stream = File::open(PATH)?;
+        self.write_block(offset, &EmptyBlock::new(header.first_empty))?;
 
-    // Rewind the stream to the beginning
-    stream.seek(SeekFrom::Start(offset))?;
+        header.first_empty = offset;
+        self.write_header(&header)
+    }
 
-    // Read the block from the stream
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<NodeBlock>());
-    stream.read_to_end(&mut buffer)?;
+    //  Mark the end of the block area.
+    pub(crate) fn write_final_block(&mut self, offset: u64) -> Result<()> {
+        let final_block = Block {
+            block_type: BlockType::Final,
+            ..Default::default()
+        };
+        self.write_block(offset, &final_block)
+    }
 
-    // Decode bytes into Block struct
-    let deserialised_block = deserialize::<Block>(&buffer);
+    //  Decode the block at offset, empty and unset blocks read as Slot::Free.
+    pub(crate) fn read_slot(&self, offset: u64) -> Result<Slot> {
+        let slot = match self.get_block(offset)?.block_type {
+            BlockType::Node => Slot::Node(self.read_block(offset)?),
+            BlockType::Relationship => Slot::Relationship(self.get_relationship(offset)?),
+            BlockType::Attribute => Slot::Attribute(self.read_block(offset)?),
+            BlockType::Overflow => Slot::Overflow(self.read_block(offset)?),
+            BlockType::Dictionary => Slot::Dictionary(self.read_block(offset)?),
+            BlockType::Label => Slot::Label(self.read_block(offset)?),
+            BlockType::Index => Slot::Index(self.read_block(offset)?),
+            BlockType::Empty | BlockType::Unset => Slot::Free,
+            BlockType::Final => return Err(GdbError::Corrupt { offset }), // final block inside the block area
+        };
+
+        Ok(slot)
+    }
 
-    return match deserialised_block {
-        Ok(block_ok) => {
-            // println!("Block Okay!");
-            Ok(block_ok)
+    //  Write slot at offset as its block type, Slot::Free pushes the block onto the free list.
+    pub(crate) fn write_slot(&mut self, offset: u64, slot: &Slot) -> Result<()> {
+        match slot {
+            Slot::Free => self.free_block(offset),
+            Slot::Node(node_block) => self.write_block(offset, node_block),
+            Slot::Relationship(relationship) => {
+                let relationship_block = RelationshipBlock {
+                    block_type: BlockType::Relationship,
+                    relationship: *relationship,
+                    pad: [0; RLT_PAD],
+                };
+                self.write_block(offset, &relationship_block)
+            }
+            Slot::Attribute(attribute_block) => self.write_block(offset, attribute_block),
+            Slot::Overflow(overflow_block) => self.write_block(offset, overflow_block),
+            Slot::Dictionary(dictionary_block) => self.write_block(offset, dictionary_block),
+            Slot::Label(label_block) => self.write_block(offset, label_block),
+            Slot::Index(index_block) => self.write_block(offset, index_block),
         }
-        Err(block_not_ok) => {
-            println!("Block Not Okay!");
-            // bad
-            custom_error!(block_not_ok);
-            // exit(1);
+    }
+
+    //  Read a record block, failing with NotFound if the block at offset holds something else.
+    pub(crate) fn read_record_block<T: DeserializeOwned>(
+        &self,
+        offset: u64,
+        block_type: BlockType,
+        kind: RecordKind,
+    ) -> Result<T> {
+        if self.get_block(offset)?.block_type != block_type {
+            return Err(GdbError::not_found(kind, format!("offset {}", offset)));
         }
-    };
 
-    // Return the block
-    // return Ok(deserialised_block);
-}
+        self.read_block::<T>(offset)
+    }
 
-//  Given an offset and file, remove corresponding record
-// TODO: test
-pub fn delete_record_offset(offset: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
+    //  Offsets of every block of block_type, in file order.
+    pub(crate) fn offsets_of(&self, block_type: BlockType) -> Result<Vec<u64>> {
+        let header = self.read_header()?;
+        let mut offsets = Vec::new();
 
-    stream.seek(SeekFrom::Start(offset))?;
+        for i in 0..header.total_blocks {
+            let offset = HEADER_SIZE + i * BLOCK_SIZE;
 
-    // read block information
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Block>());
-    stream.read_to_end(&mut buffer)?;
-    let mut block = map_bincode_error!(deserialize::<Block>(&buffer))?;
+            if self.get_block(offset)?.block_type == block_type {
+                offsets.push(offset);
+            }
+        }
 
-    block.block_type = BlockType::Empty;
+        Ok(offsets)
+    }
 
-    // write block information
-    let serialized_block = map_bincode_error!(serialize(&block))?;
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.write_all(&serialized_block)?;
+    // Debug function
+    pub fn print_first_empty(&self) -> Result<()> {
+        let header = self.read_header()?;
+        println!("First Empty: {}", header.first_empty);
+        Ok(())
+    }
 
-    Ok(())
-}
+    pub fn get_block(&self, offset: u64) -> Result<Block> {
+        self.read_block::<Block>(offset)
+    }
 
-//  Export GDB for visualisation with Python
-pub fn export_database() -> Result<()> {
-    /*
-       Serialise all nodes, relationships, attributes into JSON
-       for ease later when parsing in visualisation tool...
-    */
-
-    let mut out_stream = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(EXPORT_PATH)?;
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-    let header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    for i in 0..header.total_blocks {
-        // let mut block = get_block(i * size_of::<NodeBlock>() as u64)?;
-
-        let mut block_buffer = Vec::with_capacity(size_of::<NodeBlock>());
-        stream.seek(SeekFrom::Start(
-            i * size_of::<NodeBlock>() as u64 + size_of::<Header>() as u64,
-        ))?;
-        stream.read_to_end(&mut block_buffer)?;
-        let block = map_bincode_error!(deserialize::<Block>(&block_buffer))?;
-
-        match block.block_type {
-            BlockType::Node => {
-                let struct_data: NodeBlock =
-                    map_bincode_error!(deserialize::<NodeBlock>(&block_buffer))?;
-                let json_string = serde_json::to_string(&struct_data.node)?;
-                // writeln!(stream, "{}", json_string)?;
-                out_stream
-                    .write_all(json_string.as_bytes())
-                    .expect("Failed to write to file");
-                out_stream
-                    .write_all(b"\n")
-                    .expect("Failed to write to file"); // Add a newline after each JSON object
-            }
-            BlockType::Relationship => {
-                let struct_data: RelationshipBlock =
-                    map_bincode_error!(deserialize::<RelationshipBlock>(&block_buffer))?;
-                let json_string = serde_json::to_string(&struct_data.relationship)?;
-                // writeln!(stream, "{}", json_string)?;
-                out_stream
-                    .write_all(json_string.as_bytes())
-                    .expect("Failed to write to file");
-                out_stream
-                    .write_all(b"\n")
-                    .expect("Failed to write to file"); // Add a newline after each JSON object
-            }
-            BlockType::Attribute => {
-                let struct_data: AttributeBlock =
-                    map_bincode_error!(deserialize::<AttributeBlock>(&block_buffer))?;
-                let json_string = serde_json::to_string(&struct_data.attribute)?;
-                // writeln!(stream, "{}", json_string)?;
-                out_stream
-                    .write_all(json_string.as_bytes())
-                    .expect("Failed to write to file");
-                out_stream
-                    .write_all(b"\n")
-                    .expect("Failed to write to file"); // Add a newline after each JSON object
-            }
-            BlockType::Empty => {
-                // do nothing
+    //  Given an offset, remove corresponding record (and the overflow blocks of its string, and a node's properties, labels and index entries). Nodes go through delete_node_at and relationships through delete_relationship_at, which also unlink relationships.
+    pub(crate) fn delete_record_offset(&mut self, offset: u64) -> Result<()> {
+        self.logged_op(WalOp::DeleteRecord, |db| {
+            match db.read_slot(offset)? {
+                Slot::Node(node_block) => {
+                    let name = db.load_string(&node_block.name, offset)?;
+                    db.unindex_node(node_block.id, &name, offset)?;
+
+                    // properties go with their node, out of any index on them first
+                    for (_, attribute) in db.attribute_chain(node_block.attr_head)? {
+                        db.unindex_property(&attribute.key, &attribute.value, offset)?;
+                    }
+                    db.delete_attributes(node_block.attr_head)?;
+
+                    db.free_string(&node_block.name)?;
+                    db.delete_labels(node_block.label_head)?;
+                }
+                Slot::Attribute(attribute_block) => db.free_string(&attribute_block.property)?,
+                _ => {}
             }
-            BlockType::Unset => {
-                // do nothing
-            }
-            BlockType::Final => {
-                // do nothing
-            }
-        }
+
+            db.free_block(offset)
+        })
     }
 
-    Ok(())
+    //  Export GDB for visualisation with Python
+    pub fn export_database<P: AsRef<Path>>(&self, export_path: P) -> Result<()> {
+        /*
+           Serialise all nodes, relationships, attributes into JSON
+           for ease later when parsing in visualisation tool...
+        */
+
+        let mut out_stream = File::create(export_path)?;
+
+        let header = self.read_header()?;
+
+        for i in 0..header.total_blocks {
+            let offset = HEADER_SIZE + i * BLOCK_SIZE;
+            let block = self.get_block(offset)?;
+
+            let json_string = match block.block_type {
+                BlockType::Node => serde_json::to_string(&self.get_node(offset)?)?,
+                BlockType::Relationship => serde_json::to_string(&self.get_relationship(offset)?)?,
+                BlockType::Attribute => serde_json::to_string(&self.read_attribute(offset)?)?,
+                BlockType::Empty
+                | BlockType::Unset
+                | BlockType::Final
+                | BlockType::Overflow
+                | BlockType::Dictionary
+                | BlockType::Label
+                | BlockType::Index => {
+                    continue; // do nothing, overflow blocks are exported with their string
+                }
+            };
+
+            out_stream.write_all(json_string.as_bytes())?;
+            out_stream.write_all(b"\n")?; // Add a newline after each JSON object
+        }
+
+        Ok(())
+    }
 }
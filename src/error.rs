@@ -0,0 +1,121 @@
+/*
+    Simon H - 2024
+*/
+
+use std::fmt;
+use std::io;
+
+// Kind of record a lookup was searching for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Node,
+    Relationship,
+    Attribute,
+    Block,
+    RelationshipType,
+    Label,
+    PropertyIndex,
+    TextIndex,
+    Constraint,
+    Schema,
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RecordKind::Node => "node",
+            RecordKind::Relationship => "relationship",
+            RecordKind::Attribute => "attribute",
+            RecordKind::Block => "block",
+            RecordKind::RelationshipType => "relationship type",
+            RecordKind::Label => "label",
+            RecordKind::PropertyIndex => "property index",
+            RecordKind::TextIndex => "text index",
+            RecordKind::Constraint => "constraint",
+            RecordKind::Schema => "schema",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/*
+    Error returned from every public database function.
+
+    Callers can match on the variant to tell a missing record from a
+    corrupted file, a full database or an underlying I/O failure.
+*/
+#[derive(Debug)]
+pub enum GdbError {
+    NotFound { kind: RecordKind, key: String }, // no record matched the lookup key
+    Corrupt { offset: u64 },                    // bytes at offset could not be decoded
+    Full,                                       // no empty block left to allocate
+    Serialization(String),                      // failed to encode a block or export record
+    ConstraintViolation(String),                // write rejected by a database rule
+    SchemaViolation(String),                    // write does not fit the stored schema
+    TransactionInProgress,                      // begin() called while a transaction is open
+    NotADatabase,                               // file does not start with the magic bytes
+    IncompatibleLayout(String),                 // unknown block size or feature flags
+    Io(io::Error),                              // underlying file operation failed
+    // file written with another layout version
+    UnsupportedVersion { found: u32, supported: u32 },
+}
+
+pub type Result<T> = std::result::Result<T, GdbError>;
+
+impl GdbError {
+    pub fn not_found(kind: RecordKind, key: impl ToString) -> GdbError {
+        GdbError::NotFound {
+            kind,
+            key: key.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for GdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GdbError::NotFound { kind, key } => write!(f, "{} not found: {}", kind, key),
+            GdbError::Corrupt { offset } => write!(f, "corrupt block at offset {}", offset),
+            GdbError::Full => write!(f, "no empty block available"),
+            GdbError::Serialization(msg) => write!(f, "serialization error: {}", msg),
+            GdbError::ConstraintViolation(msg) => write!(f, "constraint violation: {}", msg),
+            GdbError::SchemaViolation(msg) => write!(f, "schema violation: {}", msg),
+            GdbError::TransactionInProgress => write!(f, "a transaction is already in progress"),
+            GdbError::NotADatabase => write!(f, "not a database file (bad magic bytes)"),
+            GdbError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "unsupported format version {} (this build reads version {})",
+                found, supported
+            ),
+            GdbError::IncompatibleLayout(msg) => write!(f, "incompatible layout: {}", msg),
+            GdbError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for GdbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GdbError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GdbError {
+    fn from(err: io::Error) -> Self {
+        GdbError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for GdbError {
+    fn from(err: bincode::Error) -> Self {
+        GdbError::Serialization(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for GdbError {
+    fn from(err: serde_json::Error) -> Self {
+        GdbError::Serialization(err.to_string())
+    }
+}
@@ -0,0 +1,443 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Indexes.
+
+    An index is a B+ tree of IndexBlocks mapping u64 keys to block offsets,
+    its root held by whatever owns the index (the header's id_index for
+    node ids). A key may map to several offsets: entries are ordered by
+    (key, offset) so each one is unique and can be removed exactly.
+
+    Full blocks split on insert. Removal frees a leaf once it is empty and
+    a branch once it is down to one child, so deletes give their blocks
+    back instead of leaving them in the tree until a rebuild.
+
+    The node id index maps every node id to its block, so a lookup by id
+    reads one block per level instead of every block in the file.
+
+    The node name index is keyed by the first 8 bytes of the case folded
+    name, big-endian so keys sort like the names. Names sharing those bytes
+    share a key, lookups read the candidates and compare their full names.
+    One tree serves exact, prefix and case-insensitive lookups.
+
+    Nodes are also entered in the full-text index (text_index.rs) here, the
+    id, name and text indexes are always updated together.
+*/
+
+// type imports can be combined, but this is easier to read
+use crate::database::Database;
+use crate::error::{GdbError, Result};
+use crate::types::Header; // import structs
+use crate::types::{BlockType, IndexBlock}; // import Block Types
+use crate::types::{INDEX_BRANCH_SIZE, INDEX_LEAF_SIZE};
+
+const MAX_INDEX_DEPTH: usize = 64; // deeper than any tree the file can hold, a loop
+
+type Entry = (u64, u64); // (key, offset)
+
+//  Lowercase every character on its own, so folding a prefix gives a prefix of the folded name.
+pub(crate) fn fold_case(name: &str) -> String {
+    name.chars().flat_map(char::to_lowercase).collect()
+}
+
+//  Key of name in the name index.
+pub(crate) fn name_index_key(name: &str) -> u64 {
+    name_key(&fold_case(name), 0)
+}
+
+//  Name index key of a folded name, bytes past its end are set to pad.
+fn name_key(folded: &str, pad: u8) -> u64 {
+    let mut bytes = [pad; 8];
+    let len = folded.len().min(8);
+    bytes[..len].copy_from_slice(&folded.as_bytes()[..len]);
+
+    u64::from_be_bytes(bytes)
+}
+
+impl Database {
+    fn read_index_block(&self, offset: u64) -> Result<IndexBlock> {
+        let index_block = self.read_block::<IndexBlock>(offset)?;
+
+        if index_block.block_type != BlockType::Index {
+            return Err(GdbError::Corrupt { offset }); // tree points at something else
+        }
+
+        Ok(index_block)
+    }
+
+    //  Add key -> offset to the tree at root, returning the root afterwards. Only valid inside a logged operation.
+    pub(crate) fn index_insert(&mut self, root: u64, key: u64, offset: u64) -> Result<u64> {
+        if root == 0 {
+            let leaf_offset = self.allocate_block()?;
+            let leaf = IndexBlock {
+                block_type: BlockType::Index,
+                leaf: true,
+                entries: vec![(key, offset)],
+                children: Vec::new(),
+            };
+            self.write_block(leaf_offset, &leaf)?;
+            return Ok(leaf_offset);
+        }
+
+        let Some((separator, right)) = self.index_insert_into(root, (key, offset), 0)? else {
+            return Ok(root);
+        };
+
+        // root split, the tree grows a level
+        let new_root = self.allocate_block()?;
+        let branch = IndexBlock {
+            block_type: BlockType::Index,
+            leaf: false,
+            entries: vec![separator],
+            children: vec![root, right],
+        };
+        self.write_block(new_root, &branch)?;
+
+        Ok(new_root)
+    }
+
+    //  Insert entry below block_offset, returning the separator and new right block if it split.
+    fn index_insert_into(
+        &mut self,
+        block_offset: u64,
+        entry: Entry,
+        depth: usize,
+    ) -> Result<Option<(Entry, u64)>> {
+        if depth > MAX_INDEX_DEPTH {
+            return Err(GdbError::Corrupt {
+                offset: block_offset,
+            });
+        }
+
+        let mut index_block = self.read_index_block(block_offset)?;
+
+        if index_block.leaf {
+            match index_block.entries.binary_search(&entry) {
+                Ok(_) => return Ok(None), // already indexed
+                Err(i) => index_block.entries.insert(i, entry),
+            }
+
+            if index_block.entries.len() <= INDEX_LEAF_SIZE {
+                self.write_block(block_offset, &index_block)?;
+                return Ok(None);
+            }
+
+            // split in half, the right block's first entry separates them
+            let right = IndexBlock {
+                block_type: BlockType::Index,
+                leaf: true,
+                entries: index_block.entries.split_off(index_block.entries.len() / 2),
+                children: Vec::new(),
+            };
+            let separator = right.entries[0];
+
+            let right_offset = self.allocate_block()?;
+            self.write_block(right_offset, &right)?;
+            self.write_block(block_offset, &index_block)?;
+
+            return Ok(Some((separator, right_offset)));
+        }
+
+        let i = index_block
+            .entries
+            .partition_point(|separator| *separator <= entry);
+        let Some((separator, child)) =
+            self.index_insert_into(index_block.children[i], entry, depth + 1)?
+        else {
+            return Ok(None);
+        };
+
+        index_block.entries.insert(i, separator);
+        index_block.children.insert(i + 1, child);
+
+        if index_block.entries.len() <= INDEX_BRANCH_SIZE {
+            self.write_block(block_offset, &index_block)?;
+            return Ok(None);
+        }
+
+        // the middle separator moves up, its children split either side
+        let mid = index_block.entries.len() / 2;
+        let right = IndexBlock {
+            block_type: BlockType::Index,
+            leaf: false,
+            entries: index_block.entries.split_off(mid + 1),
+            children: index_block.children.split_off(mid + 1),
+        };
+        let separator = index_block.entries.pop().unwrap();
+
+        let right_offset = self.allocate_block()?;
+        self.write_block(right_offset, &right)?;
+        self.write_block(block_offset, &index_block)?;
+
+        Ok(Some((separator, right_offset)))
+    }
+
+    //  Remove key -> offset from the tree at root, returning the root afterwards (0 once the tree is empty). Only valid inside a logged operation.
+    pub(crate) fn index_remove(&mut self, root: u64, key: u64, offset: u64) -> Result<u64> {
+        if root == 0 {
+            return Ok(0);
+        }
+
+        Ok(self
+            .index_remove_from(root, (key, offset), 0)?
+            .unwrap_or(root)) // not indexed, nothing changed
+    }
+
+    /*
+        Remove entry below block_offset. None if it was not there, otherwise
+        the offset now standing for the block: 0 once nothing is left under
+        it, its only child when it is a branch down to one.
+
+        An emptied leaf is freed and dropped from its parent along with one
+        separator, the neighbour taking over its span. A branch down to one
+        child is freed and the child moves up into its place, so leaves may
+        end up at different depths, lookups don't depend on them being level.
+    */
+    fn index_remove_from(
+        &mut self,
+        block_offset: u64,
+        entry: Entry,
+        depth: usize,
+    ) -> Result<Option<u64>> {
+        if depth > MAX_INDEX_DEPTH {
+            return Err(GdbError::Corrupt {
+                offset: block_offset,
+            });
+        }
+
+        let mut index_block = self.read_index_block(block_offset)?;
+
+        if index_block.leaf {
+            let Ok(i) = index_block.entries.binary_search(&entry) else {
+                return Ok(None);
+            };
+            index_block.entries.remove(i);
+
+            if index_block.entries.is_empty() {
+                self.free_block(block_offset)?;
+                return Ok(Some(0));
+            }
+
+            self.write_block(block_offset, &index_block)?;
+            return Ok(Some(block_offset));
+        }
+
+        let i = index_block
+            .entries
+            .partition_point(|separator| *separator <= entry);
+        let Some(child) = self.index_remove_from(index_block.children[i], entry, depth + 1)? else {
+            return Ok(None);
+        };
+
+        if child == index_block.children[i] {
+            return Ok(Some(block_offset)); // nothing above the leaf changed
+        }
+
+        if child == 0 {
+            // the child's span goes to the neighbour its separator bounded
+            index_block.children.remove(i);
+            if !index_block.entries.is_empty() {
+                index_block.entries.remove(i.saturating_sub(1));
+            }
+        } else {
+            index_block.children[i] = child;
+        }
+
+        if index_block.children.len() <= 1 {
+            self.free_block(block_offset)?;
+            return Ok(Some(index_block.children.first().copied().unwrap_or(0)));
+        }
+
+        self.write_block(block_offset, &index_block)?;
+        Ok(Some(block_offset))
+    }
+
+    //  Every entry with lo <= key <= hi in the tree at root, in (key, offset) order.
+    pub(crate) fn index_range(&self, root: u64, lo: u64, hi: u64) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+
+        if root != 0 {
+            self.index_range_into(root, (lo, 0), (hi, u64::MAX), &mut entries, 0)?;
+        }
+
+        Ok(entries)
+    }
+
+    fn index_range_into(
+        &self,
+        block_offset: u64,
+        lo: Entry,
+        hi: Entry,
+        entries: &mut Vec<Entry>,
+        depth: usize,
+    ) -> Result<()> {
+        if depth > MAX_INDEX_DEPTH {
+            return Err(GdbError::Corrupt {
+                offset: block_offset,
+            });
+        }
+
+        let index_block = self.read_index_block(block_offset)?;
+
+        if index_block.leaf {
+            entries.extend(
+                index_block
+                    .entries
+                    .iter()
+                    .filter(|entry| lo <= **entry && **entry <= hi),
+            );
+            return Ok(());
+        }
+
+        // visit each child whose span [separator i - 1, separator i) meets [lo, hi]
+        for (i, child) in index_block.children.iter().enumerate() {
+            let starts_after_hi = i > 0 && index_block.entries[i - 1] > hi;
+            let ends_before_lo = i < index_block.entries.len() && index_block.entries[i] <= lo;
+
+            if !starts_after_hi && !ends_before_lo {
+                self.index_range_into(*child, lo, hi, entries, depth + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    //  Offsets of every block of the tree at root.
+    pub(crate) fn index_blocks(&self, root: u64) -> Result<Vec<u64>> {
+        let mut blocks = Vec::new();
+        let mut pending = vec![root];
+
+        while let Some(block_offset) = pending.pop() {
+            if block_offset == 0 {
+                continue;
+            }
+            if blocks.len() as u64 >= self.read_header()?.total_blocks {
+                return Err(GdbError::Corrupt {
+                    offset: block_offset,
+                }); // more blocks than the file holds, a loop
+            }
+
+            pending.extend(self.read_index_block(block_offset)?.children);
+            blocks.push(block_offset);
+        }
+
+        Ok(blocks)
+    }
+
+    //  Insert into the tree whose root the header holds in root, moving it when the root splits. Only valid inside a logged operation.
+    pub(crate) fn header_index_insert(
+        &mut self,
+        root: fn(&mut Header) -> &mut u64,
+        key: u64,
+        offset: u64,
+    ) -> Result<()> {
+        let mut header = self.read_header()?;
+        let new_root = self.index_insert(*root(&mut header), key, offset)?;
+
+        if new_root != *root(&mut header) {
+            header = self.read_header()?; // allocation moved the free list
+            *root(&mut header) = new_root;
+            self.write_header(&header)?;
+        }
+
+        Ok(())
+    }
+
+    //  Remove from the tree whose root the header holds in root, moving it when the root is freed. Only valid inside a logged operation.
+    pub(crate) fn header_index_remove(
+        &mut self,
+        root: fn(&mut Header) -> &mut u64,
+        key: u64,
+        offset: u64,
+    ) -> Result<()> {
+        let mut header = self.read_header()?;
+        let new_root = self.index_remove(*root(&mut header), key, offset)?;
+
+        if new_root != *root(&mut header) {
+            header = self.read_header()?; // freeing moved the free list
+            *root(&mut header) = new_root;
+            self.write_header(&header)?;
+        }
+
+        Ok(())
+    }
+
+    //  Record that the node block at offset holds id and name. Only valid inside a logged operation.
+    pub(crate) fn index_node(&mut self, id: u64, name: &str, offset: u64) -> Result<()> {
+        self.header_index_insert(|header| &mut header.id_index, id, offset)?;
+        self.index_name(name, offset)?;
+
+        self.index_text(offset)
+    }
+
+    //  Forget the node block at offset holding id and name, while its properties are still in place. Only valid inside a logged operation.
+    pub(crate) fn unindex_node(&mut self, id: u64, name: &str, offset: u64) -> Result<()> {
+        self.unindex_text(offset)?;
+
+        self.header_index_remove(|header| &mut header.id_index, id, offset)?;
+
+        self.unindex_name(name, offset)
+    }
+
+    //  Record that the node block at offset is called name. Only valid inside a logged operation.
+    pub(crate) fn index_name(&mut self, name: &str, offset: u64) -> Result<()> {
+        let key = name_index_key(name);
+        self.header_index_insert(|header| &mut header.name_index, key, offset)
+    }
+
+    //  Forget that the node block at offset is called name. Only valid inside a logged operation.
+    pub(crate) fn unindex_name(&mut self, name: &str, offset: u64) -> Result<()> {
+        let key = name_index_key(name);
+        self.header_index_remove(|header| &mut header.name_index, key, offset)
+    }
+
+    //  Node blocks that may be called folded (or start with it when prefix is set), in index order. Callers compare the full names.
+    pub(crate) fn indexed_name_offsets(&self, folded: &str, prefix: bool) -> Result<Vec<u64>> {
+        let root = self.read_header()?.name_index;
+        let lo = name_key(folded, 0);
+        let hi = if prefix { name_key(folded, 0xff) } else { lo };
+
+        Ok(self
+            .index_range(root, lo, hi)?
+            .into_iter()
+            .map(|(_, offset)| offset)
+            .collect())
+    }
+
+    //  Offset of the first node block holding id, according to the id index.
+    pub(crate) fn indexed_node_offset(&self, id: u64) -> Result<Option<u64>> {
+        let root = self.read_header()?.id_index;
+
+        Ok(self
+            .index_range(root, id, id)?
+            .first()
+            .map(|(_, offset)| *offset))
+    }
+
+    //  Throw every index away and build it again from the records in the file. Only valid inside a logged operation.
+    pub(crate) fn rebuild_indexes(&mut self) -> Result<()> {
+        let header = self.read_header()?;
+        for root in [header.id_index, header.name_index, header.text_index] {
+            for block_offset in self.index_blocks(root)? {
+                self.free_block(block_offset)?;
+            }
+        }
+
+        let mut header = self.read_header()?;
+        header.id_index = 0;
+        header.name_index = 0;
+        header.text_index = 0;
+        header.text_documents = 0;
+        header.text_terms = 0;
+        self.write_header(&header)?;
+
+        for offset in self.offsets_of(BlockType::Node)? {
+            let node = self.get_node(offset)?;
+            self.index_node(node.id, &node.name, offset)?;
+        }
+
+        self.rebuild_property_indexes()
+    }
+}
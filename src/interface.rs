@@ -32,7 +32,7 @@ pub fn terminal_test() -> Result<(), io::Error> {
 
     // Main loop
     loop {
-        terminal.draw(|mut f| {
+        terminal.draw(|f| {
             let size = f.size();
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
@@ -46,7 +46,7 @@ pub fn terminal_test() -> Result<(), io::Error> {
                 .split(size);
 
             // Render menu items
-            let menu_items = vec![
+            let menu_items = [
                 ("Create", MenuItem::Create),
                 ("Read", MenuItem::Read),
                 ("Update", MenuItem::Update),
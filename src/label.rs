@@ -0,0 +1,218 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Node labels.
+
+    A node carries any number of labels ("Person", "Admin", ...). Label names
+    are kept in the dictionary and each (node, label) pair is one LabelBlock
+    linked onto two chains: the node's own labels, starting at its
+    label_head, and every node carrying the label, starting at the
+    dictionary entry's head. nodes_with_label() only follows the second
+    chain, so it reads one block per matching node instead of the whole file.
+
+    New members go to the front of a label's chain, new labels to the tail
+    of a node's chain. The member chain is doubly linked, so taking a node
+    off it is O(1) however many nodes carry the label.
+*/
+
+// type imports can be combined, but this is easier to read
+use crate::database::Database;
+use crate::error::{GdbError, RecordKind, Result};
+use crate::types::Node; // import structs
+use crate::types::{BlockType, DictionaryBlock, DictionaryKind, LabelBlock, NodeBlock}; // import Block Types
+use crate::wal::WalOp;
+
+impl Database {
+    //  Every (offset, label block) on the chain starting at head, following next
+    fn label_chain(
+        &self,
+        head: u64,
+        next: fn(&LabelBlock) -> u64,
+    ) -> Result<Vec<(u64, LabelBlock)>> {
+        let mut chain = Vec::new();
+        let mut label_address = head;
+
+        while label_address != 0 {
+            let label_block = self.read_record_block::<LabelBlock>(
+                label_address,
+                BlockType::Label,
+                RecordKind::Label,
+            )?;
+            let label_next = next(&label_block);
+
+            chain.push((label_address, label_block));
+            label_address = label_next;
+        }
+
+        Ok(chain)
+    }
+
+    //  Offset of the dictionary entry for label id
+    fn label_entry_offset(&self, label: u64) -> Result<u64> {
+        self.dictionary_entries()?
+            .into_iter()
+            .find(|(_, entry)| entry.kind == DictionaryKind::Label && entry.id == label)
+            .map(|(offset, _)| offset)
+            .ok_or_else(|| GdbError::not_found(RecordKind::Label, label))
+    }
+
+    //  Splice the label block at offset out of its label's member chain, O(1)
+    fn unlink_member(&mut self, offset: u64, label_block: &LabelBlock) -> Result<()> {
+        let (member_prev, member_next) = (label_block.member_prev, label_block.member_next);
+
+        if member_prev != 0 {
+            let mut prev_block = self.read_block::<LabelBlock>(member_prev)?;
+            prev_block.member_next = member_next;
+            self.write_block(member_prev, &prev_block)?;
+        } else {
+            let entry_offset = self.label_entry_offset(label_block.label)?;
+            let mut entry = self.read_block::<DictionaryBlock>(entry_offset)?;
+            if entry.head != offset {
+                return Err(GdbError::Corrupt { offset }); // label block missing from its label's chain
+            }
+            entry.head = member_next;
+            self.write_block(entry_offset, &entry)?;
+        }
+
+        if member_next != 0 {
+            let mut next_block = self.read_block::<LabelBlock>(member_next)?;
+            next_block.member_prev = member_prev;
+            self.write_block(member_next, &next_block)?;
+        }
+
+        Ok(())
+    }
+
+    //  Add label to the node with node_id, adding a label it already carries does nothing
+    pub fn add_label(&mut self, node_id: u64, label: &str) -> Result<()> {
+        self.logged_op(WalOp::CreateLabel, |db| {
+            let node_address = db.get_node_address_from_id(node_id)?;
+
+            // new labels are added to the dictionary on first use
+            let label_id = db.intern(DictionaryKind::Label, label)?;
+
+            let chain = db.label_chain(
+                db.read_block::<NodeBlock>(node_address)?.label_head,
+                |block| block.label_next,
+            )?;
+            if chain.iter().any(|(_, block)| block.label == label_id) {
+                return Ok(());
+            }
+
+            // take first empty from the free list, growing the file if needed
+            let offset = db.allocate_block()?;
+
+            // front of the label's chain, O(1) however many nodes carry it
+            let entry_offset = db.label_entry_offset(label_id)?;
+            let mut entry = db.read_block::<DictionaryBlock>(entry_offset)?;
+
+            let label_block = LabelBlock {
+                block_type: BlockType::Label,
+                label: label_id,
+                node: node_address,
+                label_next: 0,
+                member_next: entry.head,
+                member_prev: 0,
+            };
+            db.write_block(offset, &label_block)?;
+
+            if entry.head != 0 {
+                let mut next_block = db.read_block::<LabelBlock>(entry.head)?;
+                next_block.member_prev = offset;
+                db.write_block(entry.head, &next_block)?;
+            }
+
+            entry.head = offset;
+            db.write_block(entry_offset, &entry)?;
+
+            // tail of the node's chain so labels keep the order they were added in
+            match chain.last() {
+                Some((tail_offset, _)) => {
+                    let mut tail_block = db.read_block::<LabelBlock>(*tail_offset)?;
+                    tail_block.label_next = offset;
+                    db.write_block(*tail_offset, &tail_block)?;
+                }
+                None => {
+                    let mut node_block = db.read_block::<NodeBlock>(node_address)?;
+                    node_block.label_head = offset;
+                    db.write_block(node_address, &node_block)?;
+                }
+            }
+
+            // the node now has to follow the label's schema and constraints
+            db.enforce_schema(node_id)?;
+            db.enforce_constraints(node_id)
+        })
+    }
+
+    //  Remove label from the node with node_id
+    pub fn remove_label(&mut self, node_id: u64, label: &str) -> Result<()> {
+        self.logged_op(WalOp::DeleteLabel, |db| {
+            let node_address = db.get_node_address_from_id(node_id)?;
+            let not_carried = || GdbError::not_found(RecordKind::Label, label);
+
+            let Some((_, entry)) = db.dictionary_entry(DictionaryKind::Label, label)? else {
+                return Err(not_carried());
+            };
+
+            let mut node_block = db.read_block::<NodeBlock>(node_address)?;
+            let chain = db.label_chain(node_block.label_head, |block| block.label_next)?;
+
+            let Some(i) = chain.iter().position(|(_, block)| block.label == entry.id) else {
+                return Err(not_carried());
+            };
+            let (offset, label_block) = &chain[i];
+
+            // unlink from the node's chain, then from the label's
+            if i == 0 {
+                node_block.label_head = label_block.label_next;
+                db.write_block(node_address, &node_block)?;
+            } else {
+                let prev_offset = chain[i - 1].0;
+                let mut prev_block = db.read_block::<LabelBlock>(prev_offset)?;
+                prev_block.label_next = label_block.label_next;
+                db.write_block(prev_offset, &prev_block)?;
+            }
+
+            db.unlink_member(*offset, label_block)?;
+            db.free_block(*offset)?;
+
+            // its properties and relationships may have needed the label
+            db.enforce_schema(node_id)
+        })
+    }
+
+    //  Labels of the node with node_id, in the order they were added
+    pub fn node_labels(&self, node_id: u64) -> Result<Vec<String>> {
+        let node = self.get_node_from_id(node_id)?;
+
+        self.label_chain(node.label_head, |block| block.label_next)?
+            .iter()
+            .map(|(_, block)| self.dictionary_name(DictionaryKind::Label, block.label))
+            .collect()
+    }
+
+    //  Every node carrying label, most recently labelled first. Reads only the label's own chain.
+    pub fn nodes_with_label(&self, label: &str) -> Result<Vec<Node>> {
+        let Some((_, entry)) = self.dictionary_entry(DictionaryKind::Label, label)? else {
+            return Ok(Vec::new()); // a label never stored has no nodes
+        };
+
+        self.label_chain(entry.head, |block| block.member_next)?
+            .iter()
+            .map(|(_, block)| self.get_node(block.node))
+            .collect()
+    }
+
+    //  Free every label block on the chain starting at label_head, unlinking each from its label. Only valid inside a logged operation.
+    pub(crate) fn delete_labels(&mut self, label_head: u64) -> Result<()> {
+        for (offset, label_block) in self.label_chain(label_head, |block| block.label_next)? {
+            self.unlink_member(offset, &label_block)?;
+            self.free_block(offset)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+/*
+    Simon H - 2024
+*/
+
+pub mod api;
+pub mod attribute;
+pub mod check;
+pub mod compact;
+pub mod constraint;
+pub mod database;
+pub mod dictionary;
+pub mod disk;
+pub mod error;
+pub mod index;
+pub mod interface;
+pub mod label;
+pub mod migrate;
+pub mod node;
+pub mod overflow;
+pub mod property_index;
+pub mod relationship;
+pub mod schema;
+pub mod str_conversion;
+pub mod test;
+pub mod text_index;
+pub mod transaction;
+pub mod types; // Import the types module
+pub mod wal;
+
+pub use database::Database;
+pub use error::{GdbError, Result};
+pub use transaction::Transaction;
@@ -2,15 +2,7 @@
     Simon H - 2024
 */
 
-mod api;
-mod attribute;
-mod disk;
-mod interface;
-mod node;
-mod relationship;
-mod str_conversion;
-mod test;
-mod types; // Import the types module
+use gdb_rust::{migrate, test, types, Database, GdbError, Result};
 
 const TITLE: &str = r#"
             ___  ____   __   ____  _  _    ____   __  ____  __   ____   __   ____  ____
@@ -19,34 +11,112 @@ const TITLE: &str = r#"
            \___/(__\_)\_/\_/(__)  \_)(_/  (____/\_/\_/(__)\_/\_/(____/\_/\_/(____/(____)
         "#;
 
-fn db_test() {
+fn db_test() -> Result<()> {
     types::assert_struct_size_equality();
     types::print_struct_info();
 
-    println!("Format: {:?}", disk::format_disk(20));
-    println!("Header: {:?}", disk::print_header());
+    let mut db = Database::create(types::PATH, 20)?;
+    db.print_header()?;
 
-    // println!("Block 1: {:?}", disk::print_block(24));
+    println!("Nodes:");
+    test::test_nodes(&mut db)?;
 
-    // disk::print_first_empty();
+    println!("Relationships:");
+    test::test_relationships(&mut db)?;
 
-    println!("Nodes: {:?}", test::test_nodes());
+    println!("Blocks:");
+    db.print_all_blocks()?;
+    db.print_header()?;
 
-    // println!("Block 2: {:?}", disk::print_block(24));
+    db.export_database(types::EXPORT_PATH)?;
 
-    // disk::print_first_empty();
+    Ok(())
+}
+
+//  gdb-rust migrate <input> <output>: rewrite a database from an older format version.
+fn migrate_command(args: &[String]) -> Result<()> {
+    let [input, output] = args else {
+        eprintln!("usage: gdb-rust migrate <input> <output>");
+        return Err(GdbError::Io(std::io::ErrorKind::InvalidInput.into()));
+    };
+
+    let report = migrate::migrate(input, output)?;
+
+    println!(
+        "Migrated {} (version {}) -> {} (version {})",
+        input, report.from_version, output, report.to_version
+    );
+    println!(
+        "Nodes: {}, Relationships: {}, Attributes: {}",
+        report.nodes, report.relationships, report.attributes
+    );
+    if report.renumbered > 0 {
+        println!(
+            "Renumbered {} nodes with an invalid or duplicate id",
+            report.renumbered
+        );
+    }
+
+    Ok(())
+}
+
+//  gdb-rust check <database> [--repair]: report (and optionally fix) inconsistencies, exits 1 if any are left.
+fn check_command(args: &[String]) -> Result<()> {
+    let (path, repair) = match args {
+        [path] => (path, false),
+        [path, flag] if flag == "--repair" => (path, true),
+        _ => {
+            eprintln!("usage: gdb-rust check <database> [--repair]");
+            return Err(GdbError::Io(std::io::ErrorKind::InvalidInput.into()));
+        }
+    };
 
-    println!("Relationships: {:?}\n", test::test_relationships());
-    // let n = disk::print_block(24);
+    let mut db = Database::open(path)?;
+    let report = if repair { db.repair()? } else { db.check()? };
 
-    println!("blocks: {:?}\n", disk::print_all_blocks());
-    // disk::print_n_blocks(20).expect("Big no no in print_n_blocks...");
+    for violation in &report.violations {
+        println!("{}", violation);
+    }
 
-    println!("Header 2: {:?}\n", disk::print_header());
+    if report.is_clean() {
+        println!("{}: no problems found", path);
+    } else if report.repaired {
+        println!("{}: {} problems repaired", path, report.violations.len());
+    } else {
+        println!("{}: {} problems found", path, report.violations.len());
+        std::process::exit(1); // left unrepaired, fail scripts gating on the check
+    }
 
-    println!("Export {:?}\n", disk::export_database());
+    Ok(())
 }
-fn main() {
-    db_test();
-    // interface::terminal_test();
+
+//  gdb-rust compact <database>: move live blocks to the front and shrink the file.
+fn compact_command(args: &[String]) -> Result<()> {
+    let [path] = args else {
+        eprintln!("usage: gdb-rust compact <database>");
+        return Err(GdbError::Io(std::io::ErrorKind::InvalidInput.into()));
+    };
+
+    let report = Database::open(path)?.compact()?;
+
+    println!(
+        "{}: {} -> {} blocks, {} bytes reclaimed",
+        path, report.blocks_before, report.blocks_after, report.bytes_reclaimed
+    );
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("migrate") => migrate_command(&args[1..]),
+        Some("check") => check_command(&args[1..]),
+        Some("compact") => compact_command(&args[1..]),
+        _ => {
+            println!("{}", TITLE);
+            db_test()
+        }
+    }
 }
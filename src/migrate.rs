@@ -0,0 +1,871 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Offline migration between on-disk format versions.
+
+    A file in an older layout is read into a version independent snapshot:
+    one record per block slot, with chain pointers (rlt_head, rlt_next,
+    attr_head, attr_next) stored as slot numbers instead of byte offsets.
+    The snapshot is then written out as a brand new file in the current
+    layout, every record staying in the same slot, so node ids and the
+    order of every relationship and attribute chain are kept as they were.
+
+    Supported inputs:
+        1 - unversioned files (24 byte header, blocks start at offset 24)
+        2 - versioned 128 byte header, blocks without checksums
+        3 - version 2 layout with a CRC32 at the end of the header and
+            every block
+        4 - variable length strings with overflow blocks
+        5 - typed key/value attributes
+        6 - relationship types held in a name dictionary
+        7 - node labels
+        8 - incoming relationship chains
+        9 - doubly linked relationship chains
+        10 - node id index
+        11 - node ids assigned from a counter in the header
+        12 - node name index
+        13 - property indexes declared in the dictionary
+        14 - full-text index
+        15 - constraints declared in the dictionary
+        16 - schema catalog
+        17 - doubly linked label member chains
+        18 - schema generation in the header
+
+    Attributes written before version 5 only held a string, each one comes
+    through as a property named after that string and set to true. Files
+    before version 8 have no incoming relationship chains, they are built
+    from each relationship's node_to, and back pointers missing before
+    version 9 (member_prev before version 17) are filled in from the next
+    pointers. Index blocks are never
+    carried over, every index is rebuilt once the records are written, and
+    the node id counter starts after the highest id in the file.
+
+    Files written before version 11 chose their own node ids and may hold
+    id 0, the reserved u64::MAX or one id on several nodes. Every such node
+    but the first holder of an id is given a new id after the highest one,
+    and the relationships on its own chains are pointed at the new id.
+
+    When a block layout changes, bump FORMAT_VERSION, freeze the old block
+    structs in a module here and add a reader for them to read_snapshot.
+*/
+
+use bincode::deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::database::{verify_slot, Database};
+use crate::error::{GdbError, Result};
+use crate::property_index::is_property_index;
+use crate::str_conversion;
+use crate::types::{Attribute, PropertyValue, Relationship}; // import structs
+use crate::types::{BlockType, DictionaryBlock, DictionaryKind, EmptyBlock}; // import Block Types
+use crate::types::{LabelBlock, NodeBlock, RelationshipBlock}; // import Block Types
+use crate::types::{BLOCK_SIZE, FORMAT_VERSION, HEADER_SIZE, MAGIC, RLT_PAD};
+use crate::wal::WalOp;
+
+/*
+    Version 1 layout, frozen here so later changes to types.rs don't alter
+    how old files are read.
+*/
+pub(crate) mod v1 {
+    use serde_derive::{Deserialize, Serialize};
+
+    pub const HEADER_SIZE: u64 = 24; // header was written unpadded
+    pub const BLOCK_SIZE: u64 = 96;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    pub enum BlockType {
+        Empty,
+        Unset,
+        Node,
+        Relationship,
+        Attribute,
+        Final,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Header {
+        pub total_blocks: u64,
+        pub first_empty: u64,
+        pub db_size: u64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct NodeBlock {
+        pub block_type: BlockType,
+        pub id: u64,
+        pub name: [char; 16],
+        pub rlt_head: u64,
+        pub attr_head: u64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct RelationshipBlock {
+        pub block_type: BlockType,
+        pub node_from: u64,
+        pub node_to: u64,
+        pub rlt_next: u64,
+        pub attr_head: u64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct AttributeBlock {
+        pub block_type: BlockType,
+        pub value: [char; 16],
+        pub attr_next: u64,
+    }
+}
+
+/*
+    Version 2 layout: magic bytes and version in a reserved header region,
+    blocks are laid out and encoded exactly as in version 1.
+*/
+pub(crate) mod v2 {
+    use serde_derive::{Deserialize, Serialize};
+
+    pub const HEADER_SIZE: u64 = 128;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Header {
+        pub magic: [u8; 4],
+        pub format_version: u32,
+        pub block_size: u32,
+        pub feature_flags: u32,
+        pub total_blocks: u64,
+        pub first_empty: u64,
+        pub db_size: u64,
+    }
+}
+
+/*
+    Version 4 layout: header and blocks are framed as in the current
+    version, attributes hold a plain string instead of a key and value.
+*/
+pub(crate) mod v4 {
+    use crate::types::{BlockType, StoredString};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct AttributeBlock {
+        pub block_type: BlockType,
+        pub value: StoredString,
+        pub attr_next: u64,
+    }
+}
+
+/*
+    Version 6 layout: nodes without a label chain, dictionary entries
+    without a member chain.
+*/
+pub(crate) mod v6 {
+    use crate::types::{BlockType, DictionaryKind, StoredString};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct NodeBlock {
+        pub block_type: BlockType,
+        pub id: u64,
+        pub name: StoredString,
+        pub rlt_head: u64,
+        pub attr_head: u64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct DictionaryBlock {
+        pub block_type: BlockType,
+        pub kind: DictionaryKind,
+        pub id: u64,
+        pub name: StoredString,
+        pub next: u64,
+    }
+}
+
+// Record held in a block slot, pointers are slot numbers (None ends a chain)
+#[derive(Debug, PartialEq)]
+pub enum SnapshotRecord {
+    Empty,
+    Node {
+        id: u64,
+        name: String,
+        rlt_head: Option<u64>,
+        attr_head: Option<u64>,
+        label_head: Option<u64>,
+        in_head: Option<u64>,
+    },
+    Relationship {
+        node_from: u64,
+        node_to: u64,
+        rlt_next: Option<u64>,
+        attr_head: Option<u64>,
+        rlt_type: u64, // dictionary id, 0 = untyped
+        in_next: Option<u64>,
+        rlt_prev: Option<u64>,
+        in_prev: Option<u64>,
+    },
+    Attribute {
+        key: String,
+        value: PropertyValue,
+        attr_next: Option<u64>,
+    },
+    Dictionary {
+        kind: DictionaryKind,
+        id: u64,
+        name: String,
+        next: Option<u64>,
+        head: Option<u64>,
+    },
+    Label {
+        label: u64, // dictionary id
+        node: Option<u64>,
+        label_next: Option<u64>,
+        member_next: Option<u64>,
+        member_prev: Option<u64>,
+    },
+}
+
+impl SnapshotRecord {
+    //  Attribute written before values had a key and a type.
+    fn untyped_attribute(value: String, attr_next: Option<u64>) -> Self {
+        SnapshotRecord::Attribute {
+            key: value,
+            value: PropertyValue::Bool(true),
+            attr_next,
+        }
+    }
+}
+
+// Contents of a database file independent of its on-disk layout
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub records: Vec<SnapshotRecord>, // indexed by slot
+    pub dictionary_head: Option<u64>,
+    pub renumbered: u64, // nodes given a new id because theirs was invalid or taken
+}
+
+impl Snapshot {
+    //  Give nodes holding id 0, u64::MAX or an id an earlier node holds the next free id.
+    fn renumber_nodes(&mut self) -> Result<()> {
+        let valid = |id: u64| id != 0 && id != u64::MAX;
+        let mut next_id = 1 + self
+            .records
+            .iter()
+            .filter_map(|record| match record {
+                SnapshotRecord::Node { id, .. } if valid(*id) => Some(*id),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        let mut held = HashSet::new();
+        for i in 0..self.records.len() {
+            let SnapshotRecord::Node {
+                id,
+                rlt_head,
+                in_head,
+                ..
+            } = &mut self.records[i]
+            else {
+                continue;
+            };
+            if valid(*id) && held.insert(*id) {
+                continue;
+            }
+            if next_id == u64::MAX {
+                return Err(GdbError::ConstraintViolation(
+                    "node ids are exhausted".to_string(),
+                ));
+            }
+
+            *id = next_id;
+            next_id += 1;
+            self.renumbered += 1;
+
+            // the chains say which relationships are this node's, the ids alone can't
+            let (id, rlt_head, in_head) = (*id, *rlt_head, *in_head);
+            let mut visited = HashSet::new();
+            let mut target = rlt_head;
+            while let Some(slot) = target.filter(|&slot| visited.insert(slot)) {
+                let Some(SnapshotRecord::Relationship {
+                    node_from,
+                    rlt_next,
+                    ..
+                }) = self.records.get_mut(slot as usize)
+                else {
+                    break;
+                };
+                *node_from = id;
+                target = *rlt_next;
+            }
+
+            visited.clear();
+            let mut target = in_head;
+            while let Some(slot) = target.filter(|&slot| visited.insert(slot)) {
+                let Some(SnapshotRecord::Relationship {
+                    node_to, in_next, ..
+                }) = self.records.get_mut(slot as usize)
+                else {
+                    break;
+                };
+                *node_to = id;
+                target = *in_next;
+            }
+        }
+
+        Ok(())
+    }
+
+    //  Thread every relationship onto the incoming chain of its node_to, replacing any existing chains.
+    fn link_incoming(&mut self) {
+        let mut node_slots = HashMap::new(); // node id -> slot
+        for (i, record) in self.records.iter_mut().enumerate() {
+            if let SnapshotRecord::Node { id, in_head, .. } = record {
+                *in_head = None;
+                node_slots.insert(*id, i);
+            }
+        }
+
+        for i in 0..self.records.len() {
+            let SnapshotRecord::Relationship { node_to, .. } = self.records[i] else {
+                continue;
+            };
+
+            // a relationship to a missing node stays off every chain, check() reports it
+            let head = match node_slots
+                .get(&node_to)
+                .map(|&slot| &mut self.records[slot])
+            {
+                Some(SnapshotRecord::Node { in_head, .. }) => in_head.replace(i as u64),
+                _ => None,
+            };
+
+            if let SnapshotRecord::Relationship { in_next, .. } = &mut self.records[i] {
+                *in_next = head;
+            }
+        }
+    }
+
+    //  Point every label back at the one before it on its label's member chain.
+    fn link_members_back(&mut self) {
+        let mut member_prevs = HashMap::new(); // slot -> previous member of the same label
+
+        for (i, record) in self.records.iter().enumerate() {
+            if let SnapshotRecord::Label {
+                member_next: Some(next),
+                ..
+            } = record
+            {
+                member_prevs.insert(*next, i as u64);
+            }
+        }
+
+        for (i, record) in self.records.iter_mut().enumerate() {
+            if let SnapshotRecord::Label { member_prev, .. } = record {
+                *member_prev = member_prevs.get(&(i as u64)).copied();
+            }
+        }
+    }
+
+    //  Point every relationship back at the one before it on both of its chains.
+    fn link_back(&mut self) {
+        let mut rlt_prevs = HashMap::new(); // slot -> previous relationship from the same node
+        let mut in_prevs = HashMap::new(); // slot -> previous relationship to the same node
+
+        for (i, record) in self.records.iter().enumerate() {
+            if let SnapshotRecord::Relationship {
+                rlt_next, in_next, ..
+            } = record
+            {
+                if let Some(next) = rlt_next {
+                    rlt_prevs.insert(*next, i as u64);
+                }
+                if let Some(next) = in_next {
+                    in_prevs.insert(*next, i as u64);
+                }
+            }
+        }
+
+        for (i, record) in self.records.iter_mut().enumerate() {
+            if let SnapshotRecord::Relationship {
+                rlt_prev, in_prev, ..
+            } = record
+            {
+                *rlt_prev = rlt_prevs.get(&(i as u64)).copied();
+                *in_prev = in_prevs.get(&(i as u64)).copied();
+            }
+        }
+    }
+}
+
+// Summary of a finished migration
+#[derive(Debug, PartialEq)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub nodes: u64,
+    pub relationships: u64,
+    pub attributes: u64,
+    pub renumbered: u64, // nodes whose id was 0, reserved or already taken
+}
+
+//  Format version of the file at path, files without the magic bytes are version 1.
+pub fn detect_version<P: AsRef<Path>>(path: P) -> Result<u32> {
+    let mut prefix = Vec::with_capacity(8);
+    File::open(path.as_ref())?
+        .take(8)
+        .read_to_end(&mut prefix)?;
+
+    if prefix.len() == 8 && prefix[..4] == MAGIC {
+        Ok(u32::from_le_bytes(prefix[4..8].try_into().unwrap()))
+    } else {
+        Ok(1)
+    }
+}
+
+//  Read the file at input in whatever supported version it is in and write it to output in the current format.
+pub fn migrate<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<MigrationReport> {
+    // writing the output truncates it first, it must not be the input
+    if let Ok(output) = output.as_ref().canonicalize() {
+        if input.as_ref().canonicalize()? == output {
+            return Err(GdbError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "migration input and output are the same file",
+            )));
+        }
+    }
+
+    let from_version = detect_version(input.as_ref())?;
+
+    let snapshot = read_snapshot(input.as_ref(), from_version)?;
+
+    write_snapshot(&snapshot, output.as_ref())?;
+
+    let mut report = MigrationReport {
+        from_version,
+        to_version: FORMAT_VERSION,
+        nodes: 0,
+        relationships: 0,
+        attributes: 0,
+        renumbered: snapshot.renumbered,
+    };
+    for record in &snapshot.records {
+        match record {
+            SnapshotRecord::Node { .. } => report.nodes += 1,
+            SnapshotRecord::Relationship { .. } => report.relationships += 1,
+            SnapshotRecord::Attribute { .. } => report.attributes += 1,
+            SnapshotRecord::Empty
+            | SnapshotRecord::Dictionary { .. }
+            | SnapshotRecord::Label { .. } => {}
+        }
+    }
+
+    Ok(report)
+}
+
+//  Read a file written in any supported format version.
+pub fn read_snapshot(path: &Path, version: u32) -> Result<Snapshot> {
+    let mut snapshot = match version {
+        1 => read_v1(path),
+        2 => read_v2(path, false),
+        3 => read_v2(path, true),
+        4..=FORMAT_VERSION => read_framed(path, version),
+        _ => Err(GdbError::UnsupportedVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        }),
+    }?;
+
+    snapshot.renumber_nodes()?; // a no-op for files written since ids are checked
+    if version < 8 {
+        snapshot.link_incoming();
+    }
+    if version < 9 {
+        snapshot.link_back();
+    }
+    if version < 17 {
+        snapshot.link_members_back();
+    }
+
+    Ok(snapshot)
+}
+
+//  Chain pointer (byte offset) to slot number, 0 and pointers outside the block area end the chain.
+fn offset_to_slot(
+    offset: u64,
+    header_size: u64,
+    block_size: u64,
+    total_blocks: u64,
+) -> Option<u64> {
+    if offset < header_size || !(offset - header_size).is_multiple_of(block_size) {
+        return None;
+    }
+
+    let slot = (offset - header_size) / block_size;
+    (slot < total_blocks).then_some(slot)
+}
+
+fn fixed_chars_to_string(chars: &[char; 16]) -> String {
+    str_conversion::char_print(chars).trim_end().to_string()
+}
+
+fn read_v1(path: &Path) -> Result<Snapshot> {
+    let file = File::open(path)?;
+
+    let mut buffer = vec![0u8; v1::HEADER_SIZE as usize];
+    file.read_exact_at(&mut buffer, 0)
+        .map_err(|_| GdbError::NotADatabase)?;
+    let header = deserialize::<v1::Header>(&buffer)?;
+
+    read_v1_blocks(&file, v1::HEADER_SIZE, header.total_blocks, false)
+}
+
+//  Version 2 and 3 share a layout, version 3 adds a CRC32 trailer to the header and every block.
+fn read_v2(path: &Path, checksummed: bool) -> Result<Snapshot> {
+    let file = File::open(path)?;
+
+    let mut buffer = vec![0u8; v2::HEADER_SIZE as usize];
+    file.read_exact_at(&mut buffer, 0)
+        .map_err(|_| GdbError::NotADatabase)?;
+    if checksummed {
+        verify_slot(&buffer, 0)?;
+    }
+    let header = deserialize::<v2::Header>(&buffer)?;
+
+    if header.block_size as u64 != v1::BLOCK_SIZE {
+        return Err(GdbError::IncompatibleLayout(format!(
+            "block size {} (expected {})",
+            header.block_size,
+            v1::BLOCK_SIZE
+        )));
+    }
+
+    read_v1_blocks(&file, v2::HEADER_SIZE, header.total_blocks, checksummed)
+}
+
+//  Read total_blocks version 1 encoded blocks starting at header_size.
+fn read_v1_blocks(
+    file: &File,
+    header_size: u64,
+    total_blocks: u64,
+    checksummed: bool,
+) -> Result<Snapshot> {
+    // the block area must fit in the file, anything else is not a database
+    let blocks_len = total_blocks.checked_mul(v1::BLOCK_SIZE);
+    match blocks_len.and_then(|len| len.checked_add(header_size)) {
+        Some(end) if end <= file.metadata()?.len() => {}
+        _ => return Err(GdbError::NotADatabase),
+    }
+
+    let slot = |offset| offset_to_slot(offset, header_size, v1::BLOCK_SIZE, total_blocks);
+
+    let mut snapshot = Snapshot::default();
+    let mut buffer = vec![0u8; v1::BLOCK_SIZE as usize];
+
+    for i in 0..total_blocks {
+        let offset = header_size + i * v1::BLOCK_SIZE;
+        file.read_exact_at(&mut buffer, offset)?;
+        if checksummed {
+            verify_slot(&buffer, offset)?;
+        }
+
+        let corrupt = |_| GdbError::Corrupt { offset };
+        let block_type = deserialize::<v1::BlockType>(&buffer).map_err(corrupt)?;
+
+        let record = match block_type {
+            v1::BlockType::Node => {
+                let block = deserialize::<v1::NodeBlock>(&buffer).map_err(corrupt)?;
+                SnapshotRecord::Node {
+                    id: block.id,
+                    name: fixed_chars_to_string(&block.name),
+                    rlt_head: slot(block.rlt_head),
+                    attr_head: slot(block.attr_head),
+                    label_head: None,
+                    in_head: None,
+                }
+            }
+            v1::BlockType::Relationship => {
+                let block = deserialize::<v1::RelationshipBlock>(&buffer).map_err(corrupt)?;
+                SnapshotRecord::Relationship {
+                    node_from: block.node_from,
+                    node_to: block.node_to,
+                    rlt_next: slot(block.rlt_next),
+                    attr_head: slot(block.attr_head),
+                    rlt_type: 0,
+                    in_next: None,
+                    rlt_prev: None,
+                    in_prev: None,
+                }
+            }
+            v1::BlockType::Attribute => {
+                let block = deserialize::<v1::AttributeBlock>(&buffer).map_err(corrupt)?;
+                SnapshotRecord::untyped_attribute(
+                    fixed_chars_to_string(&block.value),
+                    slot(block.attr_next),
+                )
+            }
+            v1::BlockType::Empty | v1::BlockType::Unset | v1::BlockType::Final => {
+                SnapshotRecord::Empty
+            }
+        };
+
+        snapshot.records.push(record);
+    }
+
+    Ok(snapshot)
+}
+
+//  Read a file with the current header and block framing (version 4 onwards) through the Database API.
+fn read_framed(path: &Path, version: u32) -> Result<Snapshot> {
+    let db = Database::open_read_only(path, version)?;
+    let header = db.read_header()?;
+
+    let slot = |offset| offset_to_slot(offset, HEADER_SIZE, BLOCK_SIZE, header.total_blocks);
+
+    let mut snapshot = Snapshot::default();
+
+    for i in 0..header.total_blocks {
+        let offset = HEADER_SIZE + i * BLOCK_SIZE;
+
+        let record = match db.get_block(offset)?.block_type {
+            BlockType::Node if version <= 6 => {
+                let block = db.read_block::<v6::NodeBlock>(offset)?;
+                SnapshotRecord::Node {
+                    id: block.id,
+                    name: db.load_string(&block.name, offset)?,
+                    rlt_head: slot(block.rlt_head),
+                    attr_head: slot(block.attr_head),
+                    label_head: None,
+                    in_head: None,
+                }
+            }
+            BlockType::Node => {
+                let node = db.get_node(offset)?;
+                SnapshotRecord::Node {
+                    id: node.id,
+                    name: node.name,
+                    rlt_head: slot(node.rlt_head),
+                    attr_head: slot(node.attr_head),
+                    label_head: slot(node.label_head),
+                    in_head: slot(node.in_head),
+                }
+            }
+            BlockType::Relationship => {
+                let relationship = db.get_relationship(offset)?;
+                SnapshotRecord::Relationship {
+                    node_from: relationship.node_from,
+                    node_to: relationship.node_to,
+                    rlt_next: slot(relationship.rlt_next),
+                    attr_head: slot(relationship.attr_head),
+                    rlt_type: relationship.rlt_type,
+                    in_next: slot(relationship.in_next),
+                    rlt_prev: slot(relationship.rlt_prev),
+                    in_prev: slot(relationship.in_prev),
+                }
+            }
+            BlockType::Attribute if version == 4 => {
+                let block = db.read_block::<v4::AttributeBlock>(offset)?;
+                SnapshotRecord::untyped_attribute(
+                    db.load_string(&block.value, offset)?,
+                    slot(block.attr_next),
+                )
+            }
+            BlockType::Attribute => {
+                let attribute = db.read_attribute(offset)?;
+                SnapshotRecord::Attribute {
+                    key: attribute.key,
+                    value: attribute.value,
+                    attr_next: slot(attribute.attr_next),
+                }
+            }
+            BlockType::Dictionary if version <= 6 => {
+                let block = db.read_block::<v6::DictionaryBlock>(offset)?;
+                SnapshotRecord::Dictionary {
+                    kind: block.kind,
+                    id: block.id,
+                    name: db.load_string(&block.name, offset)?,
+                    next: slot(block.next),
+                    head: None,
+                }
+            }
+            BlockType::Dictionary => {
+                let block = db.read_block::<DictionaryBlock>(offset)?;
+                SnapshotRecord::Dictionary {
+                    kind: block.kind,
+                    id: block.id,
+                    name: db.load_string(&block.name, offset)?,
+                    next: slot(block.next),
+                    // index trees are rebuilt, only label chains come across
+                    head: if is_property_index(block.kind) {
+                        None
+                    } else {
+                        slot(block.head)
+                    },
+                }
+            }
+            BlockType::Label => {
+                let block = db.read_block::<LabelBlock>(offset)?;
+                SnapshotRecord::Label {
+                    label: block.label,
+                    node: slot(block.node),
+                    label_next: slot(block.label_next),
+                    member_next: slot(block.member_next),
+                    member_prev: slot(block.member_prev),
+                }
+            }
+            // strings are read whole with their holder, overflow and index blocks are written anew
+            BlockType::Empty
+            | BlockType::Unset
+            | BlockType::Final
+            | BlockType::Overflow
+            | BlockType::Index => SnapshotRecord::Empty,
+        };
+
+        snapshot.records.push(record);
+    }
+
+    snapshot.dictionary_head = slot(header.dictionary_head);
+
+    Ok(snapshot)
+}
+
+//  Create a database at path in the current format holding every record of snapshot in the same slot.
+pub fn write_snapshot(snapshot: &Snapshot, path: &Path) -> Result<Database> {
+    let mut db = Database::create(path, snapshot.records.len() as u64)?;
+
+    db.logged_op(WalOp::Migrate, |db| {
+        let to_offset = |slot: Option<u64>| slot.map_or(0, |slot| HEADER_SIZE + slot * BLOCK_SIZE);
+        let slot_offset = |i: usize| HEADER_SIZE + i as u64 * BLOCK_SIZE;
+
+        // free list first, walking backwards so it runs front to back
+        let mut header = db.read_header()?;
+        let mut first_empty = 0;
+
+        for (i, record) in snapshot.records.iter().enumerate().rev() {
+            if *record == SnapshotRecord::Empty {
+                db.write_block(slot_offset(i), &EmptyBlock::new(first_empty))?;
+                first_empty = slot_offset(i);
+            }
+        }
+
+        header.first_empty = first_empty;
+        header.dictionary_head = to_offset(snapshot.dictionary_head);
+        header.next_node_id = 1 + snapshot
+            .records
+            .iter()
+            .filter_map(|record| match record {
+                SnapshotRecord::Node { id, .. } => Some(*id),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        db.write_header(&header)?;
+
+        // then the records, long strings take their overflow blocks from that free list
+        for (i, record) in snapshot.records.iter().enumerate() {
+            let offset = slot_offset(i);
+
+            match record {
+                SnapshotRecord::Empty => {}
+                SnapshotRecord::Node {
+                    id,
+                    name,
+                    rlt_head,
+                    attr_head,
+                    label_head,
+                    in_head,
+                } => {
+                    let node_block = NodeBlock {
+                        block_type: BlockType::Node,
+                        id: *id,
+                        name: db.store_string(name)?,
+                        rlt_head: to_offset(*rlt_head),
+                        attr_head: to_offset(*attr_head),
+                        label_head: to_offset(*label_head),
+                        in_head: to_offset(*in_head),
+                    };
+                    db.write_block(offset, &node_block)?;
+                }
+                SnapshotRecord::Relationship {
+                    node_from,
+                    node_to,
+                    rlt_next,
+                    attr_head,
+                    rlt_type,
+                    in_next,
+                    rlt_prev,
+                    in_prev,
+                } => {
+                    let relationship_block = RelationshipBlock {
+                        block_type: BlockType::Relationship,
+                        relationship: Relationship {
+                            node_from: *node_from,
+                            node_to: *node_to,
+                            rlt_next: to_offset(*rlt_next),
+                            attr_head: to_offset(*attr_head),
+                            rlt_type: *rlt_type,
+                            in_next: to_offset(*in_next),
+                            rlt_prev: to_offset(*rlt_prev),
+                            in_prev: to_offset(*in_prev),
+                        },
+                        pad: [0; RLT_PAD],
+                    };
+                    db.write_block(offset, &relationship_block)?;
+                }
+                SnapshotRecord::Attribute {
+                    key,
+                    value,
+                    attr_next,
+                } => {
+                    let attribute = Attribute {
+                        key: key.clone(),
+                        value: value.clone(),
+                        attr_next: to_offset(*attr_next),
+                    };
+                    db.write_attribute(offset, &attribute)?;
+                }
+                SnapshotRecord::Dictionary {
+                    kind,
+                    id,
+                    name,
+                    next,
+                    head,
+                } => {
+                    let dictionary_block = DictionaryBlock {
+                        block_type: BlockType::Dictionary,
+                        kind: *kind,
+                        id: *id,
+                        name: db.store_string(name)?,
+                        next: to_offset(*next),
+                        head: to_offset(*head),
+                    };
+                    db.write_block(offset, &dictionary_block)?;
+                }
+                SnapshotRecord::Label {
+                    label,
+                    node,
+                    label_next,
+                    member_next,
+                    member_prev,
+                } => {
+                    let label_block = LabelBlock {
+                        block_type: BlockType::Label,
+                        label: *label,
+                        node: to_offset(*node),
+                        label_next: to_offset(*label_next),
+                        member_next: to_offset(*member_next),
+                        member_prev: to_offset(*member_prev),
+                    };
+                    db.write_block(offset, &label_block)?;
+                }
+            }
+        }
+
+        db.rebuild_indexes()
+    })?;
+
+    Ok(db)
+}
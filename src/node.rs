@@ -2,349 +2,322 @@
     Simon H - 2024
 */
 
-use bincode::{deserialize, serialize};
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
-use std::mem::size_of;
-
-use crate::attribute::*;
-use crate::disk::*;
-use crate::relationship::*;
-
 // type imports can be combined, but this is easier to read
-use crate::str_conversion;
-use crate::types::PATH;
+use crate::database::Database;
+use crate::error::{GdbError, RecordKind, Result};
+use crate::index::fold_case;
 use crate::types::{BlockType, NodeBlock}; // import Block Types
-use crate::types::{Header, Node}; // import structs // import db PATH // import fixed static strings helper functions
-
-// custom error macro
-macro_rules! custom_error {
-    ($msg:expr) => {
-        return Err(Error::new(ErrorKind::Other, $msg))
-    };
-}
-
-// map bincode error to io error
-macro_rules! map_bincode_error {
-    ($expr:expr) => {
-        $expr.map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Bincode serialization error: {:?}", err),
-            )
-        })
-    };
-}
-
-//  Given an offset print node to console.
-pub fn print_node_name(offset: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).open(PATH).unwrap();
-
-    // Move to offset
-    stream.seek(SeekFrom::Start(offset))?;
-
-    // Read bytes into Block struct
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<NodeBlock>());
-    stream.read_to_end(&mut buffer)?;
-
-    // Decode bytes into Block struct
-    let result_node = map_bincode_error!(deserialize::<NodeBlock>(&buffer))?;
-    println!("-> {}", str_conversion::char_print(&result_node.node.name));
-    Ok(())
-}
+use crate::types::{DeleteMode, Node}; // import structs
+use crate::wal::WalOp;
 
 pub fn compare_node(node1: &Node, node2: &Node) -> bool {
-    if node1.id == node2.id {
-        return true;
-    }
-    false
+    node1.id == node2.id
 }
 
-//  Given offset, return node structure
-pub fn get_node(offset: u64) -> Result<Node> {
-    let mut stream = File::open(PATH)?;
-
-    // Rewind the stream to the beginning
-    stream.seek(SeekFrom::Start(offset))?;
-
-    // Read the block from the stream
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<NodeBlock>());
-    stream.read_to_end(&mut buffer)?;
-
-    // Decode bytes into Block struct
-    let deserialized_block = map_bincode_error!(deserialize::<NodeBlock>(&buffer))?;
-
-    Ok(deserialized_block.node)
-}
-
-//  Create Node and write it to disk
-pub fn create_node(new_node: Node) -> Result<()> {
-    // let mut stream = File::create(PATH)?;
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    // read header
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-    let mut header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    // go to first empty
-    stream.seek(SeekFrom::Start(header.first_empty))?;
-
-    let node_block = NodeBlock {
-        block_type: BlockType::Node,
-        node: new_node,
-    };
-
-    // write node information
-    let serialized_node_block = map_bincode_error!(serialize(&node_block))?;
-
-    stream.write_all(&serialized_node_block)?;
-
-    // update first empty
-    let new_first_empty = get_first_empty(&stream, &header)?;
-
-    // update header
-    if new_first_empty == 0 {
-        expand_file(10)?;
-        // create_node(&new_node);  //TODO: Remove possibility for 0 offset, expand automatically inside new_first_empty
-        custom_error!("No first empty found, expanded file.")
-    } else {
-        println!("New First Empty: {}\r", new_first_empty);
-        header.first_empty = new_first_empty;
-
-        let serialized_header = map_bincode_error!(serialize(&header))?;
-        stream.seek(SeekFrom::Start(0))?;
-        stream.write_all(&serialized_header)?;
-
-        println!(" - Create Node successful...\r\n");
+impl Database {
+    //  Given an offset print node to console.
+    pub fn print_node_name(&self, offset: u64) -> Result<()> {
+        let node = self.get_node(offset)?;
+        println!("-> {}", node.name);
         Ok(())
     }
-}
-
-//  Given id, return node Address
-pub fn get_node_from_id(id: u64) -> Result<Node> {
-    // read header
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
-
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-
-    let header_result = map_bincode_error!(deserialize::<Header>(&buffer))?;
 
-    for i in 0..header_result.total_blocks {
-        let offset = size_of::<Header>() as u64 + (i * size_of::<NodeBlock>() as u64);
-        let node = get_node(offset)?;
-
-        if node.id == id {
-            return Ok(node);
-        }
+    //  Given offset, return node structure
+    pub fn get_node(&self, offset: u64) -> Result<Node> {
+        let node_block =
+            self.read_record_block::<NodeBlock>(offset, BlockType::Node, RecordKind::Node)?;
+
+        Ok(Node {
+            id: node_block.id,
+            name: self.load_string(&node_block.name, offset)?,
+            rlt_head: node_block.rlt_head,
+            attr_head: node_block.attr_head,
+            label_head: node_block.label_head,
+            in_head: node_block.in_head,
+        })
     }
 
-    custom_error!("Not found, FATAL...");
-}
-
-//  Basic Find node function
-pub fn get_node_address(node: &Node) -> Result<u64> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
-
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-
-    let header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    for i in 0..header.total_blocks {
-        let offset = size_of::<Header>() as u64 + (i * size_of::<NodeBlock>() as u64);
-        let current_node = get_node(offset)?;
-
-        if compare_node(&current_node, &node) {
-            return Ok(offset);
+    //  Id for a new node: the next one from the header's counter when requested is 0, otherwise requested if no node holds it. Only valid inside a logged operation.
+    fn assign_node_id(&mut self, requested: u64) -> Result<u64> {
+        let mut header = self.read_header()?;
+
+        // u64::MAX is never handed out, so the counter can always move past an id
+        let id = match requested {
+            0 if header.next_node_id == u64::MAX => {
+                return Err(GdbError::ConstraintViolation(
+                    "node ids are exhausted".to_string(),
+                ));
+            }
+            0 => header.next_node_id,
+            u64::MAX => {
+                return Err(GdbError::ConstraintViolation(format!(
+                    "node id {} is reserved",
+                    u64::MAX
+                )));
+            }
+            id => id,
+        };
+
+        if self.indexed_node_offset(id)?.is_some() {
+            return Err(GdbError::ConstraintViolation(format!(
+                "node id {} already exists",
+                id
+            )));
         }
-    }
-    custom_error!("Not found, FATAL...");
-}
-
-pub fn get_node_address_from_name(name: &String) -> Result<u64> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
 
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
+        // ids are never handed out twice, even after their node is deleted
+        header.next_node_id = header.next_node_id.max(id + 1);
+        self.write_header(&header)?;
 
-    let header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-    let modified_string = str_conversion::str_to_fixed_chars(&name);
-
-    for i in 0..header.total_blocks {
-        let offset = size_of::<Header>() as u64 + (i * size_of::<NodeBlock>() as u64);
-        let current_node = get_node(offset)?;
-
-        if current_node.name.eq(&modified_string) {
-            // equivalence check (==)
-            return Ok(offset);
-        }
+        Ok(id)
     }
-    custom_error!("Not found, FATAL...");
-}
-
-// traverse file and print each block
-pub fn print_all_nodes() -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
-
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
 
-    let header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    for i in 0..header.total_blocks {
-        let offset = size_of::<Header>() as u64 + (i * size_of::<NodeBlock>() as u64);
-        let node = get_node(offset)?;
-
-        println!("Node: {:?}\r", node);
+    //  Create Node and write it to disk, returning its id. Leave id at 0 to have the database pick the next one.
+    pub fn create_node(&mut self, new_node: Node) -> Result<u64> {
+        self.logged_op(WalOp::CreateNode, |db| {
+            let id = db.assign_node_id(new_node.id)?;
+
+            // take first empty from the free list, growing the file if needed
+            let offset = db.allocate_block()?;
+
+            let node_block = NodeBlock {
+                block_type: BlockType::Node,
+                id,
+                name: db.store_string(&new_node.name)?, // long names spill into overflow blocks
+                rlt_head: new_node.rlt_head,
+                attr_head: new_node.attr_head,
+                label_head: 0, // labels are added with add_label
+                in_head: 0,    // incoming relationships link themselves on creation
+            };
+
+            // write node information
+            db.write_block(offset, &node_block)?;
+            db.index_node(id, &new_node.name, offset)?;
+            db.enforce_schema(id)?;
+
+            println!(" - Create Node successful...\r\n");
+            Ok(id)
+        })
     }
 
-    Ok(())
-}
-
-/*
-    Modify node's name
-*/
-pub fn update_node_name(node_address: u64, new_node_name: String) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<NodeBlock>());
-
-    stream.seek(SeekFrom::Start(node_address))?;
-    stream.read_to_end(&mut buffer)?;
-
-    let mut node_block = map_bincode_error!(deserialize::<NodeBlock>(&buffer))?;
-
-    node_block.node.name = str_conversion::str_to_fixed_chars(&new_node_name);
-
-    let mut serialized_node_block = map_bincode_error!(serialize(&node_block))?;
-
-    stream.seek(SeekFrom::Start(node_address))?;
-    stream.write_all(&serialized_node_block)?;
-    Ok(())
-}
+    //  Given id, return node
+    pub fn get_node_from_id(&self, id: u64) -> Result<Node> {
+        let offset = self.get_node_address_from_id(id)?;
 
-//  Retrospectively update nodes relationship list head upon creation, if already set follow and set to tail of list.
-fn update_node_rlt(mut node: Node, rlt_offset: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    // send a borrowed instance
-    let node_address = get_node_address(&node)?;
-    stream.seek(SeekFrom::Start(node_address))?;
-
-    if node.rlt_head == 0 {
-        node.rlt_head = rlt_offset;
-
-        let serialized_node = map_bincode_error!(serialize(&node))?;
-        stream.seek(SeekFrom::Start(node_address))?;
-        // stream.write_all_at(&serialized_node, node_address)?;    // linux only command (FileExt)
-        stream.write_all(&serialized_node)?;
-        println!("Updated Node...");
-    } else {
-        append_relationship(node_address, rlt_offset)?;
+        self.get_node(offset)
     }
 
-    Ok(())
-}
-
-//  Retrospectively update nodes attribute list head upon creation, if already set follow and set to tail of list.
-fn update_node_attribute(mut node: Node, attrib_offset: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    let node_address = get_node_address(&node)?;
-
-    if node.attr_head == 0 {
-        node.attr_head = attrib_offset;
-
-        let serialized_node = map_bincode_error!(serialize(&node))?;
-        stream.seek(SeekFrom::Start(node_address))?;
-        // stream.write_all_at(&serialized_node, node_address)?;    // linux only command (FileExt)
-
-        stream.seek(SeekFrom::Start(node_address))?;
-        stream.write_all(&serialized_node)?;
-    } else {
-        append_attribute(node_address, attrib_offset)?;
+    //  Offset of the node block holding id, looked up in the id index
+    pub(crate) fn get_node_address_from_id(&self, id: u64) -> Result<u64> {
+        self.indexed_node_offset(id)?
+            .ok_or_else(|| GdbError::not_found(RecordKind::Node, id))
     }
 
-    Ok(())
-}
-
-//  Given a nodes name remove its record
-pub fn delete_node_name(name: String) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    let node_address = get_node_address_from_name(&name)?;
+    //  Basic Find node function
+    pub fn get_node_address(&self, node: &Node) -> Result<u64> {
+        self.get_node_address_from_id(node.id)
+    }
 
-    // read node information
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<NodeBlock>());
-    stream.read_to_end(&mut buffer)?;
-    let mut node_block = map_bincode_error!(deserialize::<NodeBlock>(&buffer))?;
+    //  Offset of the first node called name, looked up in the name index
+    pub fn get_node_address_from_name(&self, name: &str) -> Result<u64> {
+        for offset in self.indexed_name_offsets(&fold_case(name), false)? {
+            if self.get_node(offset)?.name == name {
+                return Ok(offset);
+            }
+        }
 
-    node_block.block_type = BlockType::Empty;
+        Err(GdbError::not_found(RecordKind::Node, name))
+    }
 
-    // write node information
-    let serialized_node_block = map_bincode_error!(serialize(&node_block))?;
-    stream.seek(SeekFrom::Start(node_address))?;
-    stream.write_all(&serialized_node_block)?;
+    //  Nodes from the name index whose name passes matches, the index is searched for folded
+    fn nodes_from_name_index(
+        &self,
+        folded: &str,
+        prefix: bool,
+        matches: impl Fn(&str) -> bool,
+    ) -> Result<Vec<Node>> {
+        let mut nodes = Vec::new();
+
+        for offset in self.indexed_name_offsets(folded, prefix)? {
+            let node = self.get_node(offset)?;
+
+            if matches(&node.name) {
+                nodes.push(node);
+            }
+        }
 
-    Ok(())
-}
+        Ok(nodes)
+    }
 
-//  Given a Node remove its record
+    //  Every node called name, optionally ignoring case
+    pub fn nodes_with_name(&self, name: &str, ignore_case: bool) -> Result<Vec<Node>> {
+        let folded = fold_case(name);
 
-/*
+        self.nodes_from_name_index(&folded, false, |candidate| {
+            if ignore_case {
+                fold_case(candidate) == folded
+            } else {
+                candidate == name
+            }
+        })
+    }
 
-    Open Nodes File
+    //  Every node whose name starts with prefix, optionally ignoring case
+    pub fn nodes_with_name_prefix(&self, prefix: &str, ignore_case: bool) -> Result<Vec<Node>> {
+        let folded = fold_case(prefix);
 
-    Get node address (passed in function arg)
+        self.nodes_from_name_index(&folded, true, |candidate| {
+            if ignore_case {
+                fold_case(candidate).starts_with(&folded)
+            } else {
+                candidate.starts_with(prefix)
+            }
+        })
+    }
 
-    Seek to node address
-    read block into buffer
-    deserialise
+    // traverse file and print each node
+    pub fn print_all_nodes(&self) -> Result<()> {
+        for offset in self.offsets_of(BlockType::Node)? {
+            let node = self.get_node(offset)?;
 
-    set blocktype as BLOCKTYPE::EMPTY
+            println!("Node: {:?}\r", node);
+        }
 
-    seek back to node address
+        Ok(())
+    }
 
-    write blocktype
+    /*
+        Modify node's name
+    */
+    pub fn update_node_name(&mut self, node_address: u64, new_node_name: String) -> Result<()> {
+        self.logged_op(WalOp::UpdateNode, |db| {
+            let mut node_block =
+                db.read_record_block::<NodeBlock>(node_address, BlockType::Node, RecordKind::Node)?;
 
-    make call to delete_relations(node.rlt_head)
-        return Ok() if relations deleted.
+            // move the name index entry over to the new name
+            let old_name = db.load_string(&node_block.name, node_address)?;
+            db.unindex_name(&old_name, node_address)?;
+            db.index_name(&new_node_name, node_address)?;
+            db.unindex_text(node_address)?;
 
-    update header with new first empty (potential check if different)...
+            // release the old name's overflow blocks before storing the new one
+            db.free_string(&node_block.name)?;
+            node_block.name = db.store_string(&new_node_name)?;
 
-*/
+            db.write_block(node_address, &node_block)?;
+            db.index_text(node_address)?;
 
-pub fn delete_node(node: Node) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
+            db.enforce_constraints(node_block.id)
+        })
+    }
 
-    let empty_block: NodeBlock = Default::default();
-    let empty_block_srl = map_bincode_error!(serialize(&empty_block))?; // serialise
+    //  Retrospectively update nodes relationship list head upon creation, if already set follow and set to tail of list.
+    pub(crate) fn update_node_rlt(&mut self, node: Node, rlt_offset: u64) -> Result<()> {
+        self.logged_op(WalOp::UpdateNode, |db| {
+            let node_address = db.get_node_address(&node)?;
+            let mut node_block = db.read_block::<NodeBlock>(node_address)?;
 
-    let node_address = get_node_address(&node)?;
+            if node_block.rlt_head == 0 {
+                node_block.rlt_head = rlt_offset;
 
-    // read current buffer information
-    let mut header_buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut header_buffer)?;
-    let mut header = map_bincode_error!(deserialize::<Header>(&header_buffer))?;
+                db.write_block(node_address, &node_block)?;
+                println!("Updated Node...");
+            } else {
+                db.append_relationship(node_address, rlt_offset)?;
+            }
 
-    stream.seek(SeekFrom::Start(node_address))?;
+            Ok(())
+        })
+    }
 
-    // read node_block
-    let mut node_buffer: Vec<u8> = Vec::with_capacity(size_of::<NodeBlock>());
-    stream.read_to_end(&mut node_buffer)?;
-    let mut node_block = map_bincode_error!(deserialize::<NodeBlock>(&node_buffer))?;
+    //  Given a nodes name remove its record, mode says what happens to the relationships touching it
+    pub fn delete_node_name(&mut self, name: String, mode: DeleteMode) -> Result<()> {
+        self.logged_op(WalOp::DeleteNode, |db| {
+            let node_address = db.get_node_address_from_name(&name)?;
 
-    stream.seek(SeekFrom::Start(node_address))?;
+            db.delete_node_at(node_address, mode)
+        })
+    }
 
-    stream.write_all(&empty_block_srl)?;
+    //  Given a Node remove its record, mode says what happens to the relationships touching it
+    pub fn delete_node(&mut self, node: Node, mode: DeleteMode) -> Result<()> {
+        self.logged_op(WalOp::DeleteNode, |db| {
+            let node_address = db.get_node_address(&node)?;
 
-    if header.first_empty > node_address {
-        new_first_empty()?;
+            db.delete_node_at(node_address, mode)
+        })
     }
 
-    delete_relations(node_block.node.rlt_head)?;
-    delete_attributes(node_block.node.attr_head)?;
-
-    Ok(())
+    /*
+        Delete the node at node_address, every node deletion goes through here.
+
+        Collect the relationships on the node's outgoing and incoming chains
+        (a self loop is on both, once), then per mode:
+            Restrict - fail if there are any
+            Detach   - delete them, then the node
+            Cascade  - as Detach, then go on to every node the deleted one
+                       pointed at that no relationship points to any more,
+                       and delete it the same way
+
+        Detach only takes the node and what belongs to it, Cascade also takes
+        the nodes that depended on it, e.g. deleting a root deletes the tree
+        under it but a node that is still referenced from elsewhere stays.
+
+        Relationships are deleted first, unlinking them needs the node still
+        in place. The node goes with its name, properties, labels and index
+        entries (delete_record_offset).
+    */
+    pub fn delete_node_at(&mut self, node_address: u64, mode: DeleteMode) -> Result<()> {
+        self.logged_op(WalOp::DeleteNode, |db| {
+            let mut pending = vec![node_address];
+
+            while let Some(node_address) = pending.pop() {
+                let node = db.get_node(node_address)?;
+                let outgoing = db.relationships_from(&node, None)?;
+
+                let mut touching: Vec<u64> = outgoing
+                    .iter()
+                    .map(|(offset, _)| *offset)
+                    .chain(
+                        db.relationships_to(&node, None)?
+                            .into_iter()
+                            .map(|(offset, _)| offset),
+                    )
+                    .collect();
+                touching.sort_unstable();
+                touching.dedup();
+
+                if mode == DeleteMode::Restrict && !touching.is_empty() {
+                    return Err(GdbError::ConstraintViolation(format!(
+                        "node {} still has {} relationship(s), delete them first or use another delete mode",
+                        node.id,
+                        touching.len()
+                    )));
+                }
+
+                for offset in touching {
+                    db.delete_relationship_offset(offset)?;
+                }
+
+                db.delete_record_offset(node_address)?;
+
+                if mode != DeleteMode::Cascade {
+                    continue;
+                }
+
+                for (_, relationship) in outgoing {
+                    let Some(target) = db.indexed_node_offset(relationship.node_to)? else {
+                        continue; // self loop or already deleted
+                    };
+                    if !pending.contains(&target)
+                        && db.relationships_to(&db.get_node(target)?, None)?.is_empty()
+                    {
+                        pending.push(target);
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,100 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Variable length strings.
+
+    Node names are stored as UTF-8 and properties as their bincode encoding:
+    the first INLINE_STRING_SIZE bytes sit in the node or attribute block
+    itself and the rest is split over a chain of overflow blocks taken from
+    the free list. Short strings never need more than their own block.
+*/
+
+use crate::database::Database;
+use crate::error::{GdbError, Result};
+use crate::types::{BlockType, OverflowBlock, StoredString}; // import Block Types
+use crate::types::{INLINE_STRING_SIZE, OVERFLOW_DATA_SIZE};
+
+impl Database {
+    //  Store string, allocating overflow blocks for whatever doesn't fit inline.
+    pub(crate) fn store_string(&mut self, string: &str) -> Result<StoredString> {
+        self.store_bytes(string.as_bytes())
+    }
+
+    //  Read back a stored string, holder is the offset of the block it belongs to.
+    pub(crate) fn load_string(&self, stored: &StoredString, holder: u64) -> Result<String> {
+        String::from_utf8(self.load_bytes(stored)?)
+            .map_err(|_| GdbError::Corrupt { offset: holder })
+    }
+
+    //  Store raw bytes the same way as a string.
+    pub(crate) fn store_bytes(&mut self, bytes: &[u8]) -> Result<StoredString> {
+        let (inline, rest) = bytes.split_at(bytes.len().min(INLINE_STRING_SIZE));
+
+        let chunks: Vec<&[u8]> = rest.chunks(OVERFLOW_DATA_SIZE).collect();
+        let offsets = chunks
+            .iter()
+            .map(|_| self.allocate_block())
+            .collect::<Result<Vec<u64>>>()?;
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let overflow_block = OverflowBlock {
+                block_type: BlockType::Overflow,
+                data: chunk.to_vec(),
+                next: offsets.get(i + 1).copied().unwrap_or(0),
+            };
+            self.write_block(offsets[i], &overflow_block)?;
+        }
+
+        Ok(StoredString {
+            inline: inline.to_vec(),
+            overflow: offsets.first().copied().unwrap_or(0),
+        })
+    }
+
+    //  Inline bytes followed by the contents of every overflow block.
+    pub(crate) fn load_bytes(&self, stored: &StoredString) -> Result<Vec<u8>> {
+        let mut bytes = stored.inline.clone();
+
+        for (_, overflow_block) in self.overflow_chain(stored)? {
+            bytes.extend(overflow_block.data);
+        }
+
+        Ok(bytes)
+    }
+
+    //  Return every overflow block of a stored string to the free list.
+    pub(crate) fn free_string(&mut self, stored: &StoredString) -> Result<()> {
+        for (offset, _) in self.overflow_chain(stored)? {
+            self.free_block(offset)?;
+        }
+
+        Ok(())
+    }
+
+    //  Overflow blocks holding the tail of a stored string, with their offsets.
+    fn overflow_chain(&self, stored: &StoredString) -> Result<Vec<(u64, OverflowBlock)>> {
+        let total_blocks = self.read_header()?.total_blocks;
+
+        let mut chain = Vec::new();
+        let mut offset = stored.overflow;
+
+        while offset != 0 {
+            let overflow_block = self.read_block::<OverflowBlock>(offset)?;
+
+            // wrong block type, or longer than the file can hold (a cycle)
+            if overflow_block.block_type != BlockType::Overflow
+                || chain.len() as u64 >= total_blocks
+            {
+                return Err(GdbError::Corrupt { offset });
+            }
+
+            let next = overflow_block.next;
+            chain.push((offset, overflow_block));
+            offset = next;
+        }
+
+        Ok(chain)
+    }
+}
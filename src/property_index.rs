@@ -0,0 +1,412 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Property indexes.
+
+    create_property_index() declares an index over one property key of
+    nodes. Each index is a dictionary entry named after the key whose head
+    is the root of an index tree, entries map a u64 derived from the value
+    to the node block holding it:
+
+        - hash indexes key on a hash of the value, they answer nodes_where()
+        - ordered indexes key on an encoding that sorts like the values of
+          one type (integers, floats, timestamps, booleans, and strings or
+          bytes by their first 8 bytes), they answer nodes_where() and
+          nodes_between(). Lists are left out of ordered indexes.
+
+    Different values can share a key, so every candidate is read back and
+    its value compared before it is returned. Queries on a key without an
+    index read every node instead.
+
+    set_property, remove_property and node deletion keep every index on the
+    key up to date. Relationship properties are not indexed.
+*/
+
+use std::cmp::Ordering;
+
+// type imports can be combined, but this is easier to read
+use crate::database::Database;
+use crate::error::{GdbError, RecordKind, Result};
+use crate::types::{BlockType, DictionaryBlock, DictionaryKind}; // import Block Types
+use crate::types::{IndexKind, Node, PropertyValue}; // import structs
+use crate::wal::WalOp;
+
+//  Dictionary kind declaring an index of kind.
+fn dictionary_kind(kind: IndexKind) -> DictionaryKind {
+    match kind {
+        IndexKind::Hash => DictionaryKind::HashIndex,
+        IndexKind::Ordered => DictionaryKind::OrderedIndex,
+    }
+}
+
+//  Values of the same type in their natural order, None for values of different types or lists.
+pub fn compare_values(a: &PropertyValue, b: &PropertyValue) -> Option<Ordering> {
+    match (a, b) {
+        (PropertyValue::String(a), PropertyValue::String(b)) => Some(a.cmp(b)),
+        (PropertyValue::Int(a), PropertyValue::Int(b)) => Some(a.cmp(b)),
+        (PropertyValue::Float(a), PropertyValue::Float(b)) => a.partial_cmp(b),
+        (PropertyValue::Bool(a), PropertyValue::Bool(b)) => Some(a.cmp(b)),
+        (PropertyValue::Bytes(a), PropertyValue::Bytes(b)) => Some(a.cmp(b)),
+        (PropertyValue::Timestamp(a), PropertyValue::Timestamp(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+//  First 8 bytes big-endian, shorter input padded with zeros.
+fn prefix_key(bytes: &[u8]) -> u64 {
+    let mut key = [0; 8];
+    let len = bytes.len().min(8);
+    key[..len].copy_from_slice(&bytes[..len]);
+
+    u64::from_be_bytes(key)
+}
+
+//  FNV-1a offset basis, the hash of no bytes.
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+//  Continue the FNV-1a hash of some bytes with more bytes.
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+//  Hash index key: FNV-1a over the value's encoding, with -0.0 hashed as 0.0 since they compare equal.
+fn hash_key(value: &PropertyValue) -> u64 {
+    let mut hash = FNV_OFFSET;
+    let mut feed = |bytes: &[u8]| hash = fnv1a(hash, bytes);
+
+    fn walk(value: &PropertyValue, feed: &mut dyn FnMut(&[u8])) {
+        match value {
+            PropertyValue::String(string) => {
+                feed(&[0]);
+                feed(string.as_bytes());
+            }
+            PropertyValue::Int(int) => {
+                feed(&[1]);
+                feed(&int.to_be_bytes());
+            }
+            PropertyValue::Float(float) => {
+                feed(&[2]);
+                feed(&(float + 0.0).to_bits().to_be_bytes()); // -0.0 + 0.0 is 0.0
+            }
+            PropertyValue::Bool(bool) => feed(&[3, *bool as u8]),
+            PropertyValue::Bytes(bytes) => {
+                feed(&[4]);
+                feed(bytes);
+            }
+            PropertyValue::Timestamp(timestamp) => {
+                feed(&[5]);
+                feed(&timestamp.to_be_bytes());
+            }
+            PropertyValue::List(list) => {
+                feed(&[6]);
+                feed(&(list.len() as u64).to_be_bytes());
+                for item in list {
+                    walk(item, feed);
+                }
+            }
+        }
+    }
+
+    walk(value, &mut feed);
+    hash
+}
+
+//  Ordered index key: sorts like the values of one type, None for lists.
+fn ordered_key(value: &PropertyValue) -> Option<u64> {
+    let key = match value {
+        PropertyValue::String(string) => prefix_key(string.as_bytes()),
+        PropertyValue::Bytes(bytes) => prefix_key(bytes),
+        PropertyValue::Int(int) | PropertyValue::Timestamp(int) => (*int as u64) ^ (1 << 63),
+        PropertyValue::Float(float) => {
+            let bits = (float + 0.0).to_bits();
+            // negative floats sort backwards by their bits, flip them all
+            if bits >> 63 == 1 {
+                !bits
+            } else {
+                bits | (1 << 63)
+            }
+        }
+        PropertyValue::Bool(bool) => *bool as u64,
+        PropertyValue::List(_) => return None,
+    };
+
+    Some(key)
+}
+
+//  Key of value in an index of kind, None if such an index leaves it out.
+pub(crate) fn property_index_key(kind: DictionaryKind, value: &PropertyValue) -> Option<u64> {
+    match kind {
+        DictionaryKind::HashIndex => Some(hash_key(value)),
+        DictionaryKind::OrderedIndex => ordered_key(value),
+        _ => None,
+    }
+}
+
+//  Whether entries of kind declare a property index.
+pub(crate) fn is_property_index(kind: DictionaryKind) -> bool {
+    matches!(
+        kind,
+        DictionaryKind::HashIndex | DictionaryKind::OrderedIndex
+    )
+}
+
+impl Database {
+    //  Every index declared on key, with its dictionary offset.
+    fn property_index_entries(&self, key: &str) -> Result<Vec<(u64, DictionaryBlock)>> {
+        let mut entries = Vec::new();
+
+        for (offset, entry) in self.dictionary_entries()? {
+            if is_property_index(entry.kind) && self.load_string(&entry.name, offset)? == key {
+                entries.push((offset, entry));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    //  Add key -> offset to the index tree held by the dictionary entry at entry_offset. Only valid inside a logged operation.
+    fn entry_index_insert(&mut self, entry_offset: u64, key: u64, offset: u64) -> Result<()> {
+        let root = self.read_block::<DictionaryBlock>(entry_offset)?.head;
+        let new_root = self.index_insert(root, key, offset)?;
+
+        if new_root != root {
+            let mut entry = self.read_block::<DictionaryBlock>(entry_offset)?;
+            entry.head = new_root;
+            self.write_block(entry_offset, &entry)?;
+        }
+
+        Ok(())
+    }
+
+    //  Remove key -> offset from the index tree held by the dictionary entry at entry_offset. Only valid inside a logged operation.
+    fn entry_index_remove(&mut self, entry_offset: u64, key: u64, offset: u64) -> Result<()> {
+        let root = self.read_block::<DictionaryBlock>(entry_offset)?.head;
+        let new_root = self.index_remove(root, key, offset)?;
+
+        if new_root != root {
+            let mut entry = self.read_block::<DictionaryBlock>(entry_offset)?;
+            entry.head = new_root;
+            self.write_block(entry_offset, &entry)?;
+        }
+
+        Ok(())
+    }
+
+    //  Add every node holding key to the index declared by the entry at entry_offset. Only valid inside a logged operation.
+    fn fill_property_index(&mut self, entry_offset: u64, key: &str) -> Result<()> {
+        let kind = self.read_block::<DictionaryBlock>(entry_offset)?.kind;
+
+        for node_offset in self.offsets_of(BlockType::Node)? {
+            let node = self.get_node(node_offset)?;
+
+            for (_, attribute) in self.attribute_chain(node.attr_head)? {
+                if attribute.key != key {
+                    continue;
+                }
+                if let Some(index_key) = property_index_key(kind, &attribute.value) {
+                    self.entry_index_insert(entry_offset, index_key, node_offset)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    //  Declare an index on the node property key and fill it from the nodes already stored. Declaring it again does nothing.
+    pub fn create_property_index(&mut self, key: &str, kind: IndexKind) -> Result<()> {
+        self.logged_op(WalOp::CreateIndex, |db| {
+            let kind = dictionary_kind(kind);
+            if db.dictionary_entry(kind, key)?.is_some() {
+                return Ok(());
+            }
+
+            db.intern(kind, key)?;
+            let (entry_offset, _) = db
+                .dictionary_entry(kind, key)?
+                .ok_or_else(|| GdbError::not_found(RecordKind::PropertyIndex, key))?;
+
+            db.fill_property_index(entry_offset, key)
+        })
+    }
+
+    //  Every declared property index, as (key, kind).
+    pub fn property_indexes(&self) -> Result<Vec<(String, IndexKind)>> {
+        let mut indexes = Vec::new();
+
+        for kind in [IndexKind::Hash, IndexKind::Ordered] {
+            for key in self.dictionary_names(dictionary_kind(kind))? {
+                indexes.push((key, kind));
+            }
+        }
+
+        Ok(indexes)
+    }
+
+    //  Record that the node at node_offset holds value under key, in every index on key. Only valid inside a logged operation.
+    pub(crate) fn index_property(
+        &mut self,
+        key: &str,
+        value: &PropertyValue,
+        node_offset: u64,
+    ) -> Result<()> {
+        for (entry_offset, entry) in self.property_index_entries(key)? {
+            if let Some(index_key) = property_index_key(entry.kind, value) {
+                self.entry_index_insert(entry_offset, index_key, node_offset)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    //  Forget that the node at node_offset holds value under key, in every index on key. Only valid inside a logged operation.
+    pub(crate) fn unindex_property(
+        &mut self,
+        key: &str,
+        value: &PropertyValue,
+        node_offset: u64,
+    ) -> Result<()> {
+        for (entry_offset, entry) in self.property_index_entries(key)? {
+            if let Some(index_key) = property_index_key(entry.kind, value) {
+                self.entry_index_remove(entry_offset, index_key, node_offset)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    //  Empty every property index and index every node again. Only valid inside a logged operation.
+    pub(crate) fn rebuild_property_indexes(&mut self) -> Result<()> {
+        for (entry_offset, mut entry) in self.dictionary_entries()? {
+            if !is_property_index(entry.kind) {
+                continue;
+            }
+
+            for block_offset in self.index_blocks(entry.head)? {
+                self.free_block(block_offset)?;
+            }
+            entry.head = 0;
+            self.write_block(entry_offset, &entry)?;
+
+            let key = self.load_string(&entry.name, entry_offset)?;
+            self.fill_property_index(entry_offset, &key)?;
+        }
+
+        Ok(())
+    }
+
+    //  Nodes holding a value under key that passes matches, reading only the candidate offsets when an index gave some.
+    fn nodes_with_property(
+        &self,
+        key: &str,
+        candidates: Option<Vec<u64>>,
+        matches: impl Fn(&PropertyValue) -> bool,
+    ) -> Result<Vec<Node>> {
+        let offsets = match candidates {
+            Some(offsets) => offsets,
+            None => self.offsets_of(BlockType::Node)?, // no index, read every node
+        };
+
+        let mut nodes = Vec::new();
+        for offset in offsets {
+            let node = self.get_node(offset)?;
+
+            let found = self
+                .attribute_chain(node.attr_head)?
+                .into_iter()
+                .any(|(_, attribute)| attribute.key == key && matches(&attribute.value));
+            if found {
+                nodes.push(node);
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    //  Offsets from the index of kind on key whose key lies in lo..=hi, None when key has no such index.
+    fn property_index_candidates(
+        &self,
+        key: &str,
+        kind: IndexKind,
+        lo: u64,
+        hi: u64,
+    ) -> Result<Option<Vec<u64>>> {
+        let Some((_, entry)) = self.dictionary_entry(dictionary_kind(kind), key)? else {
+            return Ok(None);
+        };
+
+        let mut offsets: Vec<u64> = self
+            .index_range(entry.head, lo, hi)?
+            .into_iter()
+            .map(|(_, offset)| offset)
+            .collect();
+        offsets.sort_unstable();
+        offsets.dedup(); // a node holds one value per key, but colliding keys may repeat it
+
+        Ok(Some(offsets))
+    }
+
+    //  Offsets from a hash or ordered index on key that may hold value, None when key has no index for it.
+    fn equal_candidates(&self, key: &str, value: &PropertyValue) -> Result<Option<Vec<u64>>> {
+        let hashed = hash_key(value);
+        let candidates = self.property_index_candidates(key, IndexKind::Hash, hashed, hashed)?;
+        if candidates.is_some() {
+            return Ok(candidates);
+        }
+
+        match ordered_key(value) {
+            Some(ordered) => {
+                self.property_index_candidates(key, IndexKind::Ordered, ordered, ordered)
+            }
+            None => Ok(None),
+        }
+    }
+
+    //  Every node whose property key equals value, in file order. Uses a hash or ordered index on key when there is one.
+    pub fn nodes_where(&self, key: &str, value: &PropertyValue) -> Result<Vec<Node>> {
+        let candidates = self.equal_candidates(key, value)?;
+
+        self.nodes_with_property(key, candidates, |found| found == value)
+    }
+
+    //  Same as nodes_where, None instead of reading every node when key has no index for value.
+    pub(crate) fn indexed_nodes_where(
+        &self,
+        key: &str,
+        value: &PropertyValue,
+    ) -> Result<Option<Vec<Node>>> {
+        let Some(candidates) = self.equal_candidates(key, value)? else {
+            return Ok(None);
+        };
+
+        self.nodes_with_property(key, Some(candidates), |found| found == value)
+            .map(Some)
+    }
+
+    //  Every node whose property key lies between min and max inclusive, in file order. Only values of the same type as min and max match. Uses an ordered index on key when there is one.
+    pub fn nodes_between(
+        &self,
+        key: &str,
+        min: &PropertyValue,
+        max: &PropertyValue,
+    ) -> Result<Vec<Node>> {
+        let candidates = match (ordered_key(min), ordered_key(max)) {
+            (Some(lo), Some(hi)) if lo <= hi => {
+                self.property_index_candidates(key, IndexKind::Ordered, lo, hi)?
+            }
+            (Some(_), Some(_)) => Some(Vec::new()), // empty range
+            _ => None,
+        };
+
+        self.nodes_with_property(key, candidates, |found| {
+            compare_values(found, min).is_some_and(|order| order != Ordering::Less)
+                && compare_values(found, max).is_some_and(|order| order != Ordering::Greater)
+        })
+    }
+}
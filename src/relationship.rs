@@ -2,317 +2,339 @@
     Simon H - 2024
 */
 
-// fn bool deleteRelationshipRecouple(Relationship relationship, fn u64 nodeRltOffset);
-use crate::disk::*;
-use crate::node::*;
-
-use bincode::{deserialize, serialize};
-use libc::RTNLGRP_MCTP_IFADDR;
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
-use std::mem::size_of;
-
 // type imports can be combined, but this is easier to read
-use crate::types;
-use crate::types::{Block, BlockType, NodeBlock, RelationshipBlock}; // import Block Types
-use crate::types::{Header, Node, Relationship}; // import structs
-use crate::types::{PATH, RLT_PAD}; // import db PATH // import fixed static strings helper functions
-
-// custom error macro
-macro_rules! custom_error {
-    ($msg:expr) => {
-        return Err(Error::new(ErrorKind::Other, $msg))
-    };
-}
-
-// map bincode error to io error
-macro_rules! map_bincode_error {
-    ($expr:expr) => {
-        $expr.map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Bincode serialization error: {:?}", err),
-            )
-        })
-    };
-}
+use crate::database::Database;
+use crate::error::{GdbError, RecordKind, Result};
+use crate::types::{BlockType, DictionaryKind, NodeBlock, RelationshipBlock}; // import Block Types
+use crate::types::{Node, Relationship}; // import structs
+use crate::types::RLT_PAD;
+use crate::wal::WalOp;
 
 pub fn print_relationship(relationship: &Relationship) {
     println!("Relationship: {:?}\r", relationship);
 }
 
 pub fn compare_relationship(rlt1: &Relationship, rlt2: &Relationship) -> bool {
-    if rlt1.node_from == rlt2.node_from && rlt1.node_to == rlt2.node_to {
-        return true;
-    }
-    false
+    rlt1.node_from == rlt2.node_from
+        && rlt1.node_to == rlt2.node_to
+        && rlt1.rlt_type == rlt2.rlt_type
 }
 
-pub fn get_relationship(offset: u64) -> Result<Relationship> {
-    let mut stream = File::open(PATH)?;
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<RelationshipBlock>());
-
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.read_to_end(&mut buffer)?;
-
-    let relationship_block = map_bincode_error!(deserialize::<RelationshipBlock>(&buffer))?;
-
-    return Ok(relationship_block.relationship);
-}
-
-pub fn create_relationship(new_relationship: Relationship) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    // read header
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-
-    let mut header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    // go to first empty
-    stream.seek(SeekFrom::Start(header.first_empty))?;
-
-    let relationship_block = RelationshipBlock {
-        block_type: BlockType::Relationship,
-        relationship: new_relationship,
-        pad: [0; RLT_PAD], // pad for consistent sizing across block types
-    };
-
-    // write relationship information
-    let serialized_relationship_block = map_bincode_error!(serialize(&relationship_block))?;
-    stream.write(&serialized_relationship_block)?;
-
-    // update first empty
-    let new_first_empty = get_first_empty(&stream, &header)?;
-
-    // update header
-    if new_first_empty == 0 {
-        expand_file(10)?;
-        create_relationship(new_relationship)?; //TODO: recursive call back once expanded...??
-                                                // custom_error!("No first empty found, expanded file.")
-    } else {
-        let node = get_node_from_id(relationship_block.relationship.node_from)?;
-
-        // update_node_rlt(node, header.first_empty)?;
-
-        // println!("New First Empty: {}\r", new_first_empty);
-        header.first_empty = new_first_empty;
+impl Database {
+    pub fn get_relationship(&self, offset: u64) -> Result<Relationship> {
+        let relationship_block = self.read_record_block::<RelationshipBlock>(
+            offset,
+            BlockType::Relationship,
+            RecordKind::Relationship,
+        )?;
 
-        // write updated header
-        let serialized_header = map_bincode_error!(serialize(&header))?;
-        stream.seek(SeekFrom::Start(0))?;
-        stream.write_all(&serialized_header)?;
-
-        println!(" - Create Relationship successful...\r\n");
+        Ok(relationship_block.relationship)
     }
 
-    Ok(())
-}
+    //  Create relationship of type rlt_type (e.g. "FOLLOWS"), link it into both endpoints' chains and return the offset it was written to
+    pub fn create_relationship(
+        &mut self,
+        new_relationship: Relationship,
+        rlt_type: &str,
+    ) -> Result<u64> {
+        self.logged_op(WalOp::CreateRelationship, |db| {
+            // both ends must be nodes
+            let node_from_address =
+                db.relationship_endpoint("node_from", new_relationship.node_from)?;
+            let node_to_address = db.relationship_endpoint("node_to", new_relationship.node_to)?;
+            let node_from = db.get_node(node_from_address)?;
+
+            // new types are added to the dictionary on first use
+            let rlt_type = db.intern(DictionaryKind::RelationshipType, rlt_type)?;
+
+            // take first empty from the free list, growing the file if needed
+            let offset = db.allocate_block()?;
+
+            // front of node_to's incoming chain
+            let mut node_to_block = db.read_block::<NodeBlock>(node_to_address)?;
+            let in_next = node_to_block.in_head;
+
+            let relationship_block = RelationshipBlock {
+                block_type: BlockType::Relationship,
+                relationship: Relationship {
+                    rlt_type,
+                    rlt_next: 0,
+                    rlt_prev: 0,
+                    in_next,
+                    in_prev: 0,
+                    ..new_relationship
+                },
+                pad: [0; RLT_PAD], // pad for consistent sizing across block types
+            };
+
+            // write relationship information
+            db.write_block(offset, &relationship_block)?;
+
+            if in_next != 0 {
+                let mut next_block = db.read_block::<RelationshipBlock>(in_next)?;
+                next_block.relationship.in_prev = offset;
+                db.write_block(in_next, &next_block)?;
+            }
 
-//  Returns relationships address given a relationship
-pub fn get_relationship_address(relationship: &Relationship) -> Result<u64> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
+            node_to_block.in_head = offset;
+            db.write_block(node_to_address, &node_to_block)?;
 
-    // read header
-    let mut buffer = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-    let header = map_bincode_error!(deserialize::<Header>(&buffer))?;
+            // tail of node_from's outgoing chain
+            db.update_node_rlt(node_from, offset)?;
+            db.enforce_relationship_schema(offset)?;
 
-    for i in 0..header.total_blocks {
-        let offset = size_of::<Header>() as u64 + (i * size_of::<RelationshipBlock>() as u64);
-        let current_relationship = get_relationship(offset)?;
+            println!(" - Create Relationship successful...\r\n");
 
-        if compare_relationship(&current_relationship, &relationship) {
-            return Ok(offset);
-        }
+            Ok(offset)
+        })
     }
 
-    custom_error!("No Relationship Found, FATAL...");
-}
-
-//  Returns attributes address given an attribute
-pub fn get_relationship_from_to(name_from: &String, name_to: &String) -> Result<Relationship> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
-
-    // let name_from = str_to_fixed_chars(&name_from);
-    // let name_to = str_to_fixed_chars(&name_to);
-
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
-
-    let header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-
-    for i in 0..header.total_blocks {
-        let offset = size_of::<Header>() as u64 + (i * size_of::<NodeBlock>() as u64);
-        let block = get_block(offset)?;
-
-        match block.block_type {
-            // need to specify full path (types::BlockType::)...
-            types::BlockType::Relationship => {
-                let node_from_address = get_node_address_from_name(&name_from)?;
-                let node_to_address = get_node_address_from_name(&name_to)?;
-
-                let relationship = get_relationship(offset)?;
-
-                // TODO: switch to .eq??
-                if relationship.node_from == node_from_address
-                    && relationship.node_to == node_to_address
-                {
-                    return Ok(relationship); // yay :)
+    //  Returns the address of a relationship with the same endpoints and type as relationship, found on node_from's outgoing chain
+    pub fn get_relationship_address(&self, relationship: &Relationship) -> Result<u64> {
+        match self.get_node_from_id(relationship.node_from) {
+            Err(GdbError::NotFound { .. }) => {} // no node, nothing links from it
+            node_from => {
+                for (offset, current_relationship) in self.relationships_from(&node_from?, None)? {
+                    if compare_relationship(&current_relationship, relationship) {
+                        return Ok(offset);
+                    }
                 }
             }
-            // nah...
-            _ => {}
         }
-    }
-
-    println!("Requested Relationship Non Existent..."); // TODO: real error needed...
-    custom_error!("No Relationship Found, FATAL...");
-}
 
-//  Print all relations FROM a node.
-pub fn print_from_relations(node: &Node) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).open(PATH)?;
+        Err(GdbError::not_found(
+            RecordKind::Relationship,
+            format!("{} -> {}", relationship.node_from, relationship.node_to),
+        ))
+    }
 
-    match node.rlt_head {
-        0 => {
-            println!("No relations found");
-            return Ok(());
+    //  Name of a relationship's type, None for relationships written before types existed
+    pub fn relationship_type(&self, relationship: &Relationship) -> Result<Option<String>> {
+        if relationship.rlt_type == 0 {
+            return Ok(None);
         }
-        _ => {
-            stream.seek(SeekFrom::Start(node.rlt_head))?;
-            let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<RelationshipBlock>());
-            stream.read_to_end(&mut buffer)?;
-            let rlt = map_bincode_error!(deserialize::<RelationshipBlock>(&buffer))?;
-            stream.seek(SeekFrom::Start(rlt.relationship.rlt_next))?;
 
-            print_relationship(&rlt.relationship);
+        self.dictionary_name(DictionaryKind::RelationshipType, relationship.rlt_type)
+            .map(Some)
+    }
 
-            while rlt.relationship.rlt_next != 0 {
-                let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<RelationshipBlock>());
-                stream.read_to_end(&mut buffer)?;
-                let rlt = map_bincode_error!(deserialize::<RelationshipBlock>(&buffer))?;
+    //  Predicate keeping relationships of type rlt_type, None keeps every relationship
+    fn type_matcher(&self, rlt_type: Option<&str>) -> Result<impl Fn(&Relationship) -> bool> {
+        let wanted = match rlt_type {
+            Some(name) => Some(self.dictionary_id(DictionaryKind::RelationshipType, name)?),
+            None => None,
+        };
 
-                print_relationship(&rlt.relationship);
+        Ok(move |relationship: &Relationship| match wanted {
+            None => true,
+            Some(id) => id == Some(relationship.rlt_type), // a type never stored matches nothing
+        })
+    }
 
-                stream.seek(SeekFrom::Start(rlt.relationship.rlt_next))?;
+    //  Returns relationship between two nodes given their names, optionally only of type rlt_type
+    pub fn get_relationship_from_to(
+        &self,
+        name_from: &str,
+        name_to: &str,
+        rlt_type: Option<&str>,
+    ) -> Result<Relationship> {
+        let node_from = self.get_node(self.get_node_address_from_name(name_from)?)?;
+        let node_to = self.get_node(self.get_node_address_from_name(name_to)?)?;
+
+        for (_, relationship) in self.relationships_from(&node_from, rlt_type)? {
+            if relationship.node_to == node_to.id {
+                return Ok(relationship); // yay :)
             }
         }
-    }
-
-    Ok(())
-}
-
-//  Print all relations TO a node.
-pub fn print_to_relations(node_offset: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut buffer)?;
 
-    let mut header = map_bincode_error!(deserialize::<Header>(&buffer))?;
-    stream.seek(SeekFrom::Start(node_offset))?;
-
-    buffer = Vec::with_capacity(size_of::<NodeBlock>());
+        Err(GdbError::not_found(
+            RecordKind::Relationship,
+            format!("{} -> {}", name_from, name_to),
+        ))
+    }
 
-    let node_block = map_bincode_error!(deserialize::<NodeBlock>(&buffer))?;
+    //  Relationships FROM a node with their offsets, in chain order, optionally only of type rlt_type.
+    pub fn relationships_from(
+        &self,
+        node: &Node,
+        rlt_type: Option<&str>,
+    ) -> Result<Vec<(u64, Relationship)>> {
+        let matches = self.type_matcher(rlt_type)?;
 
-    stream.seek(SeekFrom::Start(0))?;
+        let mut relationships = Vec::new();
+        let mut rlt_address = node.rlt_head;
 
-    buffer = Vec::with_capacity(size_of::<RelationshipBlock>());
+        while rlt_address != 0 {
+            let rlt = self.get_relationship(rlt_address)?;
 
-    for i in 0..header.total_blocks {
-        stream.read_to_end(&mut buffer)?;
-        let temp_rlt = map_bincode_error!(deserialize::<RelationshipBlock>(&buffer))?;
+            if matches(&rlt) {
+                relationships.push((rlt_address, rlt));
+            }
 
-        if temp_rlt.relationship.node_to == node_block.node.id {
-            print_block(map_bincode_error!(deserialize::<Block>(&buffer))?, &buffer)?;
+            rlt_address = rlt.rlt_next;
         }
+
+        Ok(relationships)
     }
 
-    Ok(())
-}
+    //  Relationships TO a node with their offsets, newest first, optionally only of type rlt_type.
+    pub fn relationships_to(
+        &self,
+        node: &Node,
+        rlt_type: Option<&str>,
+    ) -> Result<Vec<(u64, Relationship)>> {
+        let matches = self.type_matcher(rlt_type)?;
 
-pub fn append_relationship(node_address: u64, rlt_offset: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
+        let mut relationships = Vec::new();
+        let mut rlt_address = node.in_head;
 
-    let node = get_node(node_address)?;
+        while rlt_address != 0 {
+            let rlt = self.get_relationship(rlt_address)?;
 
-    stream.seek(SeekFrom::Start(node.rlt_head))?;
+            if matches(&rlt) {
+                relationships.push((rlt_address, rlt));
+            }
 
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<RelationshipBlock>());
+            rlt_address = rlt.in_next;
+        }
 
-    stream.read_to_end(&mut buffer)?;
+        Ok(relationships)
+    }
 
-    let rlt = map_bincode_error!(deserialize::<RelationshipBlock>(&buffer))?;
+    //  Print all relations FROM a node.
+    pub fn print_from_relations(&self, node: &Node) -> Result<()> {
+        if node.rlt_head == 0 {
+            println!("No relations found");
+            return Ok(());
+        }
 
-    if rlt.relationship.rlt_next == 0 {
-        rlt.relationship.rlt_next == rlt_offset;
+        for (_, rlt) in self.relationships_from(node, None)? {
+            print_relationship(&rlt);
+        }
 
-        // TODO: fix
-    } else {
-        append_relationship(rlt.relationship.rlt_next, rlt_offset)?;
+        Ok(())
     }
 
-    Ok(())
-}
+    //  Print all relations TO a node.
+    pub fn print_to_relations(&self, node_offset: u64) -> Result<()> {
+        let node = self.get_node(node_offset)?;
 
-//  Assigns relationshipBlock to EMPTY_BLOCK and writes to disk
-pub fn delete_relationship(relationship: Relationship) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
-
-    let empty_block: NodeBlock = Default::default();
-    let empty_block_srl = map_bincode_error!(serialize(&empty_block))?; // serialise
+        if node.in_head == 0 {
+            println!("No relations found");
+            return Ok(());
+        }
 
-    let rlt_address = get_relationship_address(&relationship)?;
+        for (_, rlt) in self.relationships_to(&node, None)? {
+            print_relationship(&rlt);
+        }
 
-    // read current header information
-    let mut header_buffer: Vec<u8> = Vec::with_capacity(size_of::<Header>());
-    stream.read_to_end(&mut header_buffer)?;
-    let header = map_bincode_error!(deserialize::<Header>(&header_buffer))?;
+        Ok(())
+    }
 
-    stream.seek(SeekFrom::Start(rlt_address))?;
+    //  Follow a nodes relationship list, set the tail's next to rlt_offset and rlt_offset's prev to the tail. The list must not be empty.
+    pub(crate) fn append_relationship(&mut self, node_address: u64, rlt_offset: u64) -> Result<()> {
+        self.logged_op(WalOp::UpdateRelationship, |db| {
+            let node = db.get_node(node_address)?;
 
-    // // read rlt_block
-    // let mut rlt_buffer: Vec<u8> = Vec::with_capacity(size_of::<RelationshipBlock>());
-    // stream.read_to_end(&mut rlt_buffer)?;
-    // let rlt_block = map_bincode_error!(deserialize::<RelationshipBlock>(&rlt_buffer))?;
+            let mut rlt_address = node.rlt_head;
+            let mut rlt_block = db.read_block::<RelationshipBlock>(rlt_address)?;
 
-    stream.seek(SeekFrom::Start(rlt_address))?;
+            while rlt_block.relationship.rlt_next != 0 {
+                rlt_address = rlt_block.relationship.rlt_next;
+                rlt_block = db.read_block::<RelationshipBlock>(rlt_address)?;
+            }
 
-    stream.write_all(&empty_block_srl)?;
+            rlt_block.relationship.rlt_next = rlt_offset;
+            db.write_block(rlt_address, &rlt_block)?;
 
-    if header.first_empty > rlt_address {
-        new_first_empty()?;
+            // and back from the new tail
+            let mut new_block = db.read_block::<RelationshipBlock>(rlt_offset)?;
+            new_block.relationship.rlt_prev = rlt_address;
+            db.write_block(rlt_offset, &new_block)
+        })
     }
 
-    Ok(())
-}
-
-/*
-    Delete specific relationship, without breaking the linked list chain
-*/
-pub fn delete_relationship_recouple(relationship: Relationship) -> Result<()> {
-    Ok()
-}
-
-// traverse linked list of relations and delete along the tree...
-pub fn delete_relations(rlt_head: u64) -> Result<()> {
-    let mut stream = OpenOptions::new().read(true).write(true).open(PATH)?;
+    /*
+        Splice the relationship at rlt_offset out of one of its chains in O(1):
+        its predecessor (or the node, when it is the head) skips to its
+        successor and the successor points back at the predecessor. head,
+        prev and next pick the chain's fields.
+    */
+    fn unlink_relationship(
+        &mut self,
+        node_id: u64,
+        rlt_offset: u64,
+        head: fn(&mut NodeBlock) -> &mut u64,
+        prev: fn(&mut Relationship) -> &mut u64,
+        next: fn(&mut Relationship) -> &mut u64,
+    ) -> Result<()> {
+        let mut relationship = self.get_relationship(rlt_offset)?;
+        let (rlt_prev, rlt_next) = (*prev(&mut relationship), *next(&mut relationship));
+
+        if rlt_prev != 0 {
+            let mut prev_block = self.read_block::<RelationshipBlock>(rlt_prev)?;
+            *next(&mut prev_block.relationship) = rlt_next;
+            self.write_block(rlt_prev, &prev_block)?;
+        } else {
+            match self.get_node_address_from_id(node_id) {
+                Err(GdbError::NotFound { .. }) => {} // endpoint already gone, no head to move
+                node_address => {
+                    let node_address = node_address?;
+                    let mut node_block = self.read_block::<NodeBlock>(node_address)?;
+
+                    if *head(&mut node_block) == rlt_offset {
+                        *head(&mut node_block) = rlt_next;
+                        self.write_block(node_address, &node_block)?;
+                    }
+                }
+            }
+        }
 
-    stream.seek(SeekFrom::Start(rlt_head))?;
+        if rlt_next != 0 {
+            let mut next_block = self.read_block::<RelationshipBlock>(rlt_next)?;
+            *prev(&mut next_block.relationship) = rlt_prev;
+            self.write_block(rlt_next, &next_block)?;
+        }
 
-    let mut rlt_address = rlt_head;
+        Ok(())
+    }
 
-    while rlt_address > 0 {
-        // read rlt information
-        let mut buffer: Vec<u8> = Vec::with_capacity(size_of::<RelationshipBlock>());
-        stream.read_to_end(&mut buffer)?;
-        let rlt_block = map_bincode_error!(deserialize::<RelationshipBlock>(&buffer))?;
+    //  Unlink the relationship at rlt_address from both endpoints' chains and free it with its properties. Only valid inside a logged operation.
+    pub(crate) fn delete_relationship_offset(&mut self, rlt_address: u64) -> Result<()> {
+        let relationship = self.get_relationship(rlt_address)?;
+
+        self.unlink_relationship(
+            relationship.node_from,
+            rlt_address,
+            |node| &mut node.rlt_head,
+            |rlt| &mut rlt.rlt_prev,
+            |rlt| &mut rlt.rlt_next,
+        )?;
+        self.unlink_relationship(
+            relationship.node_to,
+            rlt_address,
+            |node| &mut node.in_head,
+            |rlt| &mut rlt.in_prev,
+            |rlt| &mut rlt.in_next,
+        )?;
+
+        self.free_block(rlt_address)?;
+        self.delete_attributes(relationship.attr_head)
+    }
 
-        delete_relationship(rlt_block.relationship)?;
+    //  Unlink relationship from both endpoints' chains, then assign its block (and its properties) to EMPTY_BLOCK and write to disk
+    pub fn delete_relationship(&mut self, relationship: Relationship) -> Result<()> {
+        self.logged_op(WalOp::DeleteRelationship, |db| {
+            let rlt_address = db.get_relationship_address(&relationship)?;
 
-        rlt_address = rlt_block.relationship.rlt_next
+            db.delete_relationship_offset(rlt_address)
+        })
     }
 
-    Ok(())
+    //  Delete the relationship at rlt_address, unlinking it from both chains without a lookup
+    pub fn delete_relationship_at(&mut self, rlt_address: u64) -> Result<()> {
+        self.logged_op(WalOp::DeleteRelationship, |db| {
+            db.delete_relationship_offset(rlt_address)
+        })
+    }
 }
@@ -0,0 +1,325 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Schema catalog.
+
+    A Schema declares, per label, the properties its nodes may hold and
+    their types, and per relationship type which labels it may join and
+    the properties it may hold, e.g. Service { name: String, port: Int }
+    and DEPENDS_ON: Service -> Service. It is kept as JSON in a single
+    dictionary entry, set with set_schema() or read from a JSON file with
+    load_schema().
+
+    Writes are checked against it before their logged operation ends, a
+    write that does not fit is discarded with GdbError::SchemaViolation:
+
+        - create_node, set_property, remove_property, add_label and
+          remove_label on a node check its properties against its labels,
+          and the relationships touching it
+        - create_relationship, and set_property or remove_property on a
+          relationship, check its type against the labels of its ends and
+          its properties against what its type declares
+
+    In permissive mode only what is declared is checked: a declared
+    property must have its type and a declared relationship type must join
+    one of its label pairs. Strict mode also rejects labels and
+    relationship types the schema does not name, and node or relationship
+    properties nothing declares, so an unlabelled node holds none. A
+    declared property is allowed, not required, Constraint::Required makes
+    a label's nodes hold one.
+
+    Setting a schema checks every node and relationship already stored.
+
+    The parsed schema is cached on the Database, so writes don't parse the
+    JSON again. The header's schema_generation is bumped by every write that
+    can change the stored schema (set_schema, repair, format), the cache is
+    only used while the generation it was read at is still the header's.
+    That covers another handle replacing the schema and discarded writes
+    (a failed operation or a rolled back transaction) alike.
+*/
+
+use std::path::Path;
+use std::rc::Rc;
+
+// type imports can be combined, but this is easier to read
+use crate::database::Database;
+use crate::error::{GdbError, Result};
+use crate::types::{BlockType, DictionaryBlock, DictionaryKind}; // import Block Types
+use crate::types::{PropertyType, PropertyValue, Relationship, Schema, SchemaMode}; // import structs
+use crate::wal::WalOp;
+
+// Schema as last read by a Database handle
+#[derive(Debug, Default)]
+pub(crate) enum SchemaCache {
+    #[default]
+    Unread,
+    // no schema was stored at generation
+    Absent {
+        generation: u64,
+    },
+    // schema stored at generation
+    Loaded {
+        generation: u64,
+        schema: Rc<Schema>,
+    },
+}
+
+//  Type of value.
+pub fn property_type(value: &PropertyValue) -> PropertyType {
+    match value {
+        PropertyValue::String(_) => PropertyType::String,
+        PropertyValue::Int(_) => PropertyType::Int,
+        PropertyValue::Float(_) => PropertyType::Float,
+        PropertyValue::Bool(_) => PropertyType::Bool,
+        PropertyValue::Bytes(_) => PropertyType::Bytes,
+        PropertyValue::Timestamp(_) => PropertyType::Timestamp,
+        PropertyValue::List(_) => PropertyType::List,
+    }
+}
+
+impl Database {
+    //  Dictionary entry holding the schema, with its offset.
+    fn schema_entry(&self) -> Result<Option<(u64, DictionaryBlock)>> {
+        Ok(self
+            .dictionary_entries()?
+            .into_iter()
+            .find(|(_, entry)| entry.kind == DictionaryKind::Schema))
+    }
+
+    //  The stored schema, None when none was ever set.
+    pub fn schema(&self) -> Result<Option<Schema>> {
+        Ok(self.cached_schema()?.map(|schema| (*schema).clone()))
+    }
+
+    //  The stored schema, parsed again only when the header's schema generation has moved on.
+    fn cached_schema(&self) -> Result<Option<Rc<Schema>>> {
+        let generation = self.read_header()?.schema_generation;
+
+        match &*self.schema_cache.borrow() {
+            SchemaCache::Absent { generation: cached } if *cached == generation => return Ok(None),
+            SchemaCache::Loaded {
+                generation: cached,
+                schema,
+            } if *cached == generation => return Ok(Some(schema.clone())),
+            _ => {}
+        }
+
+        let schema = match self.schema_entry()? {
+            Some((offset, entry)) => Some(Rc::new(serde_json::from_str::<Schema>(
+                &self.load_string(&entry.name, offset)?,
+            )?)),
+            None => None,
+        };
+        *self.schema_cache.borrow_mut() = match &schema {
+            Some(schema) => SchemaCache::Loaded {
+                generation,
+                schema: schema.clone(),
+            },
+            None => SchemaCache::Absent { generation },
+        };
+
+        Ok(schema)
+    }
+
+    //  Tell every handle on the file, this one included, that the stored schema may have changed. Only valid inside a logged operation.
+    pub(crate) fn bump_schema_generation(&mut self) -> Result<()> {
+        let mut header = self.read_header()?;
+        header.schema_generation = header.schema_generation.wrapping_add(1);
+        self.write_header(&header)
+    }
+
+    //  Replace the stored schema, failing if a node or relationship already in the file does not fit it.
+    pub fn set_schema(&mut self, schema: Schema) -> Result<()> {
+        self.logged_op(WalOp::UpdateSchema, |db| {
+            let json = serde_json::to_string(&schema)?;
+
+            match db.schema_entry()? {
+                Some((offset, mut entry)) => {
+                    db.free_string(&entry.name)?;
+                    entry.name = db.store_string(&json)?;
+                    db.write_block(offset, &entry)?;
+                }
+                None => {
+                    db.intern(DictionaryKind::Schema, &json)?;
+                }
+            }
+            db.bump_schema_generation()?;
+
+            for offset in db.offsets_of(BlockType::Node)? {
+                let node = db.get_node(offset)?;
+                db.check_node_schema(&schema, node.id)?;
+            }
+            for offset in db.offsets_of(BlockType::Relationship)? {
+                let relationship = db.get_relationship(offset)?;
+                db.check_relationship_schema(&schema, &relationship)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    //  Read a schema from the JSON file at path and set it.
+    pub fn load_schema(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let json = std::fs::read_to_string(path)?;
+
+        self.set_schema(serde_json::from_str(&json)?)
+    }
+
+    //  Fail if the node with node_id, or a relationship touching it, does not fit the stored schema.
+    pub(crate) fn enforce_schema(&self, node_id: u64) -> Result<()> {
+        let Some(schema) = self.cached_schema()? else {
+            return Ok(());
+        };
+
+        self.check_node_schema(&schema, node_id)?;
+
+        let node = self.get_node_from_id(node_id)?;
+        let mut relationships = self.relationships_from(&node, None)?;
+        relationships.extend(self.relationships_to(&node, None)?);
+        for (_, relationship) in relationships {
+            self.check_relationship_schema(&schema, &relationship)?;
+        }
+
+        Ok(())
+    }
+
+    //  Fail if the relationship at offset does not fit the stored schema.
+    pub(crate) fn enforce_relationship_schema(&self, offset: u64) -> Result<()> {
+        let Some(schema) = self.cached_schema()? else {
+            return Ok(());
+        };
+
+        self.check_relationship_schema(&schema, &self.get_relationship(offset)?)
+    }
+
+    //  Fail if the properties of the node with node_id do not fit what schema declares for its labels.
+    fn check_node_schema(&self, schema: &Schema, node_id: u64) -> Result<()> {
+        let labels = self.node_labels(node_id)?;
+        if labels.is_empty() && schema.mode == SchemaMode::Permissive {
+            return Ok(()); // nothing declared for it
+        }
+
+        let declared: Vec<_> = schema
+            .labels
+            .iter()
+            .filter(|label_schema| labels.contains(&label_schema.label))
+            .collect();
+
+        if schema.mode == SchemaMode::Strict {
+            if let Some(label) = labels
+                .iter()
+                .find(|label| !declared.iter().any(|found| found.label == **label))
+            {
+                return Err(GdbError::SchemaViolation(format!(
+                    "node {} has label {}, which the schema does not declare",
+                    node_id, label
+                )));
+            }
+        }
+
+        let node = self.get_node_from_id(node_id)?;
+        for (_, attribute) in self.attribute_chain(node.attr_head)? {
+            let found = property_type(&attribute.value);
+            let mut known = false;
+
+            for label_schema in &declared {
+                let Some(expected) = label_schema.properties.get(&attribute.key) else {
+                    continue;
+                };
+                known = true;
+
+                if *expected != found {
+                    return Err(GdbError::SchemaViolation(format!(
+                        "node {} property {} is {:?}, label {} declares it {:?}",
+                        node_id, attribute.key, found, label_schema.label, expected
+                    )));
+                }
+            }
+
+            if !known && schema.mode == SchemaMode::Strict {
+                return Err(GdbError::SchemaViolation(format!(
+                    "node {} property {} is not declared by any of its labels",
+                    node_id, attribute.key
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    //  Fail if schema does not allow relationship's type between the labels of its ends, or its properties.
+    fn check_relationship_schema(
+        &self,
+        schema: &Schema,
+        relationship: &Relationship,
+    ) -> Result<()> {
+        let Some(rlt_type) = self.relationship_type(relationship)? else {
+            return Ok(()); // untyped, written before types existed
+        };
+
+        let allowed: Vec<_> = schema
+            .relationships
+            .iter()
+            .filter(|rule| rule.rlt_type == rlt_type)
+            .collect();
+
+        if allowed.is_empty() {
+            return match schema.mode {
+                SchemaMode::Permissive => Ok(()),
+                SchemaMode::Strict => Err(GdbError::SchemaViolation(format!(
+                    "relationship type {} is not declared by the schema",
+                    rlt_type
+                ))),
+            };
+        }
+
+        let from_labels = self.node_labels(relationship.node_from)?;
+        let to_labels = self.node_labels(relationship.node_to)?;
+        if !allowed
+            .iter()
+            .any(|rule| from_labels.contains(&rule.from) && to_labels.contains(&rule.to))
+        {
+            let pairs: Vec<String> = allowed
+                .iter()
+                .map(|rule| format!("{} -> {}", rule.from, rule.to))
+                .collect();
+            return Err(GdbError::SchemaViolation(format!(
+                "{} from node {} to node {} does not join {}",
+                rlt_type,
+                relationship.node_from,
+                relationship.node_to,
+                pairs.join(" or ")
+            )));
+        }
+
+        for (_, attribute) in self.attribute_chain(relationship.attr_head)? {
+            let found = property_type(&attribute.value);
+            let mut known = false;
+
+            for rule in &allowed {
+                let Some(expected) = rule.properties.get(&attribute.key) else {
+                    continue;
+                };
+                known = true;
+
+                if *expected != found {
+                    return Err(GdbError::SchemaViolation(format!(
+                        "{} from node {} to node {} property {} is {:?}, the schema declares it {:?}",
+                        rlt_type, relationship.node_from, relationship.node_to, attribute.key, found, expected
+                    )));
+                }
+            }
+
+            if !known && schema.mode == SchemaMode::Strict {
+                return Err(GdbError::SchemaViolation(format!(
+                    "{} from node {} to node {} property {} is not declared by the schema",
+                    rlt_type, relationship.node_from, relationship.node_to, attribute.key
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -11,9 +11,7 @@ pub fn char_print(fixed: &[char]) -> String {
 pub fn populate_fixed_chars(target: &mut [char], input_str: &str) {
     let chars: Vec<char> = input_str.chars().collect();
     let len = chars.len().min(target.len()); // Ensure we don't exceed target length
-    for i in 0..len {
-        target[i] = chars[i];
-    }
+    target[..len].copy_from_slice(&chars[..len]);
 }
 
 // Function to convert a &str to a fixed-size char array
@@ -2,165 +2,2967 @@
     Simon H - 2024
 */
 
-use crate::node::*;
-use crate::relationship::*;
+use crate::database::Database;
+use crate::error::Result;
 use crate::types::{Node, Relationship};
+
+pub fn test_nodes(db: &mut Database) -> Result<()> {
+    // define test nodes
+    let node1 = Node {
+        id: 1,
+        name: "node1".to_string(),
+        rlt_head: 0,
+        attr_head: 0,
+        label_head: 0,
+        in_head: 0,
+    };
+
+    let node2 = Node {
+        id: 2,
+        name: "node2".to_string(),
+        rlt_head: 0,
+        attr_head: 0,
+        label_head: 0,
+        in_head: 0,
+    };
+
+    let node3 = Node {
+        id: 3,
+        name: "node3".to_string(),
+        rlt_head: 0,
+        attr_head: 0,
+        label_head: 0,
+        in_head: 0,
+    };
+
+    db.create_node(node1)?;
+    db.create_node(node2)?;
+    db.create_node(node3)?;
+
+    Ok(())
+}
+
+pub fn test_relationships(db: &mut Database) -> Result<()> {
+    let rlt1 = Relationship {
+        node_from: 1,
+        node_to: 2,
+        rlt_next: 0,
+        attr_head: 0,
+        rlt_type: 0,
+        in_next: 0,
+        rlt_prev: 0,
+        in_prev: 0,
+    };
+
+    let rlt2 = Relationship {
+        node_from: 2,
+        node_to: 3,
+        rlt_next: 0,
+        attr_head: 0,
+        rlt_type: 0,
+        in_next: 0,
+        rlt_prev: 0,
+        in_prev: 0,
+    };
+
+    let rlt3 = Relationship {
+        node_from: 3,
+        node_to: 1,
+        rlt_next: 0,
+        attr_head: 0,
+        rlt_type: 0,
+        in_next: 0,
+        rlt_prev: 0,
+        in_prev: 0,
+    };
+
+    println!("{:?}", rlt1);
+    println!("{:?}", rlt2);
+    println!("{:?}", rlt3);
+
+    db.create_relationship(rlt1, "LINKS")?;
+    db.create_relationship(rlt2, "LINKS")?;
+    db.create_relationship(rlt3, "LINKS")?;
+
+    println!("RltS creation successful...");
+
+    Ok(())
+}
+
 // Module: test
 #[cfg(test)]
 mod tests {
-    use crate::disk::*;
-    use crate::node::*;
-    use crate::relationship::*;
+    use crate::database::{encode_slot, Database};
+    use crate::error::{GdbError, RecordKind};
     use crate::str_conversion;
     use crate::test::{test_nodes, test_relationships};
-    use crate::types::{Node, Relationship};
+    use crate::types::INLINE_STRING_SIZE;
+    use crate::types::{BlockType, DeleteMode, GrowthPolicy, IndexKind, NodeBlock};
+    use crate::types::{Entity, Node, PropertyValue, Relationship};
+    use crate::types::{BLOCK_SIZE, HEADER_SIZE};
+    use crate::wal::{append_record, wal_path, WalOp, WalRecord, WalWrite};
+    use std::os::unix::fs::FileExt;
+    use std::path::{Path, PathBuf};
+
+    // unique database path per test (tests run in parallel), the file and its log are removed on drop
+    struct TempDb(PathBuf);
 
-    // default test to test if tests are working :)
-    // #[test]
-    // fn test_it_works() {
-    //     let result = 2 + 2;
-    //     assert_eq!(result, 4);
-    // }
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let _ = std::fs::remove_file(wal_path(&self.0));
+        }
+    }
+
+    impl std::ops::Deref for TempDb {
+        type Target = PathBuf;
+
+        fn deref(&self) -> &PathBuf {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDb {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    fn temp_db_path(name: &str) -> TempDb {
+        TempDb(std::env::temp_dir().join(format!("gdb-rust-{}-{}.db", name, std::process::id())))
+    }
 
     #[test]
-    async fn format_test() {
-        let result = format_disk(10);
+    fn format_test() {
+        let path = temp_db_path("format");
+        let result = Database::create(&path, 10);
         assert!(result.is_ok());
     }
 
-    // #[test]
-    // fn test_relationships() {
-    //     let result = disk::test_relationships();
-    //     assert_eq!(result, ());
-    // }
+    #[test]
+    fn test_format_existing_database() {
+        // SETUP
+        let path = temp_db_path("reformat");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+
+        // TEST
+        db.format_disk(3).unwrap();
+        let header = db.read_header().unwrap();
+        assert_eq!(header.total_blocks, 3);
+        assert_eq!(
+            std::fs::metadata(&*path).unwrap().len(),
+            HEADER_SIZE + 4 * BLOCK_SIZE
+        );
+        assert!(db.get_node_address_from_name("node1").is_err());
+        drop(db);
+
+        let db = Database::open(&path).unwrap();
+        assert_eq!(
+            db.read_header().unwrap().db_size,
+            HEADER_SIZE + 4 * BLOCK_SIZE
+        );
+    }
 
     #[test]
-    async fn test_print_all_blocks() {
+    fn test_print_all_blocks() {
         // SETUP
-        let result_format = format_disk(10);
+        let path = temp_db_path("print_all_blocks");
+        let result_format = Database::create(&path, 10);
         assert!(result_format.is_ok());
-        test_nodes();
-        let rlt_result = test_relationships();
+        let mut db = result_format.unwrap();
+        test_nodes(&mut db).unwrap();
+        let rlt_result = test_relationships(&mut db);
         assert!(rlt_result.is_ok());
 
         // TEST
-        let result = print_all_blocks();
+        let result = db.print_all_blocks();
         assert!(result.is_ok());
     }
 
     #[test]
-    async fn test_print_header() {
+    fn test_print_header() {
         // SETUP
-        let result = format_disk(10);
+        let path = temp_db_path("print_header");
+        let result = Database::create(&path, 10);
         assert!(result.is_ok());
 
         // TEST
-        let result = print_header();
+        let result = result.unwrap().print_header();
         assert!(result.is_ok());
     }
 
     #[test]
-    async fn test_node_creation() {
+    fn test_node_creation() {
         // SETUP
-        let result = format_disk(10);
+        let path = temp_db_path("node_creation");
+        let result = Database::create(&path, 10);
         assert!(result.is_ok());
+        let mut db = result.unwrap();
 
         let test_node = Node {
             id: 0,
-            name: str_conversion::str_to_fixed_chars("test"),
+            name: "test".to_string(),
             rlt_head: 0,
             attr_head: 0,
+            label_head: 0,
+            in_head: 0,
         };
 
         // TEST
-        let result = create_node(test_node);
-        assert!(result.is_ok());
+        let result = db.create_node(test_node);
+        assert_eq!(result.unwrap(), 1); // id 0 asks the database for one
     }
 
     #[test]
-    async fn test_relationship_creation() {
+    fn test_relationship_creation() {
         // SETUP
-        let result = format_disk(10);
+        let path = temp_db_path("relationship_creation");
+        let result = Database::create(&path, 10);
         assert!(result.is_ok());
+        let mut db = result.unwrap();
+        test_nodes(&mut db).unwrap(); // empty blocks no longer pass for node 0
 
         let test_relationship = Relationship {
-            node_from: 0,
-            node_to: 1,
+            node_from: 1,
+            node_to: 2,
             rlt_next: 0,
             attr_head: 0,
+            rlt_type: 0,
+            in_next: 0,
+            rlt_prev: 0,
+            in_prev: 0,
         };
 
         // TEST
-        let result = create_relationship(test_relationship);
+        let result = db.create_relationship(test_relationship, "LINKS");
         assert!(result.is_ok());
     }
-}
 
-pub fn test_nodes() -> () {
-    use crate::str_conversion;
+    #[test]
+    fn test_open_existing() {
+        // SETUP
+        let path = temp_db_path("open_existing");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+        drop(db);
 
-    // define test nodes
-    let node1 = Node {
-        id: 1,
-        name: str_conversion::str_to_fixed_chars("node1"),
-        rlt_head: 0,
-        attr_head: 0,
-    };
+        // TEST
+        let db = Database::open(&path).unwrap();
+        let node = db.get_node_from_id(2).unwrap();
+        assert_eq!(node.name, "node2");
+    }
 
-    let node2 = Node {
-        id: 2,
-        name: str_conversion::str_to_fixed_chars("node2"),
-        rlt_head: 0,
-        attr_head: 0,
-    };
+    #[test]
+    fn test_independent_databases() {
+        // SETUP
+        let path1 = temp_db_path("independent_1");
+        let path2 = temp_db_path("independent_2");
+        let mut db1 = Database::create(&path1, 10).unwrap();
+        let mut db2 = Database::create(&path2, 10).unwrap();
 
-    let node3 = Node {
-        id: 3,
-        name: str_conversion::str_to_fixed_chars("node3"),
-        rlt_head: 0,
-        attr_head: 0,
-    };
+        // TEST
+        test_nodes(&mut db1).unwrap();
+        assert!(db1.get_node_address_from_name("node1").is_ok());
+        assert!(db2.get_node_address_from_name("node1").is_err());
 
-    let a = create_node(node1);
-    let b = create_node(node2);
-    let c = create_node(node3);
+        let node = Node {
+            id: 7,
+            name: "other".to_string(),
+            rlt_head: 0,
+            attr_head: 0,
+            label_head: 0,
+            in_head: 0,
+        };
+        assert!(db2.create_node(node).is_ok());
+        assert!(db1.get_node_address_from_name("other").is_err());
+    }
 
-    // println!("1: {:?}", a);
-    // println!("2: {:?}", b);
-    // println!("3: {:?}", c);
-}
+    #[test]
+    fn test_missing_node_error() {
+        // SETUP
+        let path = temp_db_path("missing_node");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
 
-pub fn test_relationships() -> std::io::Result<()> {
-    let rlt1 = Relationship {
-        node_from: 1,
-        node_to: 2,
-        rlt_next: 0,
-        attr_head: 0,
-    };
+        // TEST
+        match db.get_node_address_from_name("missing") {
+            Err(GdbError::NotFound { kind, key }) => {
+                assert_eq!(kind, RecordKind::Node);
+                assert_eq!(key, "missing");
+            }
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
 
-    let rlt2 = Relationship {
-        node_from: 2,
-        node_to: 3,
-        rlt_next: 0,
-        attr_head: 0,
-    };
+    #[test]
+    fn test_corrupt_block_error() {
+        // SETUP
+        let path = temp_db_path("corrupt_block");
+        let db = Database::create(&path, 10).unwrap();
+        let offset = HEADER_SIZE + BLOCK_SIZE;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .write_all_at(&[0xff; 4], offset) // invalid block type
+            .unwrap();
 
-    let rlt3 = Relationship {
-        node_from: 3,
-        node_to: 1,
-        rlt_next: 0,
-        attr_head: 0,
-    };
+        // TEST
+        match db.get_block(offset) {
+            Err(GdbError::Corrupt { offset: at }) => assert_eq!(at, offset),
+            other => panic!("expected Corrupt, got {:?}", other),
+        }
+    }
 
-    println!("{:?}", rlt1);
-    println!("{:?}", rlt2);
-    println!("{:?}", rlt3);
+    #[test]
+    fn test_checksum_detects_damaged_blocks() {
+        // SETUP
+        let path = temp_db_path("checksum");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+        test_relationships(&mut db).unwrap();
+        let node_offset = db.get_node_address_from_id(1).unwrap();
+        let relationship_offset = db.get_node(node_offset).unwrap().rlt_head;
+        drop(db);
 
-    create_relationship(rlt1)?;
-    create_relationship(rlt2)?;
-    create_relationship(rlt3)?;
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(b"X", node_offset + 20).unwrap(); // still decodes as a valid name
+        file.write_all_at(&[0; 48], relationship_offset + 48)
+            .unwrap(); // torn write, second half never reached the disk
 
-    println!("RltS creation successful...");
+        // TEST
+        let db = Database::open(&path).unwrap();
+        for result in [
+            db.get_node(node_offset).map(|_| ()),
+            db.get_block(node_offset).map(|_| ()),
+        ] {
+            match result {
+                Err(GdbError::Corrupt { offset }) => assert_eq!(offset, node_offset),
+                other => panic!("expected Corrupt, got {:?}", other),
+            }
+        }
+        match db.get_relationship(relationship_offset) {
+            Err(GdbError::Corrupt { offset }) => assert_eq!(offset, relationship_offset),
+            other => panic!("expected Corrupt, got {:?}", other),
+        }
+        assert!(db.get_node_from_id(2).is_ok());
+        drop(db);
 
-    Ok(())
+        file.write_all_at(&[0xff], 20).unwrap(); // inside total_blocks
+        assert!(matches!(
+            Database::open(&path),
+            Err(GdbError::Corrupt { offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_free_list_reuse() {
+        // SETUP
+        let path = temp_db_path("free_list_reuse");
+        let mut db = Database::create(&path, 6).unwrap();
+        test_nodes(&mut db).unwrap(); // three nodes and their id, name and text index leaves
+        assert_eq!(db.read_header().unwrap().first_empty, 0);
+
+        // TEST
+        let offset = db.get_node_address_from_name("node2").unwrap();
+        db.delete_node_name("node2".to_string(), DeleteMode::Detach)
+            .unwrap();
+        assert_eq!(db.read_header().unwrap().first_empty, offset);
+
+        let node = Node {
+            id: 4,
+            name: "node4".to_string(),
+            rlt_head: 0,
+            attr_head: 0,
+            label_head: 0,
+            in_head: 0,
+        };
+        db.create_node(node).unwrap();
+        assert_eq!(db.get_node_address_from_name("node4").unwrap(), offset);
+        assert_eq!(db.read_header().unwrap().first_empty, 0);
+    }
+
+    #[test]
+    fn test_automatic_growth() {
+        // SETUP
+        let path = temp_db_path("automatic_growth");
+        let mut db = Database::create(&path, 1).unwrap();
+
+        // TEST
+        test_nodes(&mut db).unwrap(); // 3 nodes into a 1 block file
+        for name in ["node1", "node2", "node3"] {
+            assert!(db.get_node_address_from_name(name).is_ok());
+        }
+
+        let header = db.read_header().unwrap();
+        assert_eq!(header.total_blocks, 11); // grew once by the default Fixed(10)
+        assert_eq!(header.db_size, std::fs::metadata(&path).unwrap().len());
+
+        let final_offset = HEADER_SIZE + header.total_blocks * BLOCK_SIZE;
+        assert_eq!(
+            db.get_block(final_offset).unwrap().block_type,
+            BlockType::Final
+        );
+    }
+
+    #[test]
+    fn test_growth_policies() {
+        assert_eq!(GrowthPolicy::Fixed(5).blocks_to_add(100), 5);
+        assert_eq!(GrowthPolicy::Double.blocks_to_add(100), 100);
+        assert_eq!(GrowthPolicy::Double.blocks_to_add(0), 1);
+        assert_eq!(GrowthPolicy::Percentage(25).blocks_to_add(10), 3);
+
+        let path = temp_db_path("growth_policies");
+        let mut db = Database::create(&path, 2).unwrap();
+        db.set_growth_policy(GrowthPolicy::Double);
+        test_nodes(&mut db).unwrap(); // three nodes and two index leaves
+        assert_eq!(db.read_header().unwrap().total_blocks, 8);
+    }
+
+    #[test]
+    fn test_wal_replay_on_open() {
+        // SETUP
+        let path = temp_db_path("wal_replay");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+
+        // log a node creation but crash before applying it
+        let mut header = db.read_header().unwrap();
+        let offset = header.first_empty;
+        let next_empty = db
+            .read_block::<crate::types::EmptyBlock>(offset)
+            .unwrap()
+            .next_empty;
+        header.first_empty = next_empty;
+
+        let name = db.store_string("logged").unwrap();
+        let node_block = NodeBlock {
+            block_type: BlockType::Node,
+            id: 9,
+            name,
+            rlt_head: 0,
+            attr_head: 0,
+            label_head: 0,
+            in_head: 0,
+        };
+        let block_bytes = encode_slot(&node_block, BLOCK_SIZE).unwrap();
+
+        let record = WalRecord {
+            op: WalOp::CreateNode,
+            writes: vec![
+                WalWrite {
+                    offset: 0,
+                    data: encode_slot(&header, HEADER_SIZE).unwrap(),
+                },
+                WalWrite {
+                    offset,
+                    data: block_bytes,
+                },
+            ],
+        };
+        append_record(&db.wal, &record).unwrap();
+        drop(db);
+
+        // TEST
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.get_node(offset).unwrap().name, "logged");
+        assert_eq!(db.read_header().unwrap().first_empty, next_empty);
+        assert_eq!(std::fs::metadata(wal_path(&path)).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_wal_pending_record_replayed() {
+        // SETUP
+        let path = temp_db_path("wal_pending");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+
+        // a commit that logged its record but failed to apply it
+        let offset = db.get_node_address_from_name("node1").unwrap();
+        let mut node_block = db.read_block::<NodeBlock>(offset).unwrap();
+        node_block.name = db.store_string("logged").unwrap();
+
+        let record = WalRecord {
+            op: WalOp::UpdateNode,
+            writes: vec![WalWrite {
+                offset,
+                data: encode_slot(&node_block, BLOCK_SIZE).unwrap(),
+            }],
+        };
+        append_record(&db.wal, &record).unwrap();
+        db.wal_pending = true;
+
+        // TEST
+        db.add_label(1, "Person").unwrap(); // the next operation applies it before committing its own
+        assert_eq!(db.get_node(offset).unwrap().name, "logged");
+        assert_eq!(std::fs::metadata(wal_path(&path)).unwrap().len(), 0);
+        assert!(!db.wal_pending);
+        drop(db);
+
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.get_node(offset).unwrap().name, "logged");
+        assert_eq!(db.node_labels(1).unwrap(), vec!["Person".to_string()]);
+    }
+
+    #[test]
+    fn test_wal_torn_record_ignored() {
+        // SETUP
+        let path = temp_db_path("wal_torn");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+        let first_empty = db.read_header().unwrap().first_empty;
+        drop(db);
+
+        std::fs::write(wal_path(&path), [200, 0, 0, 0, 1, 2, 3, 4, 5]).unwrap(); // length says 200, payload cut short
+
+        // TEST
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.read_header().unwrap().first_empty, first_empty);
+        assert!(db.get_node_address_from_name("node3").is_ok());
+        assert_eq!(std::fs::metadata(wal_path(&path)).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_failed_operation_discarded() {
+        // SETUP
+        let path = temp_db_path("failed_operation");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+        let first_empty = db.read_header().unwrap().first_empty;
+
+        // TEST
+        let result: crate::error::Result<()> = db.logged_op(WalOp::CreateNode, |db| {
+            let offset = db.allocate_block()?;
+            db.free_block(db.get_node_address_from_name("node1")?)?;
+            assert_ne!(db.read_header()?.first_empty, first_empty); // visible inside the operation
+            Err(GdbError::not_found(RecordKind::Node, offset))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(db.read_header().unwrap().first_empty, first_empty);
+        assert!(db.get_node_address_from_name("node1").is_ok());
+    }
+
+    #[test]
+    fn test_transaction_commit() {
+        // SETUP
+        let path = temp_db_path("transaction_commit");
+        let mut db = Database::create(&path, 10).unwrap();
+
+        // TEST
+        let mut txn = db.begin().unwrap();
+        test_nodes(&mut txn).unwrap();
+
+        let rlt_offset = txn
+            .create_relationship(
+                Relationship {
+                    node_from: 1,
+                    node_to: 2,
+                    rlt_next: 0,
+                    attr_head: 0,
+                    rlt_type: 0,
+                    in_next: 0,
+                    rlt_prev: 0,
+                    in_prev: 0,
+                },
+                "LINKS",
+            )
+            .unwrap();
+
+        txn.set_property(
+            Entity::Node(1),
+            "colour",
+            PropertyValue::String("red".to_string()),
+        )
+        .unwrap();
+        let attr_offset = txn.get_node_from_id(1).unwrap().attr_head;
+
+        // nothing reaches the file before commit
+        assert!(Database::open(&path)
+            .unwrap()
+            .get_node_address_from_name("node1")
+            .is_err());
+        assert!(txn.begin().is_err());
+
+        txn.commit().unwrap();
+
+        let db = Database::open(&path).unwrap();
+        let node1 = db.get_node_from_id(1).unwrap();
+        assert_eq!(node1.rlt_head, rlt_offset);
+        assert_eq!(node1.attr_head, attr_offset);
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        // SETUP
+        let path = temp_db_path("transaction_rollback");
+        let mut db = Database::create(&path, 10).unwrap();
+        let first_empty = db.read_header().unwrap().first_empty;
+
+        // TEST
+        let mut txn = db.begin().unwrap();
+        test_nodes(&mut txn).unwrap();
+        assert!(txn.get_node_address_from_name("node1").is_ok());
+        assert!(matches!(
+            txn.format_disk(10), // truncating the file can't be rolled back
+            Err(GdbError::TransactionInProgress)
+        ));
+        txn.rollback();
+
+        assert!(db.get_node_address_from_name("node1").is_err());
+        assert_eq!(db.read_header().unwrap().first_empty, first_empty);
+
+        // dropping without commit rolls back too
+        {
+            let mut txn = db.begin().unwrap();
+            test_nodes(&mut txn).unwrap();
+        }
+        assert!(db.get_node_address_from_name("node1").is_err());
+        assert!(!db.in_transaction());
+    }
+
+    #[test]
+    fn test_transaction_failed_operation() {
+        // SETUP
+        let path = temp_db_path("transaction_failed_op");
+        let mut db = Database::create(&path, 10).unwrap();
+
+        // TEST
+        let mut txn = db.begin().unwrap();
+        test_nodes(&mut txn).unwrap();
+        let first_empty = txn.read_header().unwrap().first_empty;
+
+        let result: crate::error::Result<()> = txn.logged_op(WalOp::DeleteNode, |db| {
+            db.free_block(db.get_node_address_from_name("node1")?)?;
+            Err(GdbError::not_found(RecordKind::Node, 99))
+        });
+        assert!(result.is_err());
+
+        // only the failed operation was undone
+        assert!(txn.get_node_address_from_name("node1").is_ok());
+        assert_eq!(txn.read_header().unwrap().first_empty, first_empty);
+        txn.commit().unwrap();
+
+        assert!(db.get_node_address_from_name("node3").is_ok());
+    }
+
+    #[test]
+    fn test_header_format_fields() {
+        // SETUP
+        let path = temp_db_path("header_format");
+        let db = Database::create(&path, 10).unwrap();
+
+        // TEST
+        let header = db.read_header().unwrap();
+        assert_eq!(header.magic, crate::types::MAGIC);
+        assert_eq!(header.format_version, crate::types::FORMAT_VERSION);
+        assert_eq!(header.block_size as u64, BLOCK_SIZE);
+        assert_eq!(header.feature_flags, 0);
+    }
+
+    #[test]
+    fn test_open_rejects_invalid_files() {
+        // SETUP
+        let garbage = temp_db_path("open_garbage");
+        std::fs::write(&garbage, [7u8; 512]).unwrap();
+
+        let old_version = temp_db_path("open_old_version");
+        let mut db = Database::create(&old_version, 10).unwrap();
+        let mut header = db.read_header().unwrap();
+        header.format_version = 1;
+        db.logged_op(WalOp::UpdateHeader, |db| db.write_header(&header))
+            .unwrap();
+        drop(db);
+
+        // TEST
+        assert!(matches!(
+            Database::open(&garbage),
+            Err(GdbError::NotADatabase)
+        ));
+        assert!(!crate::wal::wal_path(&garbage).exists()); // no log is created next to it
+        assert!(matches!(
+            Database::open(&old_version),
+            Err(GdbError::UnsupportedVersion {
+                found: 1,
+                supported: crate::types::FORMAT_VERSION
+            })
+        ));
+    }
+
+    //  Write a version 1 to 4 file holding blocks, followed by the final block.
+    fn write_legacy_file(path: &PathBuf, version: u32, blocks: &[Vec<u8>]) {
+        use crate::migrate::{v1, v2};
+
+        let total_blocks = blocks.len() as u64;
+        let (header, header_size) = match version {
+            1 => {
+                let header = v1::Header {
+                    total_blocks,
+                    first_empty: 0,
+                    db_size: v1::HEADER_SIZE + (total_blocks + 1) * v1::BLOCK_SIZE,
+                };
+                (bincode::serialize(&header).unwrap(), v1::HEADER_SIZE)
+            }
+            _ => {
+                let header = v2::Header {
+                    magic: crate::types::MAGIC,
+                    format_version: version,
+                    block_size: v1::BLOCK_SIZE as u32,
+                    feature_flags: 0,
+                    total_blocks,
+                    first_empty: 0,
+                    db_size: v2::HEADER_SIZE + (total_blocks + 1) * v1::BLOCK_SIZE,
+                };
+                (bincode::serialize(&header).unwrap(), v2::HEADER_SIZE)
+            }
+        };
+
+        // version 3 ends the header and every block with a CRC32
+        let seal = |data: &[u8], len: u64| {
+            let mut data = data.to_vec();
+            if version < 3 {
+                data.resize(len as usize, 0);
+                return data;
+            }
+            data.resize(len as usize - 4, 0);
+            let checksum = crc32fast::hash(&data);
+            data.extend_from_slice(&checksum.to_le_bytes());
+            data
+        };
+
+        let file = std::fs::File::create(path).unwrap();
+        file.write_all_at(&seal(&header, header_size), 0).unwrap();
+
+        let final_block = bincode::serialize(&v1::BlockType::Final).unwrap();
+        for (i, block) in blocks.iter().chain([&final_block]).enumerate() {
+            let data = seal(block, v1::BLOCK_SIZE);
+            file.write_all_at(&data, header_size + i as u64 * v1::BLOCK_SIZE)
+                .unwrap();
+        }
+    }
+
+    //  Migrate a small legacy graph and check ids, chains and the free list survived.
+    fn check_legacy_migration(version: u32) {
+        use crate::migrate::{self, v1, v2, v4, v6};
+        use crate::types::StoredString;
+
+        // SETUP
+        let header_size = if version == 1 {
+            v1::HEADER_SIZE
+        } else {
+            v2::HEADER_SIZE
+        };
+        let v1_offset = |slot: u64| header_size + slot * v1::BLOCK_SIZE;
+        let offset = |slot: u64| HEADER_SIZE + slot * BLOCK_SIZE;
+
+        let node = |id, name: &str, rlt_head, attr_head| {
+            if version >= 4 {
+                return bincode::serialize(&v6::NodeBlock {
+                    block_type: BlockType::Node,
+                    id,
+                    name: StoredString {
+                        inline: name.as_bytes().to_vec(),
+                        overflow: 0,
+                    },
+                    rlt_head,
+                    attr_head,
+                })
+                .unwrap();
+            }
+            bincode::serialize(&v1::NodeBlock {
+                block_type: v1::BlockType::Node,
+                id,
+                name: str_conversion::str_to_fixed_chars(name),
+                rlt_head,
+                attr_head,
+            })
+            .unwrap()
+        };
+        let relationship = |node_from, node_to, rlt_next| {
+            bincode::serialize(&v1::RelationshipBlock {
+                block_type: v1::BlockType::Relationship,
+                node_from,
+                node_to,
+                rlt_next,
+                attr_head: 0,
+            })
+            .unwrap()
+        };
+        let attribute = |value: &str, attr_next| {
+            if version >= 4 {
+                return bincode::serialize(&v4::AttributeBlock {
+                    block_type: BlockType::Attribute,
+                    value: StoredString {
+                        inline: value.as_bytes().to_vec(),
+                        overflow: 0,
+                    },
+                    attr_next,
+                })
+                .unwrap();
+            }
+            bincode::serialize(&v1::AttributeBlock {
+                block_type: v1::BlockType::Attribute,
+                value: str_conversion::str_to_fixed_chars(value),
+                attr_next,
+            })
+            .unwrap()
+        };
+        let empty = bincode::serialize(&v1::BlockType::Empty).unwrap();
+
+        let input = temp_db_path(&format!("migrate_v{}_in", version));
+        let output = temp_db_path(&format!("migrate_v{}_out", version));
+        write_legacy_file(
+            &input,
+            version,
+            &[
+                node(7, "alice", v1_offset(2), v1_offset(4)),
+                node(9, "bob", 0, 0),
+                relationship(7, 9, v1_offset(3)),
+                relationship(7, 7, 0),
+                attribute("red", v1_offset(5)),
+                attribute("big", 0),
+                empty.clone(),
+                empty.clone(),
+                empty,
+            ],
+        );
+
+        // TEST
+        assert_eq!(migrate::detect_version(&input).unwrap(), version);
+        assert!(Database::open(&input).is_err());
+
+        let report = migrate::migrate(&input, &output).unwrap();
+        assert_eq!(report.from_version, version);
+        assert_eq!(report.to_version, crate::types::FORMAT_VERSION);
+        assert_eq!(
+            (report.nodes, report.relationships, report.attributes),
+            (2, 2, 2)
+        );
+
+        let mut db = Database::open(&output).unwrap();
+        let alice = db.get_node_from_id(7).unwrap();
+        assert_eq!(alice.name, "alice");
+        assert_eq!(db.get_node_from_id(9).unwrap().rlt_head, 0);
+
+        // relationship chain kept its order
+        assert_eq!(alice.rlt_head, offset(2));
+        let first = db.get_relationship(alice.rlt_head).unwrap();
+        assert_eq!((first.node_from, first.node_to), (7, 9));
+        assert_eq!(first.rlt_next, offset(3));
+        let second = db.get_relationship(first.rlt_next).unwrap();
+        assert_eq!(
+            (second.node_from, second.node_to, second.rlt_next),
+            (7, 7, 0)
+        );
+        assert_eq!(second.rlt_prev, offset(2));
+
+        // incoming chains are built from node_to
+        let bob = db.get_node_from_id(9).unwrap();
+        assert_eq!(db.relationships_to(&bob, None).unwrap().len(), 1);
+        assert_eq!(db.relationships_to(&alice, None).unwrap()[0].0, offset(3));
+
+        // attribute chain kept its order, untyped values became flags
+        assert_eq!(alice.attr_head, offset(4));
+        let red = db.read_attribute(alice.attr_head).unwrap();
+        assert_eq!(red.key, "red");
+        let big = db.read_attribute(red.attr_next).unwrap();
+        assert_eq!(big.key, "big");
+        assert_eq!(big.attr_next, 0);
+        assert_eq!(
+            db.get_property(Entity::Node(7), "big").unwrap(),
+            PropertyValue::Bool(true)
+        );
+
+        // the empty slots were on the free list of the new file, the rebuilt indexes took all three
+        assert_eq!(db.get_node_address_from_id(9).unwrap(), offset(1));
+        assert_eq!(db.get_node_address_from_name("bob").unwrap(), offset(1));
+        for slot in [6, 7, 8] {
+            assert_eq!(
+                db.get_block(offset(slot)).unwrap().block_type,
+                BlockType::Index
+            );
+        }
+        assert_eq!(db.read_header().unwrap().first_empty, 0);
+        let carol = db
+            .create_node(Node {
+                id: 0,
+                name: "carol".to_string(),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            })
+            .unwrap();
+        assert_eq!(carol, 10); // counter starts after the highest migrated id
+        assert_eq!(db.get_node(offset(9)).unwrap().id, 10); // the file grew for it
+    }
+
+    #[test]
+    fn test_migrate_v1_file() {
+        check_legacy_migration(1);
+    }
+
+    #[test]
+    fn test_migrate_v2_file() {
+        check_legacy_migration(2);
+    }
+
+    #[test]
+    fn test_migrate_v3_file() {
+        check_legacy_migration(3);
+    }
+
+    #[test]
+    fn test_migrate_v4_file() {
+        check_legacy_migration(4);
+    }
+
+    //  Rewrite a current file the way version wrote it: fields added later zeroed, blocks it had no use for freed.
+    fn downgrade(path: &Path, version: u32) {
+        use crate::disk::Slot;
+
+        let mut db = Database::open(path).unwrap();
+        db.logged_op(WalOp::Migrate, |db| {
+            for i in 0..db.read_header()?.total_blocks {
+                let offset = HEADER_SIZE + i * BLOCK_SIZE;
+                let slot = match db.read_slot(offset)? {
+                    Slot::Index(_) if version < 10 => Slot::Free,
+                    Slot::Dictionary(_) if version < 6 => Slot::Free,
+                    Slot::Label(_) if version < 7 => Slot::Free,
+                    Slot::Label(mut label) => {
+                        if version < 17 {
+                            label.member_prev = 0;
+                        }
+                        Slot::Label(label)
+                    }
+                    Slot::Dictionary(mut entry) => {
+                        if version < 7 {
+                            entry.head = 0;
+                        }
+                        Slot::Dictionary(entry)
+                    }
+                    Slot::Node(mut node) => {
+                        if version < 7 {
+                            node.label_head = 0;
+                        }
+                        if version < 8 {
+                            node.in_head = 0;
+                        }
+                        Slot::Node(node)
+                    }
+                    Slot::Relationship(mut relationship) => {
+                        if version < 6 {
+                            relationship.rlt_type = 0;
+                        }
+                        if version < 8 {
+                            relationship.in_next = 0;
+                        }
+                        if version < 9 {
+                            relationship.rlt_prev = 0;
+                            relationship.in_prev = 0;
+                        }
+                        Slot::Relationship(relationship)
+                    }
+                    _ => continue,
+                };
+                db.write_slot(offset, &slot)?;
+            }
+
+            let mut header = db.read_header()?;
+            header.format_version = version;
+            if version < 6 {
+                header.dictionary_head = 0;
+            }
+            if version < 10 {
+                header.id_index = 0;
+            }
+            if version < 11 {
+                header.next_node_id = 0;
+            }
+            if version < 12 {
+                header.name_index = 0;
+            }
+            if version < 14 {
+                header.text_index = 0;
+                header.text_documents = 0;
+                header.text_terms = 0;
+            }
+            if version < 18 {
+                header.schema_generation = 0;
+            }
+            db.write_header(&header)
+        })
+        .unwrap();
+    }
+
+    //  Migrate a graph using everything version could store and check all of it survived.
+    fn check_framed_migration(version: u32) {
+        use crate::migrate;
+        use crate::types::Constraint;
+
+        // SETUP
+        let input = temp_db_path(&format!("migrate_v{}_in", version));
+        let output = temp_db_path(&format!("migrate_v{}_out", version));
+        let bio = "writes the payment service and most of its tests";
+
+        let mut db = Database::create(&input, 20).unwrap();
+        for (id, name) in [(7, "alice"), (9, "bob")] {
+            db.create_node(Node {
+                id,
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let link = |db: &mut Database, node_from, node_to| {
+            db.create_relationship(
+                Relationship {
+                    node_from,
+                    node_to,
+                    ..Default::default()
+                },
+                "KNOWS",
+            )
+            .unwrap()
+        };
+        let first = link(&mut db, 7, 9);
+        let second = link(&mut db, 7, 7);
+        db.set_property(Entity::Node(7), "age", PropertyValue::Int(42))
+            .unwrap();
+        db.set_property(
+            Entity::Node(7),
+            "bio",
+            PropertyValue::String(bio.to_string()),
+        )
+        .unwrap();
+        if version >= 7 {
+            db.add_label(9, "Person").unwrap();
+            db.add_label(7, "Person").unwrap();
+        }
+        if version >= 13 {
+            db.create_property_index("age", IndexKind::Ordered).unwrap();
+        }
+        if version >= 14 {
+            db.create_text_index("bio").unwrap();
+        }
+        if version >= 15 {
+            db.create_constraint(Constraint::UniqueName {
+                label: "Person".to_string(),
+            })
+            .unwrap();
+        }
+        drop(db);
+        downgrade(&input, version);
+
+        // TEST
+        assert_eq!(migrate::detect_version(&input).unwrap(), version);
+        assert!(Database::open(&input).is_err());
+
+        let report = migrate::migrate(&input, &output).unwrap();
+        assert_eq!(report.from_version, version);
+        assert_eq!(
+            (report.nodes, report.relationships, report.attributes),
+            (2, 2, 2)
+        );
+        assert_eq!(report.renumbered, 0);
+
+        let mut db = Database::open(&output).unwrap();
+        assert!(db.check().unwrap().is_clean());
+        let alice = db.get_node_from_id(7).unwrap();
+        let bob = db.get_node_from_id(9).unwrap();
+        assert_eq!((alice.name.as_str(), bob.name.as_str()), ("alice", "bob"));
+
+        // both chains, in order, with their back links
+        let outgoing: Vec<u64> = db
+            .relationships_from(&alice, None)
+            .unwrap()
+            .into_iter()
+            .map(|(offset, _)| offset)
+            .collect();
+        assert_eq!(outgoing, vec![first, second]);
+        assert_eq!(db.get_relationship(second).unwrap().rlt_prev, first);
+        assert_eq!(db.relationships_to(&bob, None).unwrap()[0].0, first);
+        assert_eq!(db.relationships_to(&alice, None).unwrap()[0].0, second);
+        let typed = db.relationships_from(&alice, Some("KNOWS")).unwrap().len();
+        assert_eq!(typed, if version >= 6 { 2 } else { 0 });
+
+        assert_eq!(
+            db.get_property(Entity::Node(7), "bio").unwrap(),
+            PropertyValue::String(bio.to_string())
+        );
+        assert_eq!(
+            db.get_node_address_from_name("bob").unwrap(),
+            db.get_node_address(&bob).unwrap()
+        );
+        if version >= 7 {
+            assert_eq!(
+                db.nodes_with_label("Person").unwrap(),
+                vec![alice.clone(), bob]
+            );
+        }
+        if version >= 13 {
+            let found = db
+                .nodes_between("age", &PropertyValue::Int(40), &PropertyValue::Int(50))
+                .unwrap();
+            assert_eq!(found, vec![alice]);
+        }
+        if version >= 14 {
+            assert_eq!(db.search("payment", 5).unwrap()[0].0.id, 7);
+        }
+        if version >= 15 {
+            let twin = db
+                .create_node(Node {
+                    name: "bob".to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            assert!(matches!(
+                db.add_label(twin, "Person"),
+                Err(GdbError::ConstraintViolation(_))
+            ));
+        }
+        assert_eq!(
+            db.read_header().unwrap().next_node_id,
+            if version >= 15 { 11 } else { 10 }
+        );
+    }
+
+    #[test]
+    fn test_migrate_framed_files() {
+        // one per layout change: typed attributes (5), relationship types (6), labels (7),
+        // incoming chains (8), back links (9), the indexes, constraints and counters (10-16)
+        // label back links (17) and the schema generation (18)
+        for version in 5..=17 {
+            check_framed_migration(version);
+        }
+    }
+
+    #[test]
+    fn test_migrate_renumbers_bad_ids() {
+        use crate::migrate::{self, v1};
+
+        // SETUP
+        let v1_offset = |slot: u64| v1::HEADER_SIZE + slot * v1::BLOCK_SIZE;
+        let node = |id, name: &str, rlt_head| {
+            bincode::serialize(&v1::NodeBlock {
+                block_type: v1::BlockType::Node,
+                id,
+                name: str_conversion::str_to_fixed_chars(name),
+                rlt_head,
+                attr_head: 0,
+            })
+            .unwrap()
+        };
+
+        // ids could be chosen freely before the header counter, alias took alice's
+        let input = temp_db_path("migrate_bad_ids_in");
+        let output = temp_db_path("migrate_bad_ids_out");
+        write_legacy_file(
+            &input,
+            1,
+            &[
+                node(0, "zero", 0),
+                node(7, "alice", 0),
+                node(7, "alias", v1_offset(3)),
+                bincode::serialize(&v1::RelationshipBlock {
+                    block_type: v1::BlockType::Relationship,
+                    node_from: 7,
+                    node_to: 7,
+                    rlt_next: 0,
+                    attr_head: 0,
+                })
+                .unwrap(),
+            ],
+        );
+
+        // TEST
+        let report = migrate::migrate(&input, &output).unwrap();
+        assert_eq!(report.renumbered, 2);
+
+        let db = Database::open(&output).unwrap();
+        assert_eq!(db.get_node_from_id(7).unwrap().name, "alice");
+        assert_eq!(db.get_node_from_id(8).unwrap().name, "zero");
+        let alias = db.get_node_from_id(9).unwrap();
+        assert_eq!(alias.name, "alias");
+
+        // the relationship on alias's chain left with it, its other end stays with alice
+        let (_, relationship) = db.relationships_from(&alias, None).unwrap()[0];
+        assert_eq!((relationship.node_from, relationship.node_to), (9, 7));
+        let alice = db.get_node_from_id(7).unwrap();
+        assert_eq!(db.relationships_to(&alice, None).unwrap().len(), 1);
+        assert_eq!(db.read_header().unwrap().next_node_id, 10);
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_migrate_rejects_unknown_files() {
+        use crate::migrate;
+
+        // SETUP
+        let future = temp_db_path("migrate_future");
+        let mut db = Database::create(&future, 4).unwrap();
+        let mut header = db.read_header().unwrap();
+        header.format_version = crate::types::FORMAT_VERSION + 1;
+        db.logged_op(WalOp::UpdateHeader, |db| db.write_header(&header))
+            .unwrap();
+        drop(db);
+
+        let garbage = temp_db_path("migrate_garbage");
+        std::fs::write(&garbage, [7u8; 64]).unwrap();
+
+        // TEST
+        assert!(matches!(
+            migrate::migrate(&future, temp_db_path("migrate_future_out")),
+            Err(GdbError::UnsupportedVersion { .. })
+        ));
+        assert!(matches!(
+            migrate::migrate(&garbage, temp_db_path("migrate_garbage_out")),
+            Err(GdbError::NotADatabase)
+        ));
+
+        // the output is never the input, and the input is only read
+        let current = temp_db_path("migrate_current");
+        let mut db = Database::create(&current, 4).unwrap();
+        test_nodes(&mut db).unwrap();
+        drop(db);
+        std::fs::remove_file(crate::wal::wal_path(&current)).unwrap();
+        let before = std::fs::read(&current).unwrap();
+
+        assert!(matches!(
+            migrate::migrate(&current, &current),
+            Err(GdbError::Io(_))
+        ));
+        migrate::migrate(&current, temp_db_path("migrate_current_out")).unwrap();
+        assert_eq!(std::fs::read(&current).unwrap(), before);
+        assert!(!crate::wal::wal_path(&current).exists());
+    }
+
+    #[test]
+    fn test_check_clean_database() {
+        // SETUP
+        let path = temp_db_path("check_clean");
+        let mut db = Database::create(&path, 4).unwrap();
+        test_nodes(&mut db).unwrap();
+        db.create_relationship(
+            Relationship {
+                node_from: 1,
+                node_to: 2,
+                rlt_next: 0,
+                attr_head: 0,
+                rlt_type: 0,
+                in_next: 0,
+                rlt_prev: 0,
+                in_prev: 0,
+            },
+            "LINKS",
+        )
+        .unwrap();
+        db.set_property(Entity::Node(1), "red", PropertyValue::Bool(true))
+            .unwrap();
+        db.delete_record_offset(db.get_node_address_from_id(3).unwrap())
+            .unwrap();
+
+        // TEST
+        let report = db.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.violations);
+        assert!(!db.repair().unwrap().repaired);
+    }
+
+    #[test]
+    fn test_check_reports_and_repairs() {
+        use crate::check::{Field, Violation};
+        use crate::types::RelationshipBlock;
+
+        // SETUP
+        let path = temp_db_path("check_repair");
+        let mut db = Database::create(&path, 13).unwrap();
+        test_nodes(&mut db).unwrap(); // ids 1, 2, 3 and the index leaves in the first six blocks
+        let node_offset = |i: u64| HEADER_SIZE + i * BLOCK_SIZE;
+        let node1 = db.get_node_address_from_id(1).unwrap();
+        let node3 = db.get_node_address_from_id(3).unwrap();
+
+        let link = |db: &mut Database, node_from, node_to| {
+            db.create_relationship(
+                Relationship {
+                    node_from,
+                    node_to,
+                    rlt_next: 0,
+                    attr_head: 0,
+                    rlt_type: 0,
+                    in_next: 0,
+                    rlt_prev: 0,
+                    in_prev: 0,
+                },
+                "LINKS",
+            )
+            .unwrap()
+        };
+        let r1 = link(&mut db, 1, 2);
+        let r2 = link(&mut db, 1, 3);
+        let r3 = link(&mut db, 1, 3);
+        let r4 = link(&mut db, 2, 3);
+
+        db.logged_op(WalOp::UpdateRelationship, |db| {
+            // r2's endpoint goes missing
+            let mut block = db.read_block::<RelationshipBlock>(r2)?;
+            block.relationship.node_to = 99;
+            db.write_block(r2, &block)?;
+
+            // r4 loops back on itself
+            let mut block = db.read_block::<RelationshipBlock>(r4)?;
+            block.relationship.rlt_next = r4;
+            db.write_block(r4, &block)?;
+
+            // node 3's attribute list points at a relationship
+            let mut node_block = db.read_block::<NodeBlock>(node3)?;
+            node_block.attr_head = r1;
+            db.write_block(node3, &node_block)?;
+
+            // free list points at a node, header size is off
+            let mut header = db.read_header()?;
+            header.first_empty = node1;
+            header.db_size += BLOCK_SIZE;
+            db.write_header(&header)
+        })
+        .unwrap();
+        drop(db);
+
+        let corrupt_offset = node_offset(12);
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .write_all_at(&[0xff; 8], corrupt_offset + 20)
+            .unwrap();
+
+        // TEST
+        let mut db = Database::open(&path).unwrap();
+        let report = db.check().unwrap();
+        for expected in [
+            Violation::DanglingEndpoint {
+                offset: r2,
+                field: Field::NodeTo,
+                id: 99,
+            },
+            Violation::Cycle {
+                offset: r4,
+                field: Field::RltNext,
+            },
+            Violation::BadPointer {
+                offset: node3,
+                field: Field::AttrHead,
+                target: r1,
+            },
+            Violation::BadPointer {
+                offset: 0,
+                field: Field::FirstEmpty,
+                target: node1,
+            },
+            Violation::CorruptBlock {
+                offset: corrupt_offset,
+            },
+            Violation::LostEmptyBlock {
+                offset: node_offset(11), // after the nodes, the indexes, the LINKS type and r1 to r4
+            },
+        ] {
+            assert!(report.violations.contains(&expected), "{:?}", expected);
+        }
+        assert!(report.violations.iter().any(|v| matches!(
+            v,
+            Violation::HeaderMismatch {
+                field: "db_size",
+                ..
+            }
+        )));
+
+        let report = db.repair().unwrap();
+        assert!(report.repaired);
+        assert!(db.check().unwrap().is_clean());
+
+        // r2 unlinked and freed, r4's cycle and node 3's bad attribute list cut off
+        assert_eq!(db.get_relationship(r1).unwrap().rlt_next, r3);
+        assert_eq!(db.get_block(r2).unwrap().block_type, BlockType::Empty);
+        assert_eq!(db.get_relationship(r4).unwrap().rlt_next, 0);
+        assert_eq!(db.get_node(node3).unwrap().attr_head, 0);
+        assert_eq!(
+            db.get_block(corrupt_offset).unwrap().block_type,
+            BlockType::Empty
+        );
+        assert_eq!(db.read_header().unwrap().first_empty, r2);
+    }
+
+    #[test]
+    fn test_compaction() {
+        // SETUP
+        let path = temp_db_path("compaction");
+        let mut db = Database::create(&path, 20).unwrap();
+        test_nodes(&mut db).unwrap();
+
+        let link = |db: &mut Database, node_from, node_to| {
+            db.create_relationship(
+                Relationship {
+                    node_from,
+                    node_to,
+                    rlt_next: 0,
+                    attr_head: 0,
+                    rlt_type: 0,
+                    in_next: 0,
+                    rlt_prev: 0,
+                    in_prev: 0,
+                },
+                "LINKS",
+            )
+            .unwrap()
+        };
+        let r1 = link(&mut db, 1, 2);
+        link(&mut db, 3, 1);
+        link(&mut db, 3, 3);
+        for key in ["red", "big"] {
+            db.set_property(Entity::Node(3), key, PropertyValue::Bool(true))
+                .unwrap();
+        }
+
+        // punch holes in front of node 3's chains
+        db.delete_record_offset(db.get_node_address_from_id(2).unwrap())
+            .unwrap();
+        let mut r1_block = db
+            .read_block::<crate::types::RelationshipBlock>(r1)
+            .unwrap();
+        r1_block.relationship.node_to = 3;
+        db.logged_op(WalOp::UpdateRelationship, |db| {
+            db.write_block(r1, &r1_block)
+        })
+        .unwrap();
+
+        // TEST
+        assert!(matches!(
+            db.begin().unwrap().compact(),
+            Err(GdbError::TransactionInProgress)
+        ));
+
+        let report = db.compact().unwrap();
+        assert_eq!(report.blocks_before, 20);
+        assert_eq!(report.blocks_after, 11);
+        assert_eq!(report.bytes_reclaimed, 9 * BLOCK_SIZE);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            HEADER_SIZE + 12 * BLOCK_SIZE
+        );
+        drop(db);
+
+        let mut db = Database::open(&path).unwrap();
+        let report = db.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.violations);
+
+        // node 3 moved down one block, its index entry and chains followed it
+        assert_eq!(
+            db.get_node_address_from_id(3).unwrap(),
+            HEADER_SIZE + 4 * BLOCK_SIZE
+        );
+        let node3 = db.get_node_from_id(3).unwrap();
+        let first = db.get_relationship(node3.rlt_head).unwrap();
+        assert_eq!((first.node_from, first.node_to), (3, 1));
+        let second = db.get_relationship(first.rlt_next).unwrap();
+        assert_eq!(
+            (second.node_from, second.node_to, second.rlt_next),
+            (3, 3, 0)
+        );
+
+        let red = db.read_attribute(node3.attr_head).unwrap();
+        assert_eq!(red.key, "red");
+        let big = db.read_attribute(red.attr_next).unwrap();
+        assert_eq!(big.key, "big");
+
+        // file grows again on the next allocation
+        db.create_node(Node {
+            id: 4,
+            name: "node4".to_string(),
+            rlt_head: 0,
+            attr_head: 0,
+            label_head: 0,
+            in_head: 0,
+        })
+        .unwrap();
+        assert!(db.read_header().unwrap().total_blocks > 11);
+    }
+
+    #[test]
+    fn test_long_strings() {
+        // SETUP
+        let path = temp_db_path("long_strings");
+        let mut db = Database::create(&path, 10).unwrap();
+        let short = "ünï".to_string();
+        let long = "ab€".repeat(40); // 120 characters, 200 bytes: inline part plus three overflow blocks
+
+        // TEST
+        db.create_node(Node {
+            id: 1,
+            name: long.clone(),
+            rlt_head: 0,
+            attr_head: 0,
+            label_head: 0,
+            in_head: 0,
+        })
+        .unwrap();
+        db.set_property(
+            Entity::Node(1),
+            "note",
+            PropertyValue::String(short.clone()),
+        )
+        .unwrap();
+        let node_offset = db.get_node_address_from_name(&long).unwrap();
+        let attr_offset = db.get_node(node_offset).unwrap().attr_head;
+        assert_eq!(
+            db.read_header().unwrap().first_empty,
+            HEADER_SIZE + 8 * BLOCK_SIZE
+        );
+        drop(db);
+
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(db.get_node_from_id(1).unwrap().name, long);
+        assert_eq!(
+            db.get_property(Entity::Node(1), "note").unwrap(),
+            PropertyValue::String(short)
+        );
+        assert!(matches!(
+            db.get_node(attr_offset),
+            Err(GdbError::NotFound { .. })
+        ));
+
+        // renaming and deleting hand the overflow blocks back
+        db.update_node_name(node_offset, "short".to_string())
+            .unwrap();
+        assert_eq!(db.get_node(node_offset).unwrap().name, "short");
+        assert_eq!(db.offsets_of(BlockType::Overflow).unwrap().len(), 0);
+
+        db.update_node_name(node_offset, long.clone()).unwrap();
+        assert_eq!(db.offsets_of(BlockType::Overflow).unwrap().len(), 3);
+        db.delete_node_name(long, DeleteMode::Detach).unwrap();
+        assert_eq!(db.offsets_of(BlockType::Overflow).unwrap().len(), 0);
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_check_orphan_overflow() {
+        // SETUP
+        let path = temp_db_path("orphan_overflow");
+        let mut db = Database::create(&path, 10).unwrap();
+        db.create_node(Node {
+            id: 1,
+            name: "x".repeat(100),
+            rlt_head: 0,
+            attr_head: 0,
+            label_head: 0,
+            in_head: 0,
+        })
+        .unwrap();
+        let overflow_offset = db.offsets_of(BlockType::Overflow).unwrap()[0];
+
+        // node forgets its overflow chain
+        db.logged_op(WalOp::UpdateNode, |db| {
+            let mut node_block = db.read_block::<NodeBlock>(HEADER_SIZE)?;
+            node_block.name.overflow = 0;
+            db.write_block(HEADER_SIZE, &node_block)
+        })
+        .unwrap();
+
+        // TEST
+        // the name lost its tail, so the full-text index no longer matches it either
+        let report = db.repair().unwrap();
+        for violation in &report.violations {
+            assert!(
+                matches!(
+                    violation,
+                    crate::check::Violation::IndexMismatch {
+                        field: crate::check::Field::TextIndex,
+                        ..
+                    }
+                ) || *violation
+                    == crate::check::Violation::OrphanBlock {
+                        offset: overflow_offset
+                    },
+                "{:?}",
+                violation
+            );
+        }
+        assert!(report
+            .violations
+            .contains(&crate::check::Violation::OrphanBlock {
+                offset: overflow_offset
+            }));
+        assert!(db.check().unwrap().is_clean());
+        assert_eq!(
+            db.get_node(HEADER_SIZE).unwrap().name,
+            "x".repeat(INLINE_STRING_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_typed_properties() {
+        // SETUP
+        let path = temp_db_path("typed_properties");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+        let rlt = Relationship {
+            node_from: 1,
+            node_to: 2,
+            rlt_next: 0,
+            attr_head: 0,
+            rlt_type: 0,
+            in_next: 0,
+            rlt_prev: 0,
+            in_prev: 0,
+        };
+        let rlt_offset = db.create_relationship(rlt, "KNOWS").unwrap();
+
+        let values = [
+            ("name", PropertyValue::String("Ada".to_string())),
+            ("age", PropertyValue::Int(42)),
+            ("score", PropertyValue::Float(0.5)),
+            ("active", PropertyValue::Bool(true)),
+            ("avatar", PropertyValue::Bytes(vec![0, 255, 7])),
+            ("joined", PropertyValue::Timestamp(1_700_000_000_000)),
+            (
+                "tags",
+                PropertyValue::List(vec![PropertyValue::Int(1); 20]), // spills into overflow blocks
+            ),
+        ];
+
+        // TEST
+        for (key, value) in &values {
+            db.set_property(Entity::Node(1), key, value.clone())
+                .unwrap();
+        }
+        db.set_property(
+            Entity::Relationship(rlt_offset),
+            "since",
+            PropertyValue::Int(2020),
+        )
+        .unwrap();
+        db.set_property(Entity::Node(1), "age", PropertyValue::Int(43))
+            .unwrap();
+        drop(db);
+
+        let mut db = Database::open(&path).unwrap();
+        let properties = db.get_properties(Entity::Node(1)).unwrap();
+        assert_eq!(properties.len(), values.len());
+        assert_eq!(properties[1], ("age".to_string(), PropertyValue::Int(43)));
+        assert_eq!(
+            db.get_property(Entity::Node(1), "tags").unwrap(),
+            values[6].1
+        );
+        assert_eq!(
+            db.get_property(Entity::Relationship(rlt_offset), "since")
+                .unwrap(),
+            PropertyValue::Int(2020)
+        );
+        assert!(matches!(
+            db.get_property(Entity::Node(2), "age"),
+            Err(GdbError::NotFound { .. })
+        ));
+
+        assert_eq!(
+            db.remove_property(Entity::Node(1), "name").unwrap(),
+            values[0].1
+        );
+        assert!(db.get_property(Entity::Node(1), "name").is_err());
+        assert_eq!(db.get_properties(Entity::Node(1)).unwrap().len(), 6);
+
+        // deleting the relationship frees its properties
+        db.delete_relationship(db.get_relationship(rlt_offset).unwrap())
+            .unwrap();
+        assert_eq!(db.offsets_of(BlockType::Attribute).unwrap().len(), 6);
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_relationship_types() {
+        // SETUP
+        let path = temp_db_path("relationship_types");
+        let migrated = temp_db_path("relationship_types_migrated");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+
+        let link = |db: &mut Database, node_from, node_to, rlt_type| {
+            db.create_relationship(
+                Relationship {
+                    node_from,
+                    node_to,
+                    ..Default::default()
+                },
+                rlt_type,
+            )
+            .unwrap()
+        };
+        link(&mut db, 1, 2, "FOLLOWS");
+        let owns = link(&mut db, 1, 2, "OWNS");
+        link(&mut db, 1, 3, "FOLLOWS");
+        drop(db);
+
+        // TEST
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.relationship_types().unwrap(), ["FOLLOWS", "OWNS"]);
+
+        let node1 = db.get_node_from_id(1).unwrap();
+        let follows = db.relationships_from(&node1, Some("FOLLOWS")).unwrap();
+        assert_eq!(
+            follows.iter().map(|(_, r)| r.node_to).collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert_eq!(db.relationships_from(&node1, None).unwrap().len(), 3);
+        assert!(db
+            .relationships_from(&node1, Some("BLOCKS"))
+            .unwrap()
+            .is_empty());
+
+        let node2 = db.get_node_from_id(2).unwrap();
+        assert_eq!(
+            db.relationships_to(&node2, Some("OWNS")).unwrap(),
+            [(owns, db.get_relationship(owns).unwrap())]
+        );
+
+        let rlt = db
+            .get_relationship_from_to("node1", "node2", Some("OWNS"))
+            .unwrap();
+        assert_eq!(db.relationship_type(&rlt).unwrap().as_deref(), Some("OWNS"));
+        assert!(db
+            .get_relationship_from_to("node1", "node3", Some("OWNS"))
+            .is_err());
+        drop(db);
+
+        // types survive migration and compaction
+        crate::migrate::migrate(&path, &migrated).unwrap();
+        let mut db = Database::open(&migrated).unwrap();
+        db.compact().unwrap();
+        let rlt = db.get_relationship_from_to("node1", "node3", None).unwrap();
+        assert_eq!(
+            db.relationship_type(&rlt).unwrap().as_deref(),
+            Some("FOLLOWS")
+        );
+
+        // deleting by value only matches a relationship of the same type
+        let owns = db
+            .get_relationship_from_to("node1", "node2", Some("OWNS"))
+            .unwrap();
+        db.delete_relationship(owns).unwrap();
+        assert!(db
+            .get_relationship_from_to("node1", "node2", Some("OWNS"))
+            .is_err());
+        assert!(db
+            .get_relationship_from_to("node1", "node2", Some("FOLLOWS"))
+            .is_ok());
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_node_labels() {
+        // SETUP
+        let path = temp_db_path("node_labels");
+        let migrated = temp_db_path("node_labels_migrated");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+
+        db.add_label(1, "Person").unwrap();
+        db.add_label(1, "Admin").unwrap();
+        db.add_label(2, "Person").unwrap();
+        db.add_label(3, "Person").unwrap();
+        db.add_label(1, "Person").unwrap(); // already carried, no change
+        drop(db);
+
+        // TEST
+        let mut db = Database::open(&path).unwrap();
+        let ids = |db: &Database, label| {
+            let mut ids: Vec<u64> = db
+                .nodes_with_label(label)
+                .unwrap()
+                .iter()
+                .map(|node| node.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(db.labels().unwrap(), ["Person", "Admin"]);
+        assert_eq!(db.node_labels(1).unwrap(), ["Person", "Admin"]);
+        assert_eq!(ids(&db, "Person"), [1, 2, 3]);
+        assert_eq!(ids(&db, "Admin"), [1]);
+        assert!(db.nodes_with_label("Robot").unwrap().is_empty());
+
+        db.remove_label(2, "Person").unwrap();
+        assert!(db.node_labels(2).unwrap().is_empty());
+        assert!(matches!(
+            db.remove_label(2, "Person"),
+            Err(GdbError::NotFound {
+                kind: RecordKind::Label,
+                ..
+            })
+        ));
+        assert_eq!(ids(&db, "Person"), [1, 3]);
+        assert!(db.check().unwrap().is_clean()); // spliced out of both directions of the member chain
+
+        // deleting a node takes it off every label
+        let node1 = db.get_node_from_id(1).unwrap();
+        db.delete_node(node1, DeleteMode::Detach).unwrap();
+        assert_eq!(ids(&db, "Person"), [3]);
+        assert!(db.nodes_with_label("Admin").unwrap().is_empty());
+        assert!(db.check().unwrap().is_clean());
+
+        // a member back link that doesn't match the chain is reported and reset
+        let label3 = db.get_node_from_id(3).unwrap().label_head;
+        db.logged_op(WalOp::UpdateNode, |db| {
+            let mut label_block = db.read_block::<crate::types::LabelBlock>(label3)?;
+            label_block.member_prev = label3;
+            db.write_block(label3, &label_block)
+        })
+        .unwrap();
+        assert!(db
+            .check()
+            .unwrap()
+            .violations
+            .contains(&crate::check::Violation::BadPointer {
+                offset: label3,
+                field: crate::check::Field::MemberPrev,
+                target: label3,
+            }));
+        db.repair().unwrap();
+        assert!(db.check().unwrap().is_clean());
+        drop(db);
+
+        // labels survive migration and compaction
+        crate::migrate::migrate(&path, &migrated).unwrap();
+        let mut db = Database::open(&migrated).unwrap();
+        db.compact().unwrap();
+        assert_eq!(ids(&db, "Person"), [3]);
+        assert_eq!(db.node_labels(3).unwrap(), ["Person"]);
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_incoming_relationships() {
+        // SETUP
+        let path = temp_db_path("incoming_relationships");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+
+        let link = |db: &mut Database, node_from, node_to, rlt_type| {
+            db.create_relationship(
+                Relationship {
+                    node_from,
+                    node_to,
+                    ..Default::default()
+                },
+                rlt_type,
+            )
+            .unwrap()
+        };
+        let r1 = link(&mut db, 1, 3, "FOLLOWS");
+        let r2 = link(&mut db, 2, 3, "OWNS");
+        let r3 = link(&mut db, 3, 3, "FOLLOWS");
+
+        // TEST
+        let node3 = db.get_node_from_id(3).unwrap();
+        let incoming: Vec<u64> = db
+            .relationships_to(&node3, None)
+            .unwrap()
+            .iter()
+            .map(|(offset, _)| *offset)
+            .collect();
+        assert_eq!(incoming, [r3, r2, r1]);
+        assert_eq!(db.relationships_to(&node3, Some("OWNS")).unwrap()[0].0, r2);
+        assert_eq!(db.relationships_from(&node3, None).unwrap()[0].0, r3);
+
+        // deleting unlinks from both chains
+        db.delete_relationship(db.get_relationship(r2).unwrap())
+            .unwrap();
+        let node3 = db.get_node_from_id(3).unwrap();
+        assert_eq!(db.relationships_to(&node3, None).unwrap().len(), 2);
+        assert_eq!(db.get_node_from_id(2).unwrap().rlt_head, 0);
+        assert!(db.check().unwrap().is_clean());
+
+        // relationships need both endpoints
+        assert!(matches!(
+            db.create_relationship(
+                Relationship {
+                    node_from: 1,
+                    node_to: 99,
+                    ..Default::default()
+                },
+                "FOLLOWS",
+            ),
+            Err(GdbError::ConstraintViolation(_))
+        ));
+    }
+
+    #[test]
+    fn test_unlink_relationship() {
+        use crate::check::{Field, Violation};
+        use crate::types::RelationshipBlock;
+
+        // SETUP
+        let path = temp_db_path("unlink_relationship");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+
+        let link = |db: &mut Database, node_to| {
+            db.create_relationship(
+                Relationship {
+                    node_from: 1,
+                    node_to,
+                    ..Default::default()
+                },
+                "LINKS",
+            )
+            .unwrap()
+        };
+        let r1 = link(&mut db, 2);
+        let r2 = link(&mut db, 3);
+        let r3 = link(&mut db, 2);
+
+        // TEST
+        assert_eq!(db.get_relationship(r2).unwrap().rlt_prev, r1);
+        assert_eq!(db.get_relationship(r1).unwrap().in_prev, r3);
+
+        // middle of node 1's chain, r1 and r3 now point at each other
+        db.delete_relationship_at(r2).unwrap();
+        assert_eq!(db.get_relationship(r1).unwrap().rlt_next, r3);
+        assert_eq!(db.get_relationship(r3).unwrap().rlt_prev, r1);
+        assert_eq!(db.get_node_from_id(3).unwrap().in_head, 0);
+
+        // head of node 2's incoming chain
+        db.delete_relationship_at(r3).unwrap();
+        assert_eq!(db.get_node_from_id(2).unwrap().in_head, r1);
+        assert_eq!(db.get_relationship(r1).unwrap().in_prev, 0);
+        assert_eq!(db.get_relationship(r1).unwrap().rlt_next, 0);
+        assert!(db.check().unwrap().is_clean());
+
+        // a wrong back pointer is reported and reset
+        let r4 = link(&mut db, 3);
+        db.logged_op(WalOp::UpdateRelationship, |db| {
+            let mut block = db.read_block::<RelationshipBlock>(r4)?;
+            block.relationship.rlt_prev = r4;
+            db.write_block(r4, &block)
+        })
+        .unwrap();
+        let report = db.repair().unwrap();
+        assert_eq!(
+            report.violations,
+            [Violation::BadPointer {
+                offset: r4,
+                field: Field::RltPrev,
+                target: r4,
+            }]
+        );
+        assert_eq!(db.get_relationship(r4).unwrap().rlt_prev, r1);
+    }
+
+    #[test]
+    fn test_node_id_index() {
+        use crate::check::Violation;
+
+        // SETUP
+        let path = temp_db_path("node_id_index");
+        let mut db = Database::create(&path, 8).unwrap();
+        for id in (1..=40).rev() {
+            db.create_node(Node {
+                id,
+                name: format!("node{}", id),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            })
+            .unwrap();
+        }
+        for id in (2..=40).step_by(3) {
+            db.delete_node(db.get_node_from_id(id).unwrap(), DeleteMode::Detach)
+                .unwrap();
+        }
+
+        // TEST
+        let lookup = |db: &Database| {
+            for id in 1..=40 {
+                match db.get_node_from_id(id) {
+                    Ok(node) => {
+                        assert_ne!(id % 3, 2);
+                        assert_eq!(node.name, format!("node{}", id));
+                    }
+                    Err(GdbError::NotFound { .. }) => assert_eq!(id % 3, 2),
+                    Err(err) => panic!("{:?}", err),
+                }
+            }
+        };
+        lookup(&db);
+        assert!(db.check().unwrap().is_clean());
+
+        db.compact().unwrap();
+        lookup(&db);
+        assert!(db.check().unwrap().is_clean());
+
+        // lose the index, repair builds it again
+        db.logged_op(WalOp::UpdateHeader, |db| {
+            let mut header = db.read_header()?;
+            header.id_index = 0;
+            db.write_header(&header)
+        })
+        .unwrap();
+        let report = db.check().unwrap();
+        assert!(report
+            .violations
+            .iter()
+            .any(|v| matches!(v, Violation::IndexMismatch { key: 1, .. })));
+        assert!(db.repair().unwrap().repaired);
+        assert!(db.check().unwrap().is_clean());
+        drop(db);
+
+        let db = Database::open(&path).unwrap();
+        lookup(&db);
+    }
+
+    #[test]
+    fn test_index_removal_frees_blocks() {
+        // SETUP
+        let path = temp_db_path("index_removal");
+        let mut db = Database::create(&path, 8).unwrap();
+        for id in 1..=40 {
+            db.create_node(Node {
+                id,
+                name: format!("node{}", id),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            })
+            .unwrap();
+        }
+        let index_blocks = |db: &Database| {
+            let header = db.read_header().unwrap();
+            [header.id_index, header.name_index, header.text_index]
+                .into_iter()
+                .map(|root| db.index_blocks(root).unwrap().len())
+                .sum::<usize>()
+        };
+        let full = index_blocks(&db);
+
+        // TEST
+        // emptied leaves and one child branches go back on the free list
+        for id in 1..=36 {
+            db.delete_node(db.get_node_from_id(id).unwrap(), DeleteMode::Detach)
+                .unwrap();
+        }
+        assert!(index_blocks(&db) < full / 4);
+        assert!(db.check().unwrap().is_clean());
+        for id in 37..=40 {
+            assert_eq!(db.get_node_from_id(id).unwrap().name, format!("node{}", id));
+        }
+        assert_eq!(db.nodes_with_name_prefix("node", false).unwrap().len(), 4);
+
+        // the last removals leave no index at all
+        for id in 37..=40 {
+            db.delete_node(db.get_node_from_id(id).unwrap(), DeleteMode::Detach)
+                .unwrap();
+        }
+        let header = db.read_header().unwrap();
+        assert_eq!(
+            (header.id_index, header.name_index, header.text_index),
+            (0, 0, 0)
+        );
+        assert!(db.check().unwrap().is_clean());
+
+        // and the trees grow again from nothing
+        let id = db
+            .create_node(Node {
+                id: 0,
+                name: "again".to_string(),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            })
+            .unwrap();
+        assert_eq!(
+            db.get_node_address_from_name("again").unwrap(),
+            db.get_node_address_from_id(id).unwrap()
+        );
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_assigned_node_ids() {
+        // SETUP
+        let path = temp_db_path("assigned_node_ids");
+        let mut db = Database::create(&path, 10).unwrap();
+        let node = |id, name: &str| Node {
+            id,
+            name: name.to_string(),
+            rlt_head: 0,
+            attr_head: 0,
+            label_head: 0,
+            in_head: 0,
+        };
+
+        // TEST
+        assert_eq!(db.create_node(node(0, "a")).unwrap(), 1);
+        assert_eq!(db.create_node(node(0, "b")).unwrap(), 2);
+        assert_eq!(db.create_node(node(5, "c")).unwrap(), 5); // explicit ids move the counter past them
+        assert_eq!(db.create_node(node(0, "d")).unwrap(), 6);
+        assert_eq!(db.create_node(node(3, "e")).unwrap(), 3);
+
+        assert!(matches!(
+            db.create_node(node(2, "f")),
+            Err(GdbError::ConstraintViolation(_))
+        ));
+        assert_eq!(db.get_node_from_id(2).unwrap().name, "b");
+
+        // deleted ids are not handed out again, the counter survives a reopen
+        db.delete_node(db.get_node_from_id(6).unwrap(), DeleteMode::Detach)
+            .unwrap();
+        drop(db);
+
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(db.create_node(node(0, "g")).unwrap(), 7);
+
+        // the last id is reserved, once the counter reaches it auto ids run out
+        assert!(matches!(
+            db.create_node(node(u64::MAX, "h")),
+            Err(GdbError::ConstraintViolation(_))
+        ));
+        assert_eq!(
+            db.create_node(node(u64::MAX - 1, "i")).unwrap(),
+            u64::MAX - 1
+        );
+        assert!(matches!(
+            db.create_node(node(0, "j")),
+            Err(GdbError::ConstraintViolation(_))
+        ));
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_node_name_index() {
+        // SETUP
+        let path = temp_db_path("node_name_index");
+        let mut db = Database::create(&path, 10).unwrap();
+        for name in [
+            "Alice",
+            "alice",
+            "Alicia",
+            "Bob",
+            "payment-service-a",
+            "payment-service-b",
+        ] {
+            db.create_node(Node {
+                id: 0,
+                name: name.to_string(),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            })
+            .unwrap();
+        }
+        let names = |nodes: Vec<Node>| nodes.into_iter().map(|node| node.name).collect::<Vec<_>>();
+
+        // TEST
+        let alice = db.get_node_address_from_name("alice").unwrap();
+        assert_eq!(db.get_node(alice).unwrap().name, "alice");
+        assert_eq!(
+            names(db.nodes_with_name("ALICE", true).unwrap()),
+            ["Alice", "alice"]
+        );
+        assert!(db.nodes_with_name("ALICE", false).unwrap().is_empty());
+
+        assert_eq!(
+            names(db.nodes_with_name_prefix("Ali", false).unwrap()),
+            ["Alice", "Alicia"]
+        );
+        assert_eq!(
+            names(db.nodes_with_name_prefix("ali", true).unwrap()).len(),
+            3
+        );
+
+        // names sharing their first 8 bytes share a key, the full name still decides
+        assert_eq!(
+            names(db.nodes_with_name("payment-service-b", false).unwrap()),
+            ["payment-service-b"]
+        );
+        assert_eq!(
+            names(db.nodes_with_name_prefix("Payment-Service-", true).unwrap()).len(),
+            2
+        );
+
+        // renames and deletes keep the index in step
+        db.update_node_name(alice, "Bobby".to_string()).unwrap();
+        assert_eq!(
+            names(db.nodes_with_name_prefix("bob", true).unwrap()),
+            ["Bob", "Bobby"]
+        );
+        assert_eq!(names(db.nodes_with_name("alice", true).unwrap()), ["Alice"]);
+
+        db.delete_node_name("Bob".to_string(), DeleteMode::Detach)
+            .unwrap();
+        assert_eq!(
+            names(db.nodes_with_name_prefix("B", false).unwrap()),
+            ["Bobby"]
+        );
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_property_indexes() {
+        // SETUP
+        let path = temp_db_path("property_indexes");
+        let mut db = Database::create(&path, 10).unwrap();
+        let services = [
+            ("auth", 8080, 0.5, "eu-west"),
+            ("billing", 9000, -1.5, "us-east"),
+            ("search", 443, 2.0, "eu-central"),
+            ("mail", 25, -0.25, "us-east"),
+        ];
+        for (name, port, load, region) in services {
+            let id = db
+                .create_node(Node {
+                    id: 0,
+                    name: name.to_string(),
+                    rlt_head: 0,
+                    attr_head: 0,
+                    label_head: 0,
+                    in_head: 0,
+                })
+                .unwrap();
+            let node = Entity::Node(id);
+            db.set_property(node, "port", PropertyValue::Int(port))
+                .unwrap();
+            db.set_property(node, "load", PropertyValue::Float(load))
+                .unwrap();
+            db.set_property(node, "region", PropertyValue::String(region.to_string()))
+                .unwrap();
+        }
+        let names = |nodes: Vec<Node>| nodes.into_iter().map(|node| node.name).collect::<Vec<_>>();
+        let east = PropertyValue::String("us-east".to_string());
+
+        // TEST
+        // no index yet, queries scan
+        assert_eq!(
+            names(db.nodes_where("region", &east).unwrap()),
+            ["billing", "mail"]
+        );
+
+        db.create_property_index("region", IndexKind::Hash).unwrap();
+        db.create_property_index("port", IndexKind::Ordered)
+            .unwrap();
+        db.create_property_index("load", IndexKind::Ordered)
+            .unwrap();
+        db.create_property_index("port", IndexKind::Ordered)
+            .unwrap(); // already there
+        assert_eq!(db.property_indexes().unwrap().len(), 3);
+
+        assert_eq!(
+            names(db.nodes_where("region", &east).unwrap()),
+            ["billing", "mail"]
+        );
+        assert_eq!(
+            names(db.nodes_where("port", &PropertyValue::Int(443)).unwrap()),
+            ["search"]
+        );
+        assert_eq!(
+            names(
+                db.nodes_between("port", &PropertyValue::Int(25), &PropertyValue::Int(8080))
+                    .unwrap()
+            ),
+            ["auth", "search", "mail"]
+        );
+        assert_eq!(
+            names(
+                db.nodes_between(
+                    "load",
+                    &PropertyValue::Float(-2.0),
+                    &PropertyValue::Float(0.0)
+                )
+                .unwrap()
+            ),
+            ["billing", "mail"]
+        );
+        // values of another type never fall in the range
+        assert!(db
+            .nodes_between(
+                "port",
+                &PropertyValue::Float(0.0),
+                &PropertyValue::Float(1e9)
+            )
+            .unwrap()
+            .is_empty());
+        assert!(db.check().unwrap().is_clean());
+
+        // updates, removals and deletes keep every index in step
+        let search = Entity::Node(3);
+        db.set_property(search, "region", east.clone()).unwrap();
+        db.set_property(search, "port", PropertyValue::Int(9443))
+            .unwrap();
+        db.remove_property(Entity::Node(2), "region").unwrap();
+        assert_eq!(
+            names(db.nodes_where("region", &east).unwrap()),
+            ["search", "mail"]
+        );
+        assert!(db
+            .nodes_where("port", &PropertyValue::Int(443))
+            .unwrap()
+            .is_empty());
+
+        db.delete_node_name("mail".to_string(), DeleteMode::Detach)
+            .unwrap();
+        assert_eq!(names(db.nodes_where("region", &east).unwrap()), ["search"]);
+        assert_eq!(
+            names(
+                db.nodes_between(
+                    "port",
+                    &PropertyValue::Int(0),
+                    &PropertyValue::Int(i64::MAX)
+                )
+                .unwrap()
+            ),
+            ["auth", "billing", "search"]
+        );
+        assert!(db.check().unwrap().is_clean());
+
+        // compaction moves the nodes, the indexes follow
+        db.compact().unwrap();
+        assert!(db.check().unwrap().is_clean());
+        assert_eq!(
+            names(
+                db.nodes_between(
+                    "port",
+                    &PropertyValue::Int(9000),
+                    &PropertyValue::Int(10000)
+                )
+                .unwrap()
+            ),
+            ["billing", "search"]
+        );
+    }
+
+    #[test]
+    fn test_full_text_search() {
+        // SETUP
+        let path = temp_db_path("full_text_search");
+        let mut db = Database::create(&path, 10).unwrap();
+        for (name, description) in [
+            ("payment-service", "Takes card payments"),
+            (
+                "Payment Gateway",
+                "Routes payment requests to the payment service",
+            ),
+            ("search-service", "Full text search"),
+            ("ledger", "Books every payment"),
+        ] {
+            let id = db
+                .create_node(Node {
+                    id: 0,
+                    name: name.to_string(),
+                    rlt_head: 0,
+                    attr_head: 0,
+                    label_head: 0,
+                    in_head: 0,
+                })
+                .unwrap();
+            db.set_property(
+                Entity::Node(id),
+                "description",
+                PropertyValue::String(description.to_string()),
+            )
+            .unwrap();
+        }
+        let names = |hits: Vec<(Node, f64)>| {
+            hits.into_iter()
+                .map(|(node, _)| node.name)
+                .collect::<Vec<_>>()
+        };
+
+        // TEST
+        assert_eq!(
+            crate::text_index::tokenize("Payment-Service, v2!"),
+            ["payment", "service", "v2"]
+        );
+
+        // names only until the description is declared
+        assert_eq!(
+            names(db.search("PAYMENT service", 10).unwrap()),
+            ["payment-service", "Payment Gateway", "search-service"]
+        );
+        assert!(db.search("card", 10).unwrap().is_empty());
+
+        db.create_text_index("description").unwrap();
+        db.create_text_index("description").unwrap(); // already there
+        assert_eq!(db.text_indexes().unwrap(), ["description"]);
+
+        // repeats outweigh the gateway's longer document, shorter documents win ties on one term
+        let hits = db.search("payment service", 10).unwrap();
+        assert_eq!(
+            names(hits.clone()),
+            [
+                "Payment Gateway",
+                "payment-service",
+                "ledger",
+                "search-service"
+            ]
+        );
+        assert!(hits.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert_eq!(names(db.search("card", 10).unwrap()), ["payment-service"]);
+        assert_eq!(db.search("payment", 2).unwrap().len(), 2);
+        assert!(db.search(" -- ", 10).unwrap().is_empty());
+        assert!(db.check().unwrap().is_clean());
+
+        // renames, property changes and deletes are followed
+        let ledger = db.get_node_address_from_name("ledger").unwrap();
+        db.update_node_name(ledger, "accounts".to_string()).unwrap();
+        assert!(db.search("ledger", 10).unwrap().is_empty());
+        db.set_property(
+            Entity::Node(4),
+            "description",
+            PropertyValue::String("Keeps the books".to_string()),
+        )
+        .unwrap();
+        assert_eq!(names(db.search("books", 10).unwrap()), ["accounts"]);
+        assert_eq!(db.search("payment", 10).unwrap().len(), 2);
+
+        db.remove_property(Entity::Node(1), "description").unwrap();
+        assert!(db.search("card", 10).unwrap().is_empty());
+        db.delete_node_name("search-service".to_string(), DeleteMode::Detach)
+            .unwrap();
+        assert!(db.search("search", 10).unwrap().is_empty());
+        assert!(db.check().unwrap().is_clean());
+
+        db.compact().unwrap();
+        assert!(db.check().unwrap().is_clean());
+        assert_eq!(
+            names(db.search("gateway", 10).unwrap()),
+            ["Payment Gateway"]
+        );
+    }
+
+    #[test]
+    fn test_constraints() {
+        use crate::types::Constraint;
+
+        // SETUP
+        let path = temp_db_path("constraints");
+        let mut db = Database::create(&path, 10).unwrap();
+        for name in ["alice", "bob", "alice"] {
+            db.create_node(Node {
+                id: 0,
+                name: name.to_string(),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            })
+            .unwrap();
+        }
+        let email = |address: &str| PropertyValue::String(address.to_string());
+        db.set_property(Entity::Node(1), "email", email("a@example.com"))
+            .unwrap();
+        db.set_property(Entity::Node(2), "email", email("b@example.com"))
+            .unwrap();
+        db.add_label(1, "Person").unwrap();
+        db.add_label(2, "Person").unwrap();
+        let violation = |result: crate::error::Result<()>| match result {
+            Err(GdbError::ConstraintViolation(message)) => message,
+            other => panic!("expected ConstraintViolation, got {:?}", other),
+        };
+
+        // TEST
+        for constraint in [
+            Constraint::UniqueName {
+                label: "Person".to_string(),
+            },
+            Constraint::Unique {
+                label: "Person".to_string(),
+                key: "email".to_string(),
+            },
+            Constraint::Required {
+                label: "Person".to_string(),
+                key: "email".to_string(),
+            },
+        ] {
+            db.create_constraint(constraint.clone()).unwrap();
+            db.create_constraint(constraint).unwrap(); // already there
+        }
+        assert_eq!(db.constraints().unwrap().len(), 3);
+
+        // node 3 shares alice's name and has no email, it cannot become a Person
+        assert_eq!(
+            violation(db.add_label(3, "Person")),
+            "nodes 1 and 3 with label Person are both named \"alice\""
+        );
+        assert!(db.node_labels(3).unwrap().is_empty()); // the failed write left nothing behind
+        db.update_node_name(db.get_node_address_from_id(3).unwrap(), "carol".to_string())
+            .unwrap();
+        assert_eq!(
+            violation(db.add_label(3, "Person")),
+            "node 3 with label Person has no email property"
+        );
+        db.set_property(Entity::Node(3), "email", email("c@example.com"))
+            .unwrap();
+        db.add_label(3, "Person").unwrap();
+
+        // every later write is checked too
+        let bob = db.get_node_address_from_id(2).unwrap();
+        violation(db.update_node_name(bob, "carol".to_string()));
+        assert_eq!(
+            violation(db.set_property(Entity::Node(2), "email", email("a@example.com"))),
+            "nodes 1 and 2 with label Person both have email = String(\"a@example.com\")"
+        );
+        assert_eq!(
+            db.get_property(Entity::Node(2), "email").unwrap(),
+            email("b@example.com")
+        );
+        assert!(matches!(
+            db.remove_property(Entity::Node(2), "email"),
+            Err(GdbError::ConstraintViolation(_))
+        ));
+
+        // with an index on the key only nodes holding the value are compared, other labels don't clash
+        db.create_property_index("email", IndexKind::Hash).unwrap();
+        assert_eq!(
+            violation(db.set_property(Entity::Node(2), "email", email("c@example.com"))),
+            "nodes 3 and 2 with label Person both have email = String(\"c@example.com\")"
+        );
+        db.create_node(Node {
+            id: 0,
+            name: "dave".to_string(),
+            rlt_head: 0,
+            attr_head: 0,
+            label_head: 0,
+            in_head: 0,
+        })
+        .unwrap();
+        db.set_property(Entity::Node(4), "email", email("a@example.com"))
+            .unwrap();
+
+        // declaring a constraint the stored nodes break fails and is not kept
+        db.set_property(Entity::Node(1), "team", PropertyValue::Int(1))
+            .unwrap();
+        violation(db.create_constraint(Constraint::Required {
+            label: "Person".to_string(),
+            key: "team".to_string(),
+        }));
+        assert_eq!(db.constraints().unwrap().len(), 3);
+
+        // relationships must join two nodes
+        assert_eq!(
+            violation(
+                db.create_relationship(
+                    Relationship {
+                        node_from: 99,
+                        node_to: 1,
+                        ..Default::default()
+                    },
+                    "KNOWS",
+                )
+                .map(|_| ())
+            ),
+            "relationship node_from 99 is not a node"
+        );
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_schema() {
+        use crate::types::{LabelSchema, SchemaMode};
+
+        // SETUP
+        let path = temp_db_path("schema");
+        let mut db = Database::create(&path, 10).unwrap();
+        let service = |db: &mut Database, name: &str| {
+            let id = db
+                .create_node(Node {
+                    id: 0,
+                    name: name.to_string(),
+                    rlt_head: 0,
+                    attr_head: 0,
+                    label_head: 0,
+                    in_head: 0,
+                })
+                .unwrap();
+            db.add_label(id, "Service").unwrap();
+            db.set_property(Entity::Node(id), "port", PropertyValue::Int(8080))
+                .unwrap();
+            id
+        };
+        let depends_on = |db: &mut Database, node_from, node_to| {
+            db.create_relationship(
+                Relationship {
+                    node_from,
+                    node_to,
+                    ..Default::default()
+                },
+                "DEPENDS_ON",
+            )
+            .map(|_| ())
+        };
+        let violation = |result: crate::error::Result<()>| match result {
+            Err(GdbError::SchemaViolation(message)) => message,
+            other => panic!("expected SchemaViolation, got {:?}", other),
+        };
+
+        let schema_path = temp_db_path("schema_catalog");
+        std::fs::write(
+            &schema_path,
+            r#"{
+                "labels": [{"label": "Service", "properties": {"name": "String", "port": "Int"}}],
+                "relationships": [{"rlt_type": "DEPENDS_ON", "from": "Service", "to": "Service", "properties": {"weight": "Float"}}]
+            }"#,
+        )
+        .unwrap();
+
+        // TEST
+        assert_eq!(db.schema().unwrap(), None);
+        let api = service(&mut db, "api");
+        let auth = service(&mut db, "auth");
+        db.load_schema(&schema_path).unwrap();
+        let mut schema = db.schema().unwrap().unwrap();
+        assert_eq!(schema.mode, SchemaMode::Permissive);
+
+        // declared properties must have their type, undeclared ones are allowed
+        assert_eq!(
+            violation(db.set_property(
+                Entity::Node(api),
+                "port",
+                PropertyValue::String("80".to_string())
+            )),
+            "node 1 property port is String, label Service declares it Int"
+        );
+        assert_eq!(
+            db.get_property(Entity::Node(api), "port").unwrap(),
+            PropertyValue::Int(8080)
+        );
+        db.set_property(Entity::Node(api), "owner", PropertyValue::Bool(true))
+            .unwrap();
+
+        // DEPENDS_ON only joins two services, other types are free
+        depends_on(&mut db, api, auth).unwrap();
+        let api_auth = db
+            .relationships_from(&db.get_node_from_id(api).unwrap(), None)
+            .unwrap()[0]
+            .0;
+        assert_eq!(
+            violation(db.set_property(
+                Entity::Relationship(api_auth),
+                "weight",
+                PropertyValue::Int(1)
+            )),
+            "DEPENDS_ON from node 1 to node 2 property weight is Int, the schema declares it Float"
+        );
+        db.set_property(
+            Entity::Relationship(api_auth),
+            "weight",
+            PropertyValue::Float(0.5),
+        )
+        .unwrap();
+        let db_node = db
+            .create_node(Node {
+                id: 0,
+                name: "postgres".to_string(),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            })
+            .unwrap();
+        assert_eq!(
+            violation(depends_on(&mut db, api, db_node)),
+            "DEPENDS_ON from node 1 to node 3 does not join Service -> Service"
+        );
+        db.create_relationship(
+            Relationship {
+                node_from: api,
+                node_to: db_node,
+                ..Default::default()
+            },
+            "READS",
+        )
+        .unwrap();
+        assert_eq!(
+            violation(db.remove_label(auth, "Service")),
+            "DEPENDS_ON from node 1 to node 2 does not join Service -> Service"
+        );
+        assert_eq!(db.node_labels(auth).unwrap(), ["Service"]);
+
+        // strict mode refuses what the schema does not name, starting with the stored data
+        schema.mode = SchemaMode::Strict;
+        assert_eq!(
+            violation(db.set_schema(schema.clone())),
+            "node 1 property owner is not declared by any of its labels"
+        );
+        db.remove_property(Entity::Node(api), "owner").unwrap();
+        assert_eq!(
+            violation(db.set_schema(schema.clone())),
+            "relationship type READS is not declared by the schema"
+        );
+        assert_eq!(db.schema().unwrap().unwrap().mode, SchemaMode::Permissive);
+
+        let mut reads = schema.relationships[0].clone();
+        reads.rlt_type = "READS".to_string();
+        schema.relationships.push(reads);
+        schema.labels.push(LabelSchema {
+            label: "Database".to_string(),
+            properties: Default::default(),
+        });
+        assert_eq!(
+            violation(db.set_schema(schema.clone())),
+            "READS from node 1 to node 3 does not join Service -> Service"
+        );
+        schema.relationships[1].to = "Database".to_string();
+        db.add_label(db_node, "Database").unwrap();
+        db.set_schema(schema.clone()).unwrap();
+        assert_eq!(db.schema().unwrap(), Some(schema));
+
+        assert_eq!(
+            violation(db.add_label(db_node, "Cache")),
+            "node 3 has label Cache, which the schema does not declare"
+        );
+        assert!(matches!(
+            db.set_property(Entity::Node(db_node), "port", PropertyValue::Int(5432)),
+            Err(GdbError::SchemaViolation(_))
+        ));
+        service(&mut db, "mail");
+
+        // nothing declares a property for an unlabelled node, or an undeclared one on a relationship
+        let unlabelled = db
+            .create_node(Node {
+                id: 0,
+                name: "scratch".to_string(),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            })
+            .unwrap();
+        assert_eq!(
+            violation(db.set_property(Entity::Node(unlabelled), "note", PropertyValue::Bool(true))),
+            "node 5 property note is not declared by any of its labels"
+        );
+        assert_eq!(
+            violation(db.set_property(
+                Entity::Relationship(api_auth),
+                "since",
+                PropertyValue::Int(2020)
+            )),
+            "DEPENDS_ON from node 1 to node 2 property since is not declared by the schema"
+        );
+        db.remove_property(Entity::Relationship(api_auth), "weight")
+            .unwrap();
+
+        // the cached schema follows a rolled back transaction
+        let stored = db.schema().unwrap();
+        let mut txn = db.begin().unwrap();
+        txn.set_schema(Default::default()).unwrap();
+        assert_eq!(txn.schema().unwrap(), Some(Default::default()));
+        txn.rollback();
+        assert_eq!(db.schema().unwrap(), stored);
+
+        // the schema outlives the session
+        drop(db);
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(db.schema().unwrap().unwrap().labels.len(), 2);
+        assert!(db.check().unwrap().is_clean());
+
+        // a schema replaced through another handle is picked up without reopening
+        let mut other = Database::open(&path).unwrap();
+        other.set_schema(Default::default()).unwrap();
+        drop(other);
+        assert_eq!(db.schema().unwrap(), Some(Default::default()));
+        db.set_property(
+            Entity::Node(1),
+            "port",
+            PropertyValue::String("http".to_string()),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_delete_modes() {
+        // SETUP
+        let path = temp_db_path("delete_modes");
+        let mut db = Database::create(&path, 20).unwrap();
+        test_nodes(&mut db).unwrap();
+        let link = |db: &mut Database, node_from, node_to| {
+            db.create_relationship(
+                Relationship {
+                    node_from,
+                    node_to,
+                    ..Default::default()
+                },
+                "LINKS",
+            )
+            .unwrap()
+        };
+        let into_2 = link(&mut db, 1, 2);
+        link(&mut db, 2, 3);
+        link(&mut db, 2, 2);
+        link(&mut db, 3, 1);
+        db.set_property(
+            Entity::Relationship(into_2),
+            "weight",
+            PropertyValue::Float(0.5),
+        )
+        .unwrap();
+        let node2 = db.get_node_address_from_id(2).unwrap();
+
+        // TEST
+        // restrict refuses while anything touches node 2, incoming included
+        assert!(matches!(
+            db.delete_node_at(node2, DeleteMode::Restrict),
+            Err(GdbError::ConstraintViolation(_))
+        ));
+        assert_eq!(db.offsets_of(BlockType::Relationship).unwrap().len(), 4);
+
+        // detach drops its relationships, incoming, outgoing and the self loop, then the node
+        db.delete_node_at(node2, DeleteMode::Detach).unwrap();
+        assert!(db.get_node_from_id(2).is_err());
+        let node1 = db.get_node_from_id(1).unwrap();
+        assert!(db.relationships_from(&node1, None).unwrap().is_empty());
+        assert_eq!(db.offsets_of(BlockType::Relationship).unwrap().len(), 1);
+        assert_eq!(db.offsets_of(BlockType::Attribute).unwrap().len(), 0);
+
+        // deleting by name detaches incoming relationships too, node 3 stays
+        db.delete_node_name("node1".to_string(), DeleteMode::Detach)
+            .unwrap();
+        let node3 = db.get_node_from_id(3).unwrap();
+        assert!(db.relationships_from(&node3, None).unwrap().is_empty());
+        assert_eq!(db.offsets_of(BlockType::Relationship).unwrap().len(), 0);
+
+        // nothing touches node 3 now, restrict goes ahead
+        db.delete_node(node3, DeleteMode::Restrict).unwrap();
+        assert!(db.get_node_from_id(3).is_err());
+
+        // root -> child -> leaf, root -> shared <- other
+        let add = |name: &str| {
+            db.create_node(Node {
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let [root, child, leaf, shared, other] =
+            ["root", "child", "leaf", "shared", "other"].map(add);
+        link(&mut db, root, child);
+        link(&mut db, child, leaf);
+        link(&mut db, root, shared);
+        link(&mut db, other, shared);
+        link(&mut db, leaf, root); // back link, root is being deleted so it doesn't keep the tree alive
+
+        // detach only takes the node itself
+        let root_address = db.get_node_address_from_id(root).unwrap();
+        let mut txn = db.begin().unwrap();
+        txn.delete_node_at(root_address, DeleteMode::Detach)
+            .unwrap();
+        assert!(txn.get_node_from_id(child).is_ok());
+        assert!(txn.get_node_from_id(leaf).is_ok());
+        txn.rollback();
+
+        // cascade goes on to the nodes nothing else points to
+        db.delete_node_at(root_address, DeleteMode::Cascade)
+            .unwrap();
+        for id in [root, child, leaf] {
+            assert!(db.get_node_from_id(id).is_err());
+        }
+        for id in [shared, other] {
+            assert!(db.get_node_from_id(id).is_ok());
+        }
+        let other = db.get_node_from_id(other).unwrap();
+        assert_eq!(db.relationships_from(&other, None).unwrap().len(), 1);
+        assert_eq!(db.offsets_of(BlockType::Relationship).unwrap().len(), 1);
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_check_reserved_id() {
+        use crate::check::Violation;
+
+        // SETUP
+        let path = temp_db_path("check_reserved_id");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+        let node3 = db.get_node_address_from_id(3).unwrap();
+
+        // a node written with the reserved id, the counter cannot move past it
+        db.logged_op(WalOp::UpdateNode, |db| {
+            let mut node_block = db.read_block::<NodeBlock>(node3)?;
+            node_block.id = u64::MAX;
+            db.write_block(node3, &node_block)
+        })
+        .unwrap();
+
+        // TEST
+        let report = db.check().unwrap();
+        assert!(report.violations.contains(&Violation::ReservedId {
+            offset: node3,
+            id: u64::MAX,
+        }));
+
+        db.repair().unwrap();
+        assert_eq!(db.read_header().unwrap().next_node_id, u64::MAX);
+        assert!(matches!(
+            db.create_node(Node {
+                id: 0,
+                name: "node4".to_string(),
+                rlt_head: 0,
+                attr_head: 0,
+                label_head: 0,
+                in_head: 0,
+            }),
+            Err(GdbError::ConstraintViolation(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_duplicate_ids() {
+        use crate::check::Violation;
+
+        // SETUP
+        let path = temp_db_path("check_duplicate_ids");
+        let mut db = Database::create(&path, 10).unwrap();
+        test_nodes(&mut db).unwrap();
+        let rlt_offset = db
+            .create_relationship(
+                Relationship {
+                    node_from: 1,
+                    node_to: 3,
+                    ..Default::default()
+                },
+                "LINKS",
+            )
+            .unwrap();
+        let node2 = db.get_node_address_from_id(2).unwrap();
+        let node3 = db.get_node_address_from_id(3).unwrap();
+
+        // node 3 written with node 2's id, as files from before assigned ids could hold
+        db.logged_op(WalOp::UpdateNode, |db| {
+            let mut node_block = db.read_block::<NodeBlock>(node3)?;
+            node_block.id = 2;
+            db.write_block(node3, &node_block)?;
+
+            let mut block = db.read_block::<crate::types::RelationshipBlock>(rlt_offset)?;
+            block.relationship.node_to = 2;
+            db.write_block(rlt_offset, &block)
+        })
+        .unwrap();
+
+        // TEST
+        let report = db.check().unwrap();
+        assert!(report.violations.contains(&Violation::DuplicateId {
+            offset: node3,
+            id: 2,
+            first: node2,
+        }));
+
+        // the later holder gets the next id, its relationship goes with it
+        db.repair().unwrap();
+        assert_eq!(db.get_node_address_from_id(2).unwrap(), node2);
+        assert_eq!(db.get_node_address_from_id(4).unwrap(), node3);
+        assert_eq!(db.get_relationship(rlt_offset).unwrap().node_to, 4);
+        assert_eq!(db.read_header().unwrap().next_node_id, 5);
+        assert!(db.check().unwrap().is_clean());
+    }
 }
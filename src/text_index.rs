@@ -0,0 +1,199 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Full-text index.
+
+    Every node is a document made of its name and the string values of the
+    property keys declared with create_text_index(), in chain order. Text
+    is split into terms at every character that is not a letter or a digit
+    and each term is case folded, so "Payment-Service" holds the terms
+    "payment" and "service".
+
+    The index is one tree, rooted at the header's text_index, keyed by a
+    hash of the term and holding one entry per (term, node). The header
+    also counts the documents and the terms in them for ranking. Different
+    terms can share a hash, so term frequencies are counted by reading the
+    candidate documents back.
+
+    search() ranks the nodes holding any query term with BM25. The index
+    follows node creation, renames, property changes on a declared key and
+    node deletion.
+*/
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+// type imports can be combined, but this is easier to read
+use crate::database::Database;
+use crate::error::Result;
+use crate::index::fold_case;
+use crate::property_index::{fnv1a, FNV_OFFSET};
+use crate::types::{BlockType, DictionaryKind, NodeBlock}; // import Block Types
+use crate::types::{Node, PropertyValue}; // import structs
+use crate::wal::WalOp;
+
+const BM25_K1: f64 = 1.2; // how quickly repeating a term stops adding to the score
+const BM25_B: f64 = 0.75; // how much longer documents are penalised
+
+//  Case folded terms of text, in order, repeats included.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(fold_case)
+        .collect()
+}
+
+//  Key of term in the text index.
+pub(crate) fn term_key(term: &str) -> u64 {
+    fnv1a(FNV_OFFSET, term.as_bytes())
+}
+
+//  Terms of the document made of name and the string values among values.
+pub(crate) fn document_terms<'a>(
+    name: &str,
+    values: impl IntoIterator<Item = &'a PropertyValue>,
+) -> Vec<String> {
+    let mut terms = tokenize(name);
+
+    for value in values {
+        if let PropertyValue::String(string) = value {
+            terms.extend(tokenize(string));
+        }
+    }
+
+    terms
+}
+
+impl Database {
+    //  Terms of the node block at offset, as the text index sees them.
+    fn node_terms(&self, offset: u64) -> Result<Vec<String>> {
+        let node_block = self.read_block::<NodeBlock>(offset)?;
+        let name = self.load_string(&node_block.name, offset)?;
+        let keys = self.dictionary_names(DictionaryKind::TextIndex)?;
+
+        let attributes = self.attribute_chain(node_block.attr_head)?;
+        let values = attributes
+            .iter()
+            .filter(|(_, attribute)| keys.contains(&attribute.key))
+            .map(|(_, attribute)| &attribute.value);
+
+        Ok(document_terms(&name, values))
+    }
+
+    //  Whether the text index reads the node property key.
+    pub(crate) fn is_text_indexed(&self, key: &str) -> Result<bool> {
+        Ok(self
+            .dictionary_entry(DictionaryKind::TextIndex, key)?
+            .is_some())
+    }
+
+    //  Add the node block at offset to the text index. Only valid inside a logged operation.
+    pub(crate) fn index_text(&mut self, offset: u64) -> Result<()> {
+        let mut terms = self.node_terms(offset)?;
+        let length = terms.len() as u64;
+
+        terms.sort_unstable();
+        terms.dedup();
+        for term in &terms {
+            self.header_index_insert(|header| &mut header.text_index, term_key(term), offset)?;
+        }
+
+        let mut header = self.read_header()?;
+        header.text_documents += 1;
+        header.text_terms += length;
+        self.write_header(&header)
+    }
+
+    //  Take the node block at offset out of the text index, before its name or properties change. Only valid inside a logged operation.
+    pub(crate) fn unindex_text(&mut self, offset: u64) -> Result<()> {
+        let terms = self.node_terms(offset)?;
+
+        for term in &terms {
+            self.header_index_remove(|header| &mut header.text_index, term_key(term), offset)?;
+            // repeats are already gone
+        }
+
+        let mut header = self.read_header()?;
+        header.text_documents = header.text_documents.saturating_sub(1);
+        header.text_terms = header.text_terms.saturating_sub(terms.len() as u64);
+        self.write_header(&header)
+    }
+
+    //  Include the string values of the node property key in the text index. Declaring it again does nothing.
+    pub fn create_text_index(&mut self, key: &str) -> Result<()> {
+        self.logged_op(WalOp::CreateIndex, |db| {
+            if db.is_text_indexed(key)? {
+                return Ok(());
+            }
+
+            // every document may change, take them out and add them back with the key
+            let offsets = db.offsets_of(BlockType::Node)?;
+            for offset in &offsets {
+                db.unindex_text(*offset)?;
+            }
+            db.intern(DictionaryKind::TextIndex, key)?;
+            for offset in &offsets {
+                db.index_text(*offset)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    //  Every node property key the text index reads, besides names.
+    pub fn text_indexes(&self) -> Result<Vec<String>> {
+        self.dictionary_names(DictionaryKind::TextIndex)
+    }
+
+    //  Nodes holding any term of query, best BM25 score first (ties in file order), at most limit of them.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<(Node, f64)>> {
+        let mut terms = tokenize(query);
+        terms.sort_unstable();
+        terms.dedup();
+
+        let header = self.read_header()?;
+        let documents = header.text_documents.max(1) as f64;
+        let average_length = (header.text_terms as f64 / documents).max(1.0);
+
+        let mut documents_read: HashMap<u64, Vec<String>> = HashMap::new();
+        let mut scores: BTreeMap<u64, f64> = BTreeMap::new();
+
+        for term in &terms {
+            let key = term_key(term);
+
+            // (offset, times term appears, document length) of every document holding it
+            let mut matches = Vec::new();
+            for (_, offset) in self.index_range(header.text_index, key, key)? {
+                let document = match documents_read.entry(offset) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(self.node_terms(offset)?),
+                };
+
+                let frequency = document.iter().filter(|found| *found == term).count();
+                if frequency > 0 {
+                    matches.push((offset, frequency as f64, document.len() as f64));
+                } // else another term with the same key
+            }
+
+            let holding = matches.len() as f64;
+            let idf = (1.0 + (documents - holding + 0.5) / (holding + 0.5)).ln();
+
+            for (offset, frequency, length) in matches {
+                let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * length / average_length);
+                *scores.entry(offset).or_default() +=
+                    idf * frequency * (BM25_K1 + 1.0) / (frequency + norm);
+            }
+        }
+
+        let mut ranked: Vec<(u64, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked.truncate(limit);
+
+        ranked
+            .into_iter()
+            .map(|(offset, score)| Ok((self.get_node(offset)?, score)))
+            .collect()
+    }
+}
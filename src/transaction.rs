@@ -0,0 +1,98 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Multi-operation transactions.
+
+    A transaction keeps the write-ahead log's staging area open across many
+    operations: every create/update/delete made through the transaction is
+    staged in memory and visible to later reads on the same transaction,
+    but nothing reaches the log or the database file until commit(), which
+    writes everything as a single record. rollback() (or dropping the
+    transaction without committing) discards the staged writes.
+
+    An operation that fails inside the transaction is undone on its own,
+    the transaction stays usable and can still be committed.
+*/
+
+use std::ops::{Deref, DerefMut};
+
+use crate::database::Database;
+use crate::error::{GdbError, Result};
+use crate::wal::WalOp;
+
+pub struct Transaction<'a> {
+    db: &'a mut Database,
+    finished: bool,
+}
+
+impl Database {
+    //  Start a transaction, database operations are available on the returned handle.
+    pub fn begin(&mut self) -> Result<Transaction<'_>> {
+        if self.txn_depth > 0 {
+            return Err(GdbError::TransactionInProgress);
+        }
+        self.replay_pending()?;
+
+        self.op_depth += 1;
+        self.txn_depth = self.op_depth;
+
+        Ok(Transaction {
+            db: self,
+            finished: false,
+        })
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.txn_depth > 0
+    }
+}
+
+impl Transaction<'_> {
+    //  Log and apply every change made in the transaction as one record.
+    pub fn commit(mut self) -> Result<()> {
+        self.end();
+
+        let result = self.db.commit_staged(WalOp::Transaction);
+        if result.is_err() {
+            self.db.staged.clear();
+        }
+        result
+    }
+
+    //  Discard every change made in the transaction.
+    pub fn rollback(mut self) {
+        self.end();
+        self.db.staged.clear();
+    }
+
+    fn end(&mut self) {
+        self.finished = true;
+        self.db.op_depth -= 1;
+        self.db.txn_depth = 0;
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.end();
+            self.db.staged.clear(); // not committed, roll back
+        }
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.db
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut Database {
+        self.db
+    }
+}
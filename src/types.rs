@@ -5,62 +5,288 @@
 pub const PATH: &str = "database/test_database.db"; // The path to the database
 pub const EXPORT_PATH: &str = "database/output.json"; // The path to the exported database
 pub const INPUT_PATH: &str = "database/input.txt"; // Input file path, for testing
-pub const RLT_PAD: usize = 7; // Relationship padding
-pub const ATR_PAD: usize = 2; // Attribute padding
+pub const RLT_PAD: usize = 3; // Relationship padding
+pub const EMPTY_PAD: usize = 10; // Empty block padding
+pub const HEADER_SIZE: u64 = 128; // Space reserved for the header at the start of the file
+pub const BLOCK_SIZE: u64 = 96; // Every block is padded to this size
+pub const CHECKSUM_SIZE: u64 = 4; // CRC32 stored in the last bytes of the header and of every block
+
+pub const MAGIC: [u8; 4] = *b"GDBR"; // First bytes of every database file
+pub const INLINE_STRING_SIZE: usize = 32; // String bytes kept inside a node or attribute block
+pub const OVERFLOW_DATA_SIZE: usize = 72; // String bytes held by each overflow block
+pub const INDEX_LEAF_SIZE: usize = 4; // Entries held by a full index leaf
+pub const INDEX_BRANCH_SIZE: usize = 2; // Separators held by a full index branch
+
+pub const FORMAT_VERSION: u32 = 18; // On-disk layout version, bump when a block layout changes (1 = unversioned files)
+pub const SUPPORTED_FEATURES: u32 = 0; // Feature flags this build understands
 
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::mem::size_of;
 
 // Define the structs used in the database...
 
 // BlockType enum
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum BlockType {
     Empty,
-    Unset,
+    #[default]
+    Unset, // Default is used to set the default value of a struct (when defining empty struct)
     Node,
     Relationship,
     Attribute,
     Final,
-}
-
-// Default is used to set the default value of a struct (when defining empty struct)
-impl Default for BlockType {
-    fn default() -> Self {
-        BlockType::Unset // Default value of BlockType is Empty
-    }
+    Overflow,   // continuation of a string too long to fit inline
+    Dictionary, // name shared by many records, e.g. a relationship type
+    Label,      // membership of one node in one label
+    Index,      // node of an index tree
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
 #[repr(C)]
 pub struct Header {
+    pub magic: [u8; 4],
+    pub format_version: u32,
+    pub block_size: u32,
+    pub feature_flags: u32,
     pub total_blocks: u64,
-    pub first_empty: u64,
+    pub first_empty: u64, // head of the free list, 0 when no empty block is left
     pub db_size: u64,
+    pub dictionary_head: u64, // first DictionaryBlock, 0 when no name has been stored yet
+    pub id_index: u64,        // root IndexBlock of the node id index, 0 when there are no nodes
+    pub next_node_id: u64,    // id create_node hands out next, above every id ever used
+    pub name_index: u64,      // root IndexBlock of the node name index, 0 when there are no nodes
+    pub text_index: u64,      // root IndexBlock of the full-text index, 0 when no term is indexed
+    pub text_documents: u64,  // documents in the full-text index, one per node
+    pub text_terms: u64,      // terms in those documents, repeats included
+    pub schema_generation: u64, // bumped whenever the stored schema may have changed
+}
+
+impl Header {
+    //  Header for a file written by this build.
+    pub fn new(total_blocks: u64, first_empty: u64, db_size: u64) -> Self {
+        Header {
+            magic: MAGIC,
+            format_version: FORMAT_VERSION,
+            block_size: BLOCK_SIZE as u32,
+            feature_flags: 0,
+            total_blocks,
+            first_empty,
+            db_size,
+            dictionary_head: 0,
+            id_index: 0,
+            next_node_id: 1,
+            name_index: 0,
+            text_index: 0,
+            text_documents: 0,
+            text_terms: 0,
+            schema_generation: 0,
+        }
+    }
+}
+
+/*
+    String stored in a block: up to INLINE_STRING_SIZE bytes of UTF-8 (or of
+    an encoded property) in the block itself, anything after that in a chain
+    of overflow blocks.
+*/
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct StoredString {
+    pub inline: Vec<u8>,
+    pub overflow: u64, // first OverflowBlock, 0 when the string fits inline
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[repr(C)] // This is used to make sure the struct is represented in memory the same way as in C
 pub struct NodeBlock {
     pub block_type: BlockType,
-    pub node: Node,
+    pub id: u64,
+    pub name: StoredString,
+    pub rlt_head: u64,
+    pub attr_head: u64,
+    pub label_head: u64, // first LabelBlock of this node, 0 when it has no labels
+    pub in_head: u64,    // first relationship pointing at this node, 0 when there is none
+}
+
+// Continuation of a StoredString
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct OverflowBlock {
+    pub block_type: BlockType,
+    pub data: Vec<u8>, // up to OVERFLOW_DATA_SIZE bytes
+    pub next: u64,     // next overflow block, 0 ends the string
+}
+
+// What the names in a dictionary are used for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DictionaryKind {
+    RelationshipType,
+    Label,
+    HashIndex,    // property key with a hash index
+    OrderedIndex, // property key with an ordered index
+    TextIndex,    // property key read by the full-text index
+    UniqueName,   // label whose nodes must have distinct names
+    UniqueKey,    // label and property key whose values must be distinct
+    RequiredKey,  // label and property key every node carrying the label must have
+    Schema,       // the schema catalog, as JSON
+}
+
+// Dictionary entry, records refer to it by id
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct DictionaryBlock {
+    pub block_type: BlockType,
+    pub kind: DictionaryKind,
+    pub id: u64, // unique per kind, starting at 1
+    pub name: StoredString,
+    pub next: u64, // next DictionaryBlock, 0 ends the dictionary
+    pub head: u64, // labels: first LabelBlock of the nodes carrying it, property indexes: root IndexBlock, unused otherwise
+}
+
+/*
+    One label on one node. Each block sits on two chains: the node's labels
+    (NodeBlock::label_head, label_next) and the nodes carrying the label
+    (DictionaryBlock::head, member_next / member_prev).
+*/
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct LabelBlock {
+    pub block_type: BlockType,
+    pub label: u64,       // label id in the dictionary
+    pub node: u64,        // offset of the labelled node's block
+    pub label_next: u64,  // next label of the same node
+    pub member_next: u64, // next node with the same label
+    pub member_prev: u64, // previous node with the same label, 0 at the front of the chain
+}
+
+/*
+    Node of a B+ tree mapping u64 keys to block offsets. Leaves hold the
+    (key, offset) entries in order, branches hold separators with one more
+    child than separators: children[i] covers the entries from separator
+    i - 1 up to (not including) separator i.
+*/
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[repr(C)]
+pub struct IndexBlock {
+    pub block_type: BlockType,
+    pub leaf: bool,
+    pub entries: Vec<(u64, u64)>, // up to INDEX_LEAF_SIZE in a leaf, INDEX_BRANCH_SIZE in a branch
+    pub children: Vec<u64>,       // empty in a leaf
+}
+
+// Property index flavour, hash answers equality only, ordered answers ranges too
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexKind {
+    Hash,
+    Ordered,
+}
+
+// Rule every node carrying label must follow, checked on every write
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    UniqueName { label: String }, // no two nodes with the label share a name
+    Unique { label: String, key: String }, // no two nodes with the label share a value of key
+    Required { label: String, key: String }, // every node with the label has key
+}
+
+// Type of a PropertyValue, as named in a schema
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PropertyType {
+    String,
+    Int,
+    Float,
+    Bool,
+    Bytes,
+    Timestamp,
+    List,
+}
+
+// How a schema treats what it does not declare
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SchemaMode {
+    #[default]
+    Permissive, // anything undeclared is allowed, only declarations are checked
+    Strict, // labels, relationship types and every node and relationship property must be declared
+}
+
+// Properties a node carrying label may hold, and their types
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelSchema {
+    pub label: String,
+    #[serde(default)]
+    pub properties: BTreeMap<String, PropertyType>,
 }
 
-impl Default for NodeBlock {
+// Relationships of rlt_type may go from a node labelled from to a node labelled to, holding properties
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelationshipSchema {
+    pub rlt_type: String,
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub properties: BTreeMap<String, PropertyType>,
+}
+
+// Schema catalog, stored in the file and checked on every write
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schema {
+    #[serde(default)]
+    pub mode: SchemaMode,
+    #[serde(default)]
+    pub labels: Vec<LabelSchema>,
+    #[serde(default)]
+    pub relationships: Vec<RelationshipSchema>, // a type may have several, any one of them allows a relationship
+}
+
+// What deleting a node does with the relationships touching it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeleteMode {
+    Restrict, // refuse while any relationship touches the node
+    Detach,   // delete every incoming and outgoing relationship and its properties, then the node
+    Cascade,  // as Detach, then the nodes it pointed at that nothing else points to, recursively
+}
+
+// How many blocks to add when the free list runs out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrowthPolicy {
+    Fixed(u64),      // add a fixed number of blocks
+    Double,          // double the current number of blocks
+    Percentage(u64), // add a percentage of the current number of blocks
+}
+
+impl Default for GrowthPolicy {
     fn default() -> Self {
-        NodeBlock {
+        GrowthPolicy::Fixed(10)
+    }
+}
+
+impl GrowthPolicy {
+    //  Number of blocks to add to a file currently holding total_blocks, always at least one.
+    pub fn blocks_to_add(&self, total_blocks: u64) -> u64 {
+        let amount = match *self {
+            GrowthPolicy::Fixed(amount) => amount,
+            GrowthPolicy::Double => total_blocks,
+            GrowthPolicy::Percentage(percent) => (total_blocks * percent).div_ceil(100),
+        };
+        amount.max(1)
+    }
+}
+
+// Free block, threaded into the free list starting at Header::first_empty
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct EmptyBlock {
+    pub block_type: BlockType,
+    pub next_empty: u64, // next free block, 0 terminates the list
+    pub pad: [u64; EMPTY_PAD],
+}
+
+impl EmptyBlock {
+    pub fn new(next_empty: u64) -> Self {
+        EmptyBlock {
             block_type: BlockType::Empty,
-            node: {
-                Node {
-                    id: 0,
-                    name: [
-                        'E', 'M', 'P', 'T', 'Y', '\0', '\0', '\0', '\0', '\0', '\0', '\0', '\0',
-                        '\0', '\0', '\0',
-                    ],
-                    rlt_head: 0,
-                    attr_head: 0,
-                }
-            },
+            next_empty,
+            pad: [0; EMPTY_PAD],
         }
     }
 }
@@ -69,13 +295,14 @@ pub struct TestSize {
     pub blocks: [u8; 64],
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
-#[repr(C)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Node {
     pub id: u64,
-    pub name: [char; 16],
+    pub name: String,
     pub rlt_head: u64,
     pub attr_head: u64,
+    pub label_head: u64,
+    pub in_head: u64,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -85,13 +312,17 @@ pub struct Block {
     pub pad: [u64; 11],
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub struct Relationship {
     pub node_from: u64,
     pub node_to: u64,
     pub rlt_next: u64,
     pub attr_head: u64,
+    pub rlt_type: u64, // relationship type id in the dictionary, 0 = untyped
+    pub in_next: u64,  // next relationship pointing at node_to
+    pub rlt_prev: u64, // previous relationship from node_from, 0 at the head
+    pub in_prev: u64,  // previous relationship pointing at node_to, 0 at the head
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -102,10 +333,30 @@ pub struct RelationshipBlock {
     pub pad: [u64; RLT_PAD],
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
-#[repr(C)]
+// Typed value of a node or relationship property
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PropertyValue {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Timestamp(i64), // milliseconds since the Unix epoch
+    List(Vec<PropertyValue>),
+}
+
+// Owner of a property chain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Entity {
+    Node(u64),         // node id
+    Relationship(u64), // offset of the relationship block
+}
+
+// One key/value pair in an attribute chain
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Attribute {
-    pub value: [char; 16],
+    pub key: String,
+    pub value: PropertyValue,
     pub attr_next: u64,
 }
 
@@ -113,31 +364,94 @@ pub struct Attribute {
 #[repr(C)]
 pub struct AttributeBlock {
     pub block_type: BlockType,
-    pub attribute: Attribute,
-    pub pad: [u64; ATR_PAD],
+    pub property: StoredString, // bincode encoded (key, value), spills into overflow blocks like a string
+    pub attr_next: u64,
 }
 
 // Define a public function that uses the structs
 pub fn print_struct_info() {
     println!("Test Struct Info:     {}\r", size_of::<TestSize>());
     println!("Header Size:          {}\r", size_of::<Header>());
-    // println!("Block Size:           {}\r", size_of::<NodeBlock>());
+    println!("Block Size:           {}\r", BLOCK_SIZE);
     println!("Node Size:            {}\r", size_of::<Node>());
     println!("Relationship Size:    {}\r", size_of::<Relationship>());
     println!("Attribute Size:       {}\r", size_of::<Attribute>());
     println!("NodeBlock Size:       {}\r", size_of::<NodeBlock>());
     println!("Relt Block Size:      {}\r", size_of::<RelationshipBlock>());
     println!("AttributeBlock Size:  {}\r", size_of::<AttributeBlock>());
+    println!("OverflowBlock Size:   {}\r", size_of::<OverflowBlock>());
+    println!("DictionaryBlock Size: {}\r", size_of::<DictionaryBlock>());
+    println!("LabelBlock Size:      {}\r", size_of::<LabelBlock>());
+    println!("IndexBlock Size:      {}\r", size_of::<IndexBlock>());
+    println!("EmptyBlock Size:      {}\r", size_of::<EmptyBlock>());
     println!("Generic Block Size:   {}\r", size_of::<Block>());
     println!("String Size:          {}\r", size_of::<String>());
     println!("----------------------");
 }
 
+//  Check every block type still fits in a block (with its checksum) when its strings are as long as allowed.
 pub fn assert_struct_size_equality() {
-    // let SIZE: usize = size_of::<Header>();
-    let SIZE: usize = size_of::<NodeBlock>();
-    assert_eq!(size_of::<NodeBlock>(), SIZE);
-    assert_eq!(size_of::<RelationshipBlock>(), SIZE);
-    assert_eq!(size_of::<AttributeBlock>(), SIZE);
-    assert_eq!(size_of::<Block>(), SIZE);
+    let encoded_size = |size: bincode::Result<u64>| size.unwrap() + CHECKSUM_SIZE;
+
+    let full_string = || StoredString {
+        inline: vec![0; INLINE_STRING_SIZE],
+        overflow: 0,
+    };
+    let node_block = NodeBlock {
+        block_type: BlockType::Node,
+        id: 0,
+        name: full_string(),
+        rlt_head: 0,
+        attr_head: 0,
+        label_head: 0,
+        in_head: 0,
+    };
+    let attribute_block = AttributeBlock {
+        block_type: BlockType::Attribute,
+        property: full_string(),
+        attr_next: 0,
+    };
+    let dictionary_block = DictionaryBlock {
+        block_type: BlockType::Dictionary,
+        kind: DictionaryKind::RelationshipType,
+        id: 0,
+        name: full_string(),
+        next: 0,
+        head: 0,
+    };
+    let overflow_block = OverflowBlock {
+        block_type: BlockType::Overflow,
+        data: vec![0; OVERFLOW_DATA_SIZE],
+        next: 0,
+    };
+
+    let relationship_block = RelationshipBlock {
+        block_type: BlockType::Relationship,
+        relationship: Relationship::default(),
+        pad: [0; RLT_PAD],
+    };
+    let index_leaf = IndexBlock {
+        block_type: BlockType::Index,
+        leaf: true,
+        entries: vec![(0, 0); INDEX_LEAF_SIZE],
+        children: Vec::new(),
+    };
+    let index_branch = IndexBlock {
+        block_type: BlockType::Index,
+        leaf: false,
+        entries: vec![(0, 0); INDEX_BRANCH_SIZE],
+        children: vec![0; INDEX_BRANCH_SIZE + 1],
+    };
+
+    assert!(encoded_size(bincode::serialized_size(&Header::default())) <= HEADER_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&node_block)) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&relationship_block)) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&attribute_block)) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&overflow_block)) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&dictionary_block)) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&LabelBlock::default())) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&index_leaf)) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&index_branch)) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&EmptyBlock::new(0))) <= BLOCK_SIZE);
+    assert!(encoded_size(bincode::serialized_size(&Block::default())) <= BLOCK_SIZE);
 }
@@ -0,0 +1,244 @@
+/*
+    Simon H - 2024
+*/
+
+/*
+    Write-ahead log.
+
+    Every mutating operation stages its block and header writes in memory
+    (see Database::write_block / write_header). When the outermost operation
+    finishes the staged writes are appended to the log as one record and
+    fsynced, then applied to the database file, and the log is truncated.
+
+    On open any complete record left in the log (crash between logging and
+    applying) is replayed. A commit that fails after logging leaves its
+    record in the log the same way, it is replayed before the next
+    operation starts instead of being truncated away by that operation's
+    commit. Records hold full block images so replaying one
+    that was already applied is harmless. A torn record at the tail (crash
+    while logging) fails its checksum and is dropped, the database file was
+    never touched by it.
+
+    Log layout, repeated per record:
+        [len: u32][crc32 of payload: u32][payload: bincode WalRecord]
+*/
+
+use bincode::{deserialize, serialize};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::database::Database;
+use crate::error::Result;
+
+const FRAME_HEADER_SIZE: usize = 8; // len + crc
+
+// Logical operation a record belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WalOp {
+    Format,
+    UpdateHeader,
+    CreateNode,
+    UpdateNode,
+    DeleteNode,
+    CreateRelationship,
+    UpdateRelationship,
+    DeleteRelationship,
+    CreateAttribute,
+    UpdateAttribute,
+    DeleteAttribute,
+    DeleteRecord,
+    Transaction,
+    Migrate,
+    Repair,
+    Compact,
+    CreateLabel,
+    DeleteLabel,
+    CreateIndex,
+    CreateConstraint,
+    UpdateSchema,
+}
+
+// Bytes to write at an offset of the database file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalWrite {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub op: WalOp,
+    pub writes: Vec<WalWrite>,
+}
+
+//  Log file lives next to the database, "<db path>.wal"
+pub fn wal_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".wal");
+    PathBuf::from(path)
+}
+
+//  Append a record to the end of the log and fsync it.
+pub fn append_record(mut wal: &File, record: &WalRecord) -> Result<()> {
+    let payload = serialize(record)?;
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+
+    wal.seek(SeekFrom::End(0))?;
+    wal.write_all(&frame)?;
+    wal.sync_data()?;
+
+    Ok(())
+}
+
+//  Read every complete record from the log, stopping at the first torn or corrupt frame.
+pub fn read_records(mut wal: &File) -> Result<Vec<WalRecord>> {
+    let mut contents = Vec::new();
+    wal.seek(SeekFrom::Start(0))?;
+    wal.read_to_end(&mut contents)?;
+
+    let mut records = Vec::new();
+    let mut pos = 0;
+
+    while pos + FRAME_HEADER_SIZE <= contents.len() {
+        let len = u32::from_le_bytes(contents[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(contents[pos + 4..pos + 8].try_into().unwrap());
+        let start = pos + FRAME_HEADER_SIZE;
+
+        if start + len > contents.len() {
+            break; // torn write at the tail
+        }
+
+        let payload = &contents[start..start + len];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+
+        match deserialize::<WalRecord>(payload) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+
+        pos = start + len;
+    }
+
+    Ok(records)
+}
+
+impl Database {
+    /*
+        Run f as one logged operation.
+
+        Nested calls (delete_node -> delete_node_at -> delete_record_offset)
+        join the outermost operation, so the whole call is logged and applied
+        as a single record. On error every write staged by f is discarded.
+
+        Inside a transaction the writes stay staged until commit, a failed
+        operation only rolls back to the state before it started.
+    */
+    pub(crate) fn logged_op<T>(
+        &mut self,
+        op: WalOp,
+        f: impl FnOnce(&mut Database) -> Result<T>,
+    ) -> Result<T> {
+        if self.op_depth == 0 {
+            self.replay_pending()?;
+        }
+
+        let savepoint = if self.txn_depth > 0 && self.op_depth == self.txn_depth {
+            Some(self.staged.clone())
+        } else {
+            None
+        };
+
+        self.op_depth += 1;
+        let result = f(self);
+        self.op_depth -= 1;
+
+        if self.op_depth > 0 {
+            if let (Err(_), Some(staged)) = (&result, savepoint) {
+                self.staged = staged;
+            }
+            return result;
+        }
+
+        match result {
+            Ok(value) => {
+                if let Err(err) = self.commit_staged(op) {
+                    self.staged.clear();
+                    return Err(err);
+                }
+                Ok(value)
+            }
+            Err(err) => {
+                self.staged.clear();
+                Err(err)
+            }
+        }
+    }
+
+    //  Log staged writes, apply them to the database file, then clear the log.
+    pub(crate) fn commit_staged(&mut self, op: WalOp) -> Result<()> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+
+        let record = WalRecord {
+            op,
+            writes: std::mem::take(&mut self.staged)
+                .into_iter()
+                .map(|(offset, data)| WalWrite { offset, data })
+                .collect(),
+        };
+
+        // until the log is cleared it may hold a record (or a torn frame) the file doesn't reflect
+        self.wal_pending = true;
+        append_record(&self.wal, &record)?;
+        self.apply_record(&record)?;
+        self.clear_wal()?;
+        self.wal_pending = false;
+
+        Ok(())
+    }
+
+    //  Apply what an earlier failed commit left in the log before anything else reads or logs over it.
+    pub(crate) fn replay_pending(&mut self) -> Result<()> {
+        if self.wal_pending {
+            self.recover()?;
+        }
+        Ok(())
+    }
+
+    fn apply_record(&mut self, record: &WalRecord) -> Result<()> {
+        for write in &record.writes {
+            self.file.write_all_at(&write.data, write.offset)?;
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn clear_wal(&mut self) -> Result<()> {
+        self.wal.set_len(0)?;
+        self.wal.sync_data()?;
+        Ok(())
+    }
+
+    //  Replay records left in the log by a crash (called from Database::open) or a failed commit.
+    pub(crate) fn recover(&mut self) -> Result<usize> {
+        let records = read_records(&self.wal)?;
+
+        for record in &records {
+            self.apply_record(record)?;
+        }
+
+        self.clear_wal()?;
+        self.wal_pending = false;
+        Ok(records.len())
+    }
+}